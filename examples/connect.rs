@@ -0,0 +1,60 @@
+//! Measures how many connections per second a running server sets up, each
+//! sending one `PING` and closing once it's answered.
+//!
+//! ```sh
+//! cargo run --release --example connect -- [clients] [seconds] [port]
+//! ```
+
+use std::{
+    env,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let clients: usize = args.next().map_or(4, |arg| arg.parse().expect("clients"));
+    let seconds: u64 = args.next().map_or(5, |arg| arg.parse().expect("seconds"));
+    let port: u16 = args.next().map_or(6379, |arg| arg.parse().expect("port"));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let completed = Arc::new(AtomicU64::new(0));
+    let workers = (0..clients)
+        .map(|_| {
+            let stop = stop.clone();
+            let completed = completed.clone();
+            thread::spawn(move || {
+                let mut pong = [0u8; 7];
+                while !stop.load(Ordering::Relaxed) {
+                    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect");
+                    stream.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+                    stream.read_exact(&mut pong).unwrap();
+                    completed.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let started = Instant::now();
+    thread::sleep(Duration::from_secs(seconds));
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = started.elapsed();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let completed = completed.load(Ordering::Relaxed);
+    println!(
+        "{} clients: {} connections in {:.1}s, {:.0} connections/s, {:.1}us each",
+        clients,
+        completed,
+        elapsed.as_secs_f64(),
+        completed as f64 / elapsed.as_secs_f64(),
+        elapsed.as_secs_f64() * 1e6 / completed as f64 * clients as f64
+    );
+}
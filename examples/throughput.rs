@@ -0,0 +1,100 @@
+//! Measures how many commands per second a running server handles with many
+//! clients at once, either writing unrelated keys (`set`) or all reading the
+//! same key (`get`).
+//!
+//! ```sh
+//! cargo run --release --example throughput -- [clients] [seconds] [port] [set|get]
+//! ```
+
+use std::{
+    env,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Commands each client sends before reading their replies.
+const PIPELINE: usize = 16;
+
+/// The key every client reads in the `get` workload.
+const HOT_KEY: &str = "bench:hot";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let clients: usize = args.next().map_or(64, |arg| arg.parse().expect("clients"));
+    let seconds: u64 = args.next().map_or(5, |arg| arg.parse().expect("seconds"));
+    let port: u16 = args.next().map_or(6379, |arg| arg.parse().expect("port"));
+    let reads = match args.next().as_deref() {
+        None | Some("set") => false,
+        Some("get") => true,
+        Some(workload) => panic!("unknown workload {}", workload),
+    };
+
+    if reads {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect");
+        stream
+            .write_all(&command(&["SET", HOT_KEY, "value"]))
+            .unwrap();
+        stream.read_exact(&mut [0u8; 5]).unwrap();
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let completed = Arc::new(AtomicU64::new(0));
+    let workers = (0..clients)
+        .map(|client| {
+            let stop = stop.clone();
+            let completed = completed.clone();
+            thread::spawn(move || {
+                let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect");
+                stream.set_nodelay(true).unwrap();
+                let batch = (0..PIPELINE)
+                    .flat_map(|i| {
+                        if reads {
+                            command(&["GET", HOT_KEY])
+                        } else {
+                            command(&["SET", &format!("bench:{}:{}", client, i), "value"])
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                // Every reply is `$5\r\nvalue\r\n` or `+OK\r\n`.
+                let reply_len = if reads { 11 } else { 5 };
+                let mut replies = vec![0u8; PIPELINE * reply_len];
+                while !stop.load(Ordering::Relaxed) {
+                    stream.write_all(&batch).unwrap();
+                    stream.read_exact(&mut replies).unwrap();
+                    completed.fetch_add(PIPELINE as u64, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let started = Instant::now();
+    thread::sleep(Duration::from_secs(seconds));
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = started.elapsed();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let completed = completed.load(Ordering::Relaxed);
+    println!(
+        "{} clients: {} commands in {:.1}s, {:.0} commands/s",
+        clients,
+        completed,
+        elapsed.as_secs_f64(),
+        completed as f64 / elapsed.as_secs_f64()
+    );
+}
+
+/// Encodes `args` as a RESP array of bulk strings.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+    }
+    out
+}
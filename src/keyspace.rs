@@ -0,0 +1,266 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{random_u64, Entry, Storage};
+
+/// How many stripes each database's keys are split into.
+pub const STRIPES: usize = 256;
+
+/// The keys of one database, split into stripes that each have their own
+/// lock so commands on keys in different stripes can run at the same time,
+/// and commands that only read can share a stripe. A key always lives in
+/// the stripe its hash picks. Commands that lock more than one stripe take
+/// them in order of index, so they can't deadlock.
+pub struct Keyspace {
+    stripes: Vec<RwLock<Storage>>,
+    /// The keys in each stripe that may have an expiry, so the active
+    /// expire cycle only has to look at those. Keys are added when they're
+    /// given an expiry, but left in when they lose it or are deleted until
+    /// the cycle comes across them. Always lock the stripe before this.
+    expiring: Vec<Mutex<HashSet<Vec<u8>>>>,
+    /// The keys in each stripe that are being watched with `WATCH`. An
+    /// entry is removed once no client watches the key any more. Always lock
+    /// the stripe before this.
+    watches: Vec<Mutex<HashMap<Vec<u8>, Watch>>>,
+}
+
+/// A key watched by at least one client.
+struct Watch {
+    /// Bumped whenever the key is written, so `EXEC` can tell if it changed
+    /// since `WATCH`.
+    version: u64,
+    /// How many times the key is being watched, by any client.
+    watchers: usize,
+}
+
+/// The locked stripes of a [`Keyspace`], for commands that touch several
+/// keys or the whole database.
+pub struct Stripes<'a, G> {
+    guards: BTreeMap<usize, G>,
+    expiring: &'a [Mutex<HashSet<Vec<u8>>>],
+}
+
+pub type ReadStripes<'a> = Stripes<'a, RwLockReadGuard<'a, Storage>>;
+pub type WriteStripes<'a> = Stripes<'a, RwLockWriteGuard<'a, Storage>>;
+
+impl Keyspace {
+    pub fn new(storage: Storage) -> Self {
+        let keyspace = Self {
+            stripes: (0..STRIPES).map(|_| RwLock::default()).collect(),
+            expiring: (0..STRIPES).map(|_| Mutex::default()).collect(),
+            watches: (0..STRIPES).map(|_| Mutex::default()).collect(),
+        };
+        keyspace.write_all().extend(storage);
+        keyspace
+    }
+
+    fn stripe(key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % STRIPES as u64) as usize
+    }
+
+    /// Locks the stripe that holds `key` for reading.
+    pub fn read(&self, key: &[u8]) -> RwLockReadGuard<'_, Storage> {
+        self.stripes[Self::stripe(key)].read().unwrap()
+    }
+
+    /// Locks the stripe that holds `key` for writing.
+    pub fn write(&self, key: &[u8]) -> RwLockWriteGuard<'_, Storage> {
+        self.stripes[Self::stripe(key)].write().unwrap()
+    }
+
+    /// Locks the stripes that hold `keys` for reading.
+    pub fn read_keys<K: AsRef<[u8]>>(&self, keys: impl IntoIterator<Item = K>) -> ReadStripes<'_> {
+        self.lock_stripes(Self::indices(keys), |stripe| stripe.read().unwrap())
+    }
+
+    /// Locks the stripes that hold `keys` for writing.
+    pub fn write_keys<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> WriteStripes<'_> {
+        self.lock_stripes(Self::indices(keys), |stripe| stripe.write().unwrap())
+    }
+
+    /// Locks every stripe for reading.
+    pub fn read_all(&self) -> ReadStripes<'_> {
+        self.lock_stripes(0..STRIPES, |stripe| stripe.read().unwrap())
+    }
+
+    /// Locks every stripe for writing.
+    pub fn write_all(&self) -> WriteStripes<'_> {
+        self.lock_stripes(0..STRIPES, |stripe| stripe.write().unwrap())
+    }
+
+    /// Records that `key` may have an expiry, for a command that just gave
+    /// it one.
+    pub fn note_expiring(&self, key: &[u8]) {
+        self.expiring[Self::stripe(key)]
+            .lock()
+            .unwrap()
+            .insert(key.to_vec());
+    }
+
+    /// Runs `f` on the stripe at `index`, locked for writing, along with the
+    /// keys in it that may have an expiry.
+    pub fn with_expiring<T>(
+        &self,
+        index: usize,
+        f: impl FnOnce(&mut Storage, &mut HashSet<Vec<u8>>) -> T,
+    ) -> T {
+        let mut storage = self.stripes[index].write().unwrap();
+        f(&mut storage, &mut self.expiring[index].lock().unwrap())
+    }
+
+    /// Starts watching `key` and returns its version, to be compared with
+    /// [`Keyspace::version`] by `EXEC`. Every call must be matched by a call
+    /// to [`Keyspace::unwatch`].
+    pub fn watch(&self, key: &[u8]) -> u64 {
+        let mut watches = self.watches[Self::stripe(key)].lock().unwrap();
+        let watch = watches.entry(key.to_vec()).or_insert(Watch {
+            version: 0,
+            watchers: 0,
+        });
+        watch.watchers += 1;
+        watch.version
+    }
+
+    /// Stops watching `key`, forgetting its version if no one else is
+    /// watching it.
+    pub fn unwatch(&self, key: &[u8]) {
+        let mut watches = self.watches[Self::stripe(key)].lock().unwrap();
+        if let Some(watch) = watches.get_mut(key) {
+            watch.watchers -= 1;
+            if watch.watchers == 0 {
+                watches.remove(key);
+            }
+        }
+    }
+
+    /// The version of `key`, or `None` if no one is watching it.
+    pub fn version(&self, key: &[u8]) -> Option<u64> {
+        let watches = self.watches[Self::stripe(key)].lock().unwrap();
+        watches.get(key).map(|watch| watch.version)
+    }
+
+    /// Bumps the version of `key` if it's being watched.
+    pub fn touch(&self, key: &[u8]) {
+        let mut watches = self.watches[Self::stripe(key)].lock().unwrap();
+        if let Some(watch) = watches.get_mut(key) {
+            watch.version += 1;
+        }
+    }
+
+    /// Bumps the version of every watched key, for commands that replace
+    /// the whole database.
+    pub fn touch_all(&self) {
+        for watches in &self.watches {
+            let mut watches = watches.lock().unwrap();
+            watches.values_mut().for_each(|watch| watch.version += 1);
+        }
+    }
+
+    /// Picks a key at random and returns what `f` makes of it and its entry,
+    /// or `None` if the database is empty. Only one stripe is locked at a
+    /// time. Keys in emptier stripes are a little likelier to be picked,
+    /// which doesn't matter for sampling.
+    pub fn random_entry<T>(&self, f: impl Fn(&[u8], &Entry) -> T) -> Option<T> {
+        let start = random_u64() as usize;
+        (0..STRIPES).find_map(|i| {
+            let storage = self.stripes[(start + i) % STRIPES].read().unwrap();
+            if storage.is_empty() {
+                return None;
+            }
+            let index = random_u64() as usize % storage.len();
+            let (key, entry) = storage.iter().nth(index)?;
+            Some(f(key, entry))
+        })
+    }
+
+    /// The distinct stripes holding `keys`, in order of index.
+    fn indices<K: AsRef<[u8]>>(keys: impl IntoIterator<Item = K>) -> BTreeSet<usize> {
+        keys.into_iter()
+            .map(|key| Self::stripe(key.as_ref()))
+            .collect()
+    }
+
+    /// Locks the stripes at `indices`, which must be in ascending order.
+    fn lock_stripes<'a, G>(
+        &'a self,
+        indices: impl IntoIterator<Item = usize>,
+        lock: impl Fn(&'a RwLock<Storage>) -> G,
+    ) -> Stripes<'a, G> {
+        Stripes {
+            guards: indices
+                .into_iter()
+                .map(|index| (index, lock(&self.stripes[index])))
+                .collect(),
+            expiring: &self.expiring,
+        }
+    }
+}
+
+impl<G: Deref<Target = Storage>> Stripes<'_, G> {
+    /// The stripe that holds `key`, which must be one of the keys locked.
+    pub fn stripe(&self, key: &[u8]) -> &Storage {
+        &self.guards[&Keyspace::stripe(key)]
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.stripe(key).get(key)
+    }
+
+    /// The keys in every locked stripe, with their entries.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Entry)> {
+        self.guards.values().flat_map(|storage| storage.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.guards.values().map(|storage| storage.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guards.values().all(|storage| storage.is_empty())
+    }
+}
+
+impl<G: DerefMut<Target = Storage>> Stripes<'_, G> {
+    /// The stripe that holds `key`, which must be one of the keys locked.
+    pub fn stripe_mut(&mut self, key: &[u8]) -> &mut Storage {
+        self.guards
+            .get_mut(&Keyspace::stripe(key))
+            .expect("stripe of key is locked")
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        let index = Keyspace::stripe(&key);
+        if entry.0.is_some() {
+            self.expiring[index].lock().unwrap().insert(key.clone());
+        }
+        self.stripe_mut(&key).insert(key, entry)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.stripe_mut(key).remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        for (index, storage) in &mut self.guards {
+            storage.clear();
+            self.expiring[*index].lock().unwrap().clear();
+        }
+    }
+}
+
+impl<G: DerefMut<Target = Storage>> Extend<(Vec<u8>, Entry)> for Stripes<'_, G> {
+    fn extend<T: IntoIterator<Item = (Vec<u8>, Entry)>>(&mut self, entries: T) {
+        for (key, entry) in entries {
+            self.insert(key, entry);
+        }
+    }
+}
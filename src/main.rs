@@ -1,12 +1,34 @@
+mod keyspace;
+mod memory;
+mod pool;
+mod rdb;
+mod zset;
+
 use std::{
-    collections::{self, HashMap},
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    iter,
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex, OnceLock, RwLock,
+    },
     thread, time,
 };
 
 use clap::Parser;
+use keyspace::{Keyspace, ReadStripes, WriteStripes};
+use pool::ThreadPool;
+use rdb::RDBObject;
+use tokio::signal::unix::{signal, SignalKind};
+use zset::SortedSet;
+
+#[global_allocator]
+static ALLOCATOR: memory::CountingAllocator = memory::CountingAllocator;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -14,388 +36,7291 @@ struct Args {
     dir: Option<String>,
     #[arg(long("dbfilename"))]
     db_filename: Option<String>,
+    /// How often expired keys are actively removed, in milliseconds.
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    active_expire_interval: u64,
+    /// Which keyspace events are published, as `notify-keyspace-events`
+    /// flags.
+    #[arg(long, default_value = "", value_parser = parse_keyspace_events)]
+    notify_keyspace_events: String,
+    /// Addresses to listen on.
+    #[arg(long, num_args = 1.., default_value = "127.0.0.1")]
+    bind: Vec<String>,
+    #[arg(long, default_value_t = 6379)]
+    port: u16,
+    /// Path of a Unix socket to listen on as well.
+    #[arg(long)]
+    unixsocket: Option<String>,
+    /// Allow the `DEBUG` command, which is meant for testing.
+    #[arg(long)]
+    enable_debug_command: bool,
+    /// Replicate the master at "<host> <port>".
+    #[arg(long)]
+    replicaof: Option<String>,
+    /// Commands that take longer than this many microseconds are recorded
+    /// in the slow log. Negative disables the slow log.
+    #[arg(long, default_value_t = 10000, allow_negative_numbers = true)]
+    slowlog_log_slower_than: i64,
+    /// How many commands the slow log keeps.
+    #[arg(long, default_value_t = 128)]
+    slowlog_max_len: u64,
+    /// How many clients can be connected at once. Connections beyond this
+    /// are refused.
+    #[arg(long, default_value_t = 10000)]
+    maxclients: u64,
+    /// How many threads read commands, each serving one connection at a
+    /// time, and so how many clients are served at once. Connections beyond
+    /// this wait for a thread to free up, and a client blocked in `BLPOP`
+    /// keeps its thread while it waits. Threads are reused from one
+    /// connection to the next.
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    io_threads: u64,
+    /// Close connections that send nothing for this many seconds. 0 never
+    /// closes them.
+    #[arg(long, default_value_t = 0)]
+    timeout: u64,
+    /// How much memory the server may use, in bytes or with a unit like
+    /// `100mb`. 0 means no limit.
+    #[arg(long, default_value = "0", value_parser = parse_memory)]
+    maxmemory: u64,
+    /// What to do when a write needs memory beyond `maxmemory`: refuse it
+    /// (`noeviction`), or evict the least recently used key
+    /// (`allkeys-lru`) or a random one (`allkeys-random`) until there's room.
+    #[arg(long, default_value = "noeviction", value_parser = ["noeviction", "allkeys-lru", "allkeys-random"])]
+    maxmemory_policy: String,
 }
 
-fn init_config(conf: &mut Config) {
+/// Fills in the config from the command line, and returns the master given
+/// with `--replicaof`, which isn't part of the config as `REPLICAOF` changes
+/// it.
+fn init_config(conf: &mut Config) -> Option<(String, u16)> {
     let args = Args::parse();
-    conf.dir = args.dir;
-    conf.db_filename = args.db_filename;
+    if let Some(dir) = args.dir {
+        conf.params.insert("dir".to_string(), dir);
+    }
+    if let Some(db_filename) = args.db_filename {
+        conf.params.insert("dbfilename".to_string(), db_filename);
+    }
+    conf.params.insert(
+        "active-expire-interval".to_string(),
+        args.active_expire_interval.to_string(),
+    );
+    conf.params.insert(
+        "notify-keyspace-events".to_string(),
+        args.notify_keyspace_events,
+    );
+    conf.params.insert("bind".to_string(), args.bind.join(" "));
+    conf.params
+        .insert("port".to_string(), args.port.to_string());
+    if let Some(unixsocket) = args.unixsocket {
+        conf.params.insert("unixsocket".to_string(), unixsocket);
+    }
+    conf.params.insert(
+        "enable-debug-command".to_string(),
+        if args.enable_debug_command {
+            "yes"
+        } else {
+            "no"
+        }
+        .to_string(),
+    );
+    conf.params.insert(
+        "slowlog-log-slower-than".to_string(),
+        args.slowlog_log_slower_than.to_string(),
+    );
+    conf.params.insert(
+        "slowlog-max-len".to_string(),
+        args.slowlog_max_len.to_string(),
+    );
+    conf.params
+        .insert("maxclients".to_string(), args.maxclients.to_string());
+    conf.params
+        .insert("io-threads".to_string(), args.io_threads.to_string());
+    conf.params
+        .insert("timeout".to_string(), args.timeout.to_string());
+    conf.params
+        .insert("maxmemory".to_string(), args.maxmemory.to_string());
+    conf.params
+        .insert("maxmemory-policy".to_string(), args.maxmemory_policy);
+    args.replicaof.map(|replicaof| {
+        let master = match replicaof.split_whitespace().collect::<Vec<_>>()[..] {
+            [host, port] => port.parse().ok().map(|port| (host.to_string(), port)),
+            _ => None,
+        };
+        master.unwrap_or_else(|| {
+            println!("error: invalid replicaof address {}", replicaof);
+            std::process::exit(1);
+        })
+    })
 }
 
-struct State {
-    config: Mutex<Config>,
-    storage: Mutex<HashMap<String, (Option<time::Instant>, Vec<u8>)>>,
+#[derive(Debug, Clone)]
+enum RedisValue {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    ZSet(SortedSet),
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
-    let mut config = Config::new();
-    init_config(&mut config);
-
-    let data_storage = collections::HashMap::<String, (Option<time::Instant>, Vec<u8>)>::new();
-
-    let state = Arc::new(State {
-        config: Mutex::new(config),
-        storage: Mutex::new(data_storage),
-    });
+impl RedisValue {
+    /// Name of the type of the value, as reported by `TYPE`.
+    fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::Set(_) => "set",
+            RedisValue::ZSet(_) => "zset",
+        }
+    }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(s) => {
-                let cloned_state = state.clone();
-                thread::spawn(move || handle(s, cloned_state));
+    /// Name of the encoding Redis would store the value in, as reported by
+    /// `OBJECT ENCODING` and `DEBUG OBJECT`. Small collections get a compact
+    /// encoding, using the default limits of Redis.
+    fn encoding(&self) -> &'static str {
+        match self {
+            RedisValue::String(s) if is_integer(s) => "int",
+            RedisValue::String(s) if s.len() <= EMBSTR_MAX_LEN => "embstr",
+            RedisValue::String(_) => "raw",
+            // Each element takes at least a couple of bytes of overhead.
+            RedisValue::List(list)
+                if list.iter().map(|item| item.len() + 2).sum::<usize>()
+                    <= LIST_LISTPACK_MAX_BYTES =>
+            {
+                "listpack"
             }
-            Err(e) => {
-                println!("error: {}", e);
+            RedisValue::List(_) => "quicklist",
+            RedisValue::Set(set)
+                if set.len() <= INTSET_MAX_ENTRIES
+                    && set.iter().all(|member| is_integer(member)) =>
+            {
+                "intset"
+            }
+            RedisValue::Set(set) if fits_listpack(set.len(), set.iter()) => "listpack",
+            RedisValue::Hash(hash)
+                if fits_listpack(
+                    hash.len(),
+                    hash.iter().flat_map(|(field, value)| [field, value]),
+                ) =>
+            {
+                "listpack"
             }
+            RedisValue::Set(_) | RedisValue::Hash(_) => "hashtable",
+            RedisValue::ZSet(zset) if fits_listpack(zset.len(), zset.members()) => "listpack",
+            RedisValue::ZSet(_) => "skiplist",
         }
     }
-}
 
-fn handle(mut stream: TcpStream, state: Arc<State>) {
-    let mut buf = [0u8; 1024];
-    loop {
-        let read_count = stream.read(&mut buf).expect("Could not read from client");
-        if read_count == 0 {
-            return;
-        }
-        let mut new_buf = Vec::new();
-        for i in 0..read_count {
-            new_buf.push(buf[i]);
-        }
-        match Command::from_buffer(&new_buf.as_slice()) {
-            Ok(Command::Ping) => {
-                stream.write_all(b"+PONG\r\n");
-            }
-            Ok(Command::Echo(s)) => {
-                let out = serialize_to_bulk_string(s.as_bytes());
-                stream.write_all(out.as_slice());
-            }
-            Ok(Command::Set(key, value, expiry)) => {
-                let mut storage = state.storage.lock().unwrap();
-                let expiry =
-                    expiry.map(|t| time::Instant::now() + time::Duration::from_millis(t as u64));
-                storage.insert(key, (expiry, value));
-                let out = serialize_to_simple_string("OK".as_bytes());
-                stream.write_all(out.as_slice());
-            }
-            Ok(Command::Get(key)) => {
-                let storage = state.storage.lock().unwrap();
-                match storage.get(&key) {
-                    Some((expiry, v)) => {
-                        if let Some(expiry) = expiry {
-                            if time::Instant::now() >= *expiry {
-                                stream.write_all(b"$-1\r\n");
-                            } else {
-                                let out = serialize_to_bulk_string(v);
-                                stream.write_all(out.as_slice());
-                            }
-                        } else {
-                            let out = serialize_to_bulk_string(v);
-                            stream.write_all(out.as_slice());
-                        }
-                    }
-                    None => {
-                        stream.write_all(b"$-1\r\n");
-                    }
-                }
+    /// Estimates the bytes the value's contents take up, for `MEMORY USAGE`.
+    /// Only the first `samples` elements of a collection are measured, and
+    /// the rest assumed to be the same size, or every element with `None`.
+    fn memory_usage(&self, samples: Option<usize>) -> usize {
+        match self {
+            RedisValue::String(s) => s.capacity(),
+            RedisValue::List(list) => {
+                list.capacity() * size_of::<Vec<u8>>()
+                    + sampled_size(list.iter().map(|item| item.capacity()), list.len(), samples)
             }
-            Ok(Command::ConfigGet(key)) => {
-                if !["dir", "dbfilename"].contains(&key.as_str()) {
-                    stream.write_all(b"-Error\r\n");
-                } else {
-                    let config = state.config.lock().unwrap();
-                    match key.as_str() {
-                        "dir" => match config.dir.clone() {
-                            Some(dir) => {
-                                let out = serialize_to_array(&["dir".as_bytes(), dir.as_bytes()]);
-                                stream.write_all(out.as_slice());
-                            }
-                            None => {
-                                stream.write_all(b"-Error\r\n");
-                            }
-                        },
-                        "dbfilename" => match config.db_filename.clone() {
-                            Some(db_filename) => {
-                                let out = serialize_to_array(&[
-                                    "dbfilename".as_bytes(),
-                                    db_filename.as_bytes(),
-                                ]);
-                                stream.write_all(out.as_slice());
-                            }
-                            None => {}
-                        },
-                        _ => {
-                            stream.write_all(b"-Error\r\n");
-                        }
-                    }
-                }
+            // Hash tables take a control byte for each slot.
+            RedisValue::Hash(hash) => {
+                hash.capacity() * (size_of::<(Vec<u8>, Vec<u8>)>() + 1)
+                    + sampled_size(
+                        hash.iter()
+                            .map(|(field, value)| field.capacity() + value.capacity()),
+                        hash.len(),
+                        samples,
+                    )
             }
-            Err(_) => {
-                stream.write_all(b"-Error\r\n");
+            RedisValue::Set(set) => {
+                set.capacity() * (size_of::<Vec<u8>>() + 1)
+                    + sampled_size(
+                        set.iter().map(|member| member.capacity()),
+                        set.len(),
+                        samples,
+                    )
             }
+            // Each member is kept twice, once with its score by name and
+            // once in a node of the tree that keeps them in score order.
+            RedisValue::ZSet(zset) => sampled_size(
+                zset.iter().map(|(member, _)| {
+                    2 * member.len() + size_of::<(Vec<u8>, f64)>() + 1 + zset::NODE_SIZE
+                }),
+                zset.len(),
+                samples,
+            ),
         }
     }
 }
 
-fn serialize_to_array(strings: &[&[u8]]) -> Vec<u8> {
-    [
-        b"*",
-        format!("{}", strings.len()).as_bytes(),
-        b"\r\n",
-        strings
-            .iter()
-            .map(|s| serialize_to_bulk_string(s))
-            .collect::<Vec<_>>()
-            .concat()
-            .as_slice(),
-    ]
-    .concat()
+/// The total of `len` sizes, measuring the first `samples` of them and
+/// assuming the rest average the same, or measuring all of them with `None`.
+fn sampled_size(sizes: impl Iterator<Item = usize>, len: usize, samples: Option<usize>) -> usize {
+    let samples = samples.unwrap_or(len).min(len);
+    if samples == 0 {
+        return 0;
+    }
+    sizes.take(samples).sum::<usize>() * len / samples
 }
-fn serialize_to_simple_string(s: &[u8]) -> Vec<u8> {
-    [b"+", s, b"\r\n"].concat()
+
+/// Longest string Redis stores in the same allocation as its object.
+const EMBSTR_MAX_LEN: usize = 44;
+/// Largest list Redis keeps in a single listpack, in bytes.
+const LIST_LISTPACK_MAX_BYTES: usize = 8 * 1024;
+/// Most members of a set of integers Redis keeps in an intset.
+const INTSET_MAX_ENTRIES: usize = 512;
+/// Most elements of a set, hash or sorted set Redis keeps in a listpack.
+const LISTPACK_MAX_ENTRIES: usize = 128;
+/// Longest element of a set, hash or sorted set Redis keeps in a listpack.
+const LISTPACK_MAX_VALUE: usize = 64;
+
+/// Whether a set, hash or sorted set of `len` elements with the given
+/// members, or fields and values, is small enough for Redis to keep in a listpack.
+fn fits_listpack<'a>(len: usize, mut values: impl Iterator<Item = &'a Vec<u8>>) -> bool {
+    len <= LISTPACK_MAX_ENTRIES && values.all(|value| value.len() <= LISTPACK_MAX_VALUE)
 }
 
-fn serialize_to_bulk_string(s: &[u8]) -> Vec<u8> {
-    [b"$", format!("{}", s.len()).as_bytes(), b"\r\n", s, b"\r\n"].concat()
+/// Whether a string is an integer in canonical form, which Redis stores as a
+/// number.
+fn is_integer(s: &[u8]) -> bool {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .is_some_and(|n| n.to_string().as_bytes() == s)
 }
 
-struct Config {
-    dir: Option<String>,
-    db_filename: Option<String>,
+type Entry = (Option<time::Instant>, RedisValue, LastAccess);
+type Storage = HashMap<Vec<u8>, Entry>;
+type Blocked = HashMap<Vec<u8>, VecDeque<Arc<Waker>>>;
+
+/// What a client blocked in `BLPOP` waits on. The flag remembers a wakeup
+/// that comes after the client lets go of its keys but before it starts
+/// waiting.
+#[derive(Default)]
+struct Waker {
+    woken: Mutex<bool>,
+    condvar: Condvar,
 }
 
-impl Config {
+impl Waker {
+    fn wake(&self) {
+        *self.woken.lock().unwrap() = true;
+        self.condvar.notify_one();
+    }
+
+    /// Waits until woken or until `timeout` has passed.
+    fn wait(&self, timeout: Option<time::Duration>) {
+        let woken = self.woken.lock().unwrap();
+        let mut woken = match timeout {
+            Some(timeout) => {
+                self.condvar
+                    .wait_timeout_while(woken, timeout, |woken| !*woken)
+                    .unwrap()
+                    .0
+            }
+            None => self.condvar.wait_while(woken, |woken| !*woken).unwrap(),
+        };
+        *woken = false;
+    }
+}
+
+/// When a key was last looked up or written, in milliseconds since the
+/// server started. It's atomic so commands that only read, and so share the
+/// lock, can update it too.
+#[derive(Debug)]
+struct LastAccess(AtomicU64);
+
+impl LastAccess {
+    fn touch(&self) {
+        self.0.store(uptime_millis(), Ordering::Relaxed);
+    }
+
+    /// A key last accessed `idle` ago, as loaded from an RDB file, or when
+    /// the server started if that's more recent.
+    fn idle_for(idle: time::Duration) -> Self {
+        let idle = idle.as_millis().min(u64::MAX as u128) as u64;
+        Self(AtomicU64::new(uptime_millis().saturating_sub(idle)))
+    }
+
+    /// How long it's been since the key was last accessed.
+    fn idle(&self) -> time::Duration {
+        time::Duration::from_millis(uptime_millis().saturating_sub(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// A new key counts as just accessed.
+impl Default for LastAccess {
+    fn default() -> Self {
+        Self(AtomicU64::new(uptime_millis()))
+    }
+}
+
+impl Clone for LastAccess {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+/// Milliseconds since the server started, on a clock that never goes back.
+fn uptime_millis() -> u64 {
+    static START: OnceLock<time::Instant> = OnceLock::new();
+    START.get_or_init(time::Instant::now).elapsed().as_millis() as u64
+}
+
+/// The Redis version this server reports itself as compatible with.
+const REDIS_VERSION: &str = "7.2.0";
+
+/// Every command the server implements, as counted by `COMMAND COUNT`.
+const COMMAND_NAMES: &[&str] = &[
+    "APPEND",
+    "BGSAVE",
+    "BLPOP",
+    "CLIENT",
+    "COMMAND",
+    "CONFIG",
+    "COPY",
+    "DEBUG",
+    "DECR",
+    "DECRBY",
+    "DEL",
+    "DISCARD",
+    "ECHO",
+    "EXEC",
+    "EXISTS",
+    "EXPIRE",
+    "EXPIREAT",
+    "FLUSHALL",
+    "FLUSHDB",
+    "GET",
+    "GETDEL",
+    "GETEX",
+    "GETRANGE",
+    "GETSET",
+    "HDEL",
+    "HELLO",
+    "HEXISTS",
+    "HGET",
+    "HGETALL",
+    "HINCRBY",
+    "HINCRBYFLOAT",
+    "HKEYS",
+    "HLEN",
+    "HMGET",
+    "HSET",
+    "HSETNX",
+    "HVALS",
+    "INCR",
+    "INCRBY",
+    "INCRBYFLOAT",
+    "INFO",
+    "KEYS",
+    "LASTSAVE",
+    "LINDEX",
+    "LLEN",
+    "LMOVE",
+    "LPOP",
+    "LPOS",
+    "LPUSH",
+    "LRANGE",
+    "MEMORY",
+    "MGET",
+    "MONITOR",
+    "MSET",
+    "MULTI",
+    "OBJECT",
+    "PERSIST",
+    "PEXPIRE",
+    "PEXPIREAT",
+    "PING",
+    "PSETEX",
+    "PSUBSCRIBE",
+    "PSYNC",
+    "PTTL",
+    "PUBLISH",
+    "PUNSUBSCRIBE",
+    "RANDOMKEY",
+    "REPLCONF",
+    "REPLICAOF",
+    "RPOP",
+    "RPOPLPUSH",
+    "RPUSH",
+    "SADD",
+    "SCARD",
+    "SDIFF",
+    "SELECT",
+    "SET",
+    "SETEX",
+    "SETNX",
+    "SETRANGE",
+    "SINTER",
+    "SISMEMBER",
+    "SLOWLOG",
+    "SLAVEOF",
+    "SMEMBERS",
+    "SREM",
+    "STRLEN",
+    "SUBSCRIBE",
+    "SUBSTR",
+    "SUNION",
+    "TIME",
+    "TOUCH",
+    "TTL",
+    "TYPE",
+    "UNLINK",
+    "UNSUBSCRIBE",
+    "UNWATCH",
+    "WAIT",
+    "WATCH",
+    "ZADD",
+    "ZCARD",
+    "ZCOUNT",
+    "ZRANGE",
+    "ZRANGEBYSCORE",
+    "ZRANK",
+    "ZREM",
+    "ZREVRANK",
+    "ZSCORE",
+];
+
+/// Collections with more elements than this are freed by `UNLINK` on another
+/// thread, like in Redis.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Longest string a value can grow to, like Redis's `proto-max-bulk-len`.
+const STRING_MAX_LEN: usize = 512 * 1024 * 1024;
+
+/// Longest inline command accepted, like Redis's limit.
+const INLINE_MAX_LEN: usize = 64 * 1024;
+
+/// Most arguments a command can be sent with, like Redis's limit.
+const MULTIBULK_MAX_LEN: usize = 1024 * 1024;
+
+/// Number of logical databases clients can `SELECT`.
+const DB_COUNT: usize = 16;
+
+struct State {
+    config: Mutex<Config>,
+    /// The keyspace of each logical database.
+    storage: Vec<Keyspace>,
+    /// Clients blocked in `BLPOP`, queued per key in the order they started
+    /// waiting. Each client waits on its own `Waker`, so a push of `n`
+    /// elements wakes only the `n` longest waiting clients instead of every
+    /// blocked client. Always lock `storage` before this. Indexed by
+    /// database, like `storage`.
+    blocked: Vec<Mutex<Blocked>>,
+    /// Set while a `BGSAVE` is writing a snapshot, so only one runs at once.
+    bgsave_in_progress: AtomicBool,
+    /// Unix time in seconds of the last successful save.
+    rdb_last_save_time: AtomicI64,
+    /// Id given to the next client that connects.
+    next_client_id: AtomicU64,
+    /// Connected clients by id.
+    clients: Mutex<HashMap<u64, Client>>,
+    /// Held exclusively by `EXEC` so the commands of a transaction run
+    /// without others in between, and shared by every other command. Always
+    /// lock this before `storage`.
+    exec_lock: RwLock<()>,
+    /// Clients subscribed to each Pub/Sub channel, by client id.
+    channels: Mutex<HashMap<Vec<u8>, HashMap<u64, Subscriber>>>,
+    /// Clients subscribed to each Pub/Sub pattern, by client id.
+    patterns: Mutex<HashMap<Vec<u8>, HashMap<u64, Subscriber>>>,
+    start_time: time::Instant,
+    /// Set once a shutdown signal is received, so no more clients are
+    /// accepted.
+    shutting_down: AtomicBool,
+    replication: Mutex<Replication>,
+    /// Clients that ran `MONITOR`, by client id. Every command any client
+    /// sends is formatted and sent to each of them, so monitoring slows the
+    /// whole server down.
+    monitors: Mutex<HashMap<u64, Sender<Vec<u8>>>>,
+    /// How many clients are in `monitors`, so commands can skip formatting
+    /// themselves without taking its lock when there are none.
+    monitor_count: AtomicUsize,
+    /// Whether `replication` has a master, so writes can be refused without
+    /// taking its lock.
+    is_replica: AtomicBool,
+    /// How many replicas `replication` had when last looked at, so writes
+    /// aren't propagated when there are none. Only changed while holding
+    /// `replication`.
+    replica_count: AtomicUsize,
+    /// `timeout` in seconds, or 0 to never time out clients, copied out of
+    /// `config` like the settings below by `State::apply_config`.
+    timeout: AtomicU64,
+    /// `maxmemory` in bytes, or 0 for no limit.
+    maxmemory: AtomicU64,
+    /// `notify-keyspace-events`, as `Config::notify_keyspace_events` gives
+    /// it.
+    keyspace_events: AtomicU32,
+    slowlog: Mutex<SlowLog>,
+    /// Connections accepted and not yet closed, held to `maxclients`.
+    connections: AtomicU64,
+    /// Threads that read the commands of each connection, at most
+    /// `io-threads` of them. Connections accepted while they're all busy
+    /// wait in its queue.
+    readers: ThreadPool,
+    /// Threads that write replies to each connection. One is needed for
+    /// every connection a reader serves, so there's no limit here; were
+    /// there one, a connection could be left waiting for a writer that
+    /// never frees up. There are never many more than readers.
+    writers: ThreadPool,
+    /// Whether expired keys are removed in the background, rather than only
+    /// when they're accessed. Turned off with `DEBUG SET-ACTIVE-EXPIRE 0`.
+    active_expire: AtomicBool,
+    /// Values `UNLINK` left to a single background thread to free.
+    lazyfree: Sender<RedisValue>,
+}
+
+/// Commands that took longer than `slowlog-log-slower-than`, newest first,
+/// keeping at most `slowlog-max-len` of them.
+#[derive(Default)]
+struct SlowLog {
+    entries: VecDeque<SlowLogEntry>,
+    /// Id given to the next entry. Not reset by `SLOWLOG RESET`.
+    next_id: u64,
+}
+
+struct SlowLogEntry {
+    id: u64,
+    /// Unix time in seconds when the command was run.
+    timestamp: i64,
+    duration: time::Duration,
+    /// The command's arguments, shortened as `slowlog_args` does.
+    args: Vec<Vec<u8>>,
+    /// Address of the client that ran the command.
+    addr: String,
+    /// Name of the client that ran the command, empty if it has none.
+    name: String,
+}
+
+/// Most arguments of a command kept in a slow log entry.
+const SLOWLOG_ENTRY_MAX_ARGC: usize = 32;
+/// Most bytes of an argument kept in a slow log entry.
+const SLOWLOG_ENTRY_MAX_STRING: usize = 128;
+
+/// The replicas attached to this server, the stream of writes sent to them,
+/// and the master this server replicates if it's a replica itself.
+struct Replication {
+    /// Identifies the history of writes this server's data set comes from.
+    /// Like Redis, a random 40 character hex string picked at startup, so a
+    /// replica that reconnects after a restart knows it must resync in full.
+    /// A replica uses its master's.
+    replid: String,
+    /// How many bytes of commands have been propagated, or on a replica,
+    /// received from the master.
+    offset: u64,
+    /// The database the last propagated command ran in, or `None` if a
+    /// `SELECT` must be sent before the next one.
+    db: Option<usize>,
+    /// The replicas attached to this server, by client id.
+    replicas: HashMap<u64, Replica>,
+    /// The master set with `REPLICAOF`, or `None` if this server isn't a
+    /// replica.
+    master: Option<Master>,
+    /// Bumped by every `REPLICAOF`, so the thread replicating a master can
+    /// tell it's been replaced.
+    generation: u64,
+}
+
+impl Replication {
     fn new() -> Self {
         Self {
-            dir: None,
-            db_filename: None,
+            replid: random_replid(),
+            offset: 0,
+            db: None,
+            replicas: HashMap::new(),
+            master: None,
+            generation: 0,
         }
     }
 }
 
-#[derive(Debug)]
-enum Command {
-    Ping,
-    Echo(String),
-    Set(String, Vec<u8>, Option<u64>),
-    Get(String),
-    ConfigGet(String),
+/// A replica attached to this server with `PSYNC`.
+struct Replica {
+    /// Where to send writes.
+    sender: Sender<Vec<u8>>,
+    /// The offset the replica last acknowledged with `REPLCONF ACK`.
+    ack_offset: u64,
+}
+
+/// The master this server replicates.
+struct Master {
+    host: String,
+    port: u16,
+    /// The connection to the master, for closing it when replication stops,
+    /// or `None` while connecting.
+    link: Option<TcpStream>,
+    /// Set once the master's snapshot has been loaded.
+    synced: bool,
+}
+
+/// A random 40 character hex string, for identifying the history of writes
+/// a data set comes from.
+fn random_replid() -> String {
+    format!(
+        "{:016x}{:016x}{:08x}",
+        random_u64(),
+        random_u64(),
+        random_u64() as u32
+    )
+}
+
+/// A connected client, as listed by `CLIENT LIST`.
+struct Client {
+    addr: String,
+    /// Name set with `CLIENT SETNAME`, empty if none was.
+    name: String,
+    connected_at: time::Instant,
+    db: usize,
+    /// A handle on the client's socket, for shutting it down with
+    /// `CLIENT KILL`.
+    stream: Box<dyn Connection>,
+}
+
+/// A client's connection, over TCP or a Unix socket.
+trait Connection: Read + Write + Send {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>>;
+    /// Makes reads fail once nothing has arrived for `timeout`, or wait
+    /// forever if it's `None`.
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()>;
+    /// Shuts down both directions of the connection.
+    fn close(&self);
+    /// The client's address, as shown by `CLIENT LIST`.
+    fn addr(&self) -> String;
+}
+
+impl Connection for TcpStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    fn addr(&self) -> String {
+        self.peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
+    }
+}
+
+impl Connection for UnixStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+
+    /// Clients of a Unix socket have no address of their own, so like Redis
+    /// use the socket's path.
+    fn addr(&self) -> String {
+        let path = self
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+            .unwrap_or_default();
+        format!("{}:0", path)
+    }
 }
 
-enum DataType {
-    SimpleString,
-    SimpleErr,
-    Integer,
-    BulkString,
-    Array,
+/// Keeps a client registered in `State::clients` until it's dropped, however
+/// the connection ends.
+struct ConnectedClient<'a> {
+    state: &'a State,
+    id: u64,
 }
 
-impl DataType {
-    fn from_byte(b: u8) -> Self {
-        match b {
-            b'+' => Self::SimpleString,
-            b'-' => Self::SimpleErr,
-            b':' => Self::Integer,
-            b'$' => Self::BulkString,
-            b'*' => Self::Array,
-            _ => unimplemented!(),
+impl<'a> ConnectedClient<'a> {
+    fn new(state: &'a State, stream: Box<dyn Connection>) -> Self {
+        let id = state.next_client_id.fetch_add(1, Ordering::SeqCst);
+        let client = Client {
+            addr: stream.addr(),
+            name: String::new(),
+            connected_at: time::Instant::now(),
+            db: 0,
+            stream,
+        };
+        state.clients.lock().unwrap().insert(id, client);
+        Self { state, id }
+    }
+
+    /// Updates this client's entry in the registry.
+    fn update<F: FnOnce(&mut Client)>(&self, f: F) {
+        if let Some(client) = self.state.clients.lock().unwrap().get_mut(&self.id) {
+            f(client);
         }
     }
 }
 
-#[derive(Debug)]
-enum RedisObject {
-    SimpleString(String),
-    SimpleErr(String),
-    Integer(i32),
-    BulkString(usize, String),
-    Array(Vec<RedisObject>),
+impl Drop for ConnectedClient<'_> {
+    fn drop(&mut self) {
+        self.state.clients.lock().unwrap().remove(&self.id);
+    }
 }
 
-struct RESPParser<'a> {
-    stream: &'a [u8],
+impl State {
+    /// The state of a server that starts out with `databases`, last saved
+    /// at `last_save_time` in seconds since the epoch.
+    fn new(config: Config, databases: Vec<Storage>, last_save_time: i64) -> Self {
+        let io_threads = config.io_threads();
+        let (lazyfree, freed) = mpsc::channel::<RedisValue>();
+        thread::spawn(move || freed.into_iter().for_each(drop));
+        let state = Self {
+            config: Mutex::new(config),
+            storage: databases.into_iter().map(Keyspace::new).collect(),
+            blocked: (0..DB_COUNT).map(|_| Mutex::new(HashMap::new())).collect(),
+            bgsave_in_progress: AtomicBool::new(false),
+            rdb_last_save_time: AtomicI64::new(last_save_time),
+            next_client_id: AtomicU64::new(1),
+            clients: Mutex::new(HashMap::new()),
+            exec_lock: RwLock::new(()),
+            channels: Mutex::new(HashMap::new()),
+            patterns: Mutex::new(HashMap::new()),
+            start_time: time::Instant::now(),
+            shutting_down: AtomicBool::new(false),
+            replication: Mutex::new(Replication::new()),
+            monitors: Mutex::new(HashMap::new()),
+            monitor_count: AtomicUsize::new(0),
+            is_replica: AtomicBool::new(false),
+            replica_count: AtomicUsize::new(0),
+            timeout: AtomicU64::new(0),
+            maxmemory: AtomicU64::new(0),
+            keyspace_events: AtomicU32::new(0),
+            slowlog: Mutex::new(SlowLog::default()),
+            connections: AtomicU64::new(0),
+            readers: ThreadPool::new(io_threads),
+            writers: ThreadPool::new(usize::MAX),
+            active_expire: AtomicBool::new(true),
+            lazyfree,
+        };
+        state.apply_config(&state.config.lock().unwrap());
+        state
+    }
+
+    /// Copies the settings read by every command out of `config`, which
+    /// must be called whenever it changes.
+    fn apply_config(&self, config: &Config) {
+        let timeout = config.timeout().map_or(0, |timeout| timeout.as_secs());
+        self.timeout.store(timeout, Ordering::Relaxed);
+        let maxmemory = config.maxmemory().unwrap_or(0) as u64;
+        self.maxmemory.store(maxmemory, Ordering::Relaxed);
+        let events = config.notify_keyspace_events();
+        self.keyspace_events.store(events, Ordering::Relaxed);
+    }
+
+    /// How long clients may be idle, or `None` if they never time out.
+    fn timeout(&self) -> Option<time::Duration> {
+        let seconds = self.timeout.load(Ordering::Relaxed);
+        (seconds > 0).then(|| time::Duration::from_secs(seconds))
+    }
 }
 
-impl<'a> RESPParser<'a> {
-    fn new(stream: &'a [u8]) -> Self {
-        Self { stream }
+fn main() {
+    let mut config = Config::new();
+    let replicaof = init_config(&mut config);
+    let listeners = bind_listeners(&config);
+    let unix_listener = config.get("unixsocket").map(bind_unix_listener);
+
+    let mut last_save_time = unix_millis_now() / 1000;
+    let mut databases = (0..DB_COUNT).map(|_| Storage::new()).collect::<Vec<_>>();
+    match (config.get("dir"), config.get("dbfilename")) {
+        (Some(dir), Some(db_filename)) if Path::new(dir).join(db_filename).exists() => {
+            match RDBObject::from_file(&Path::new(dir).join(db_filename)) {
+                Ok(rdb) => {
+                    // The file was last saved when it was last written.
+                    if let Some(modified) = fs::metadata(Path::new(dir).join(db_filename))
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.duration_since(time::UNIX_EPOCH).ok())
+                    {
+                        last_save_time = modified.as_secs() as i64;
+                    }
+                    for (index, storage) in rdb.databases {
+                        let Some(database) = databases.get_mut(index) else {
+                            println!("error: RDB file uses database {} of {}", index, DB_COUNT);
+                            std::process::exit(1);
+                        };
+                        *database = storage;
+                    }
+                }
+                Err(_) => {
+                    println!("error: could not load RDB file {}/{}", dir, db_filename);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let state = Arc::new(State::new(config, databases, last_save_time));
+
+    let expire_state = state.clone();
+    thread::spawn(move || loop {
+        let interval = expire_state.config.lock().unwrap().active_expire_interval();
+        thread::sleep(interval);
+        if !expire_state.active_expire.load(Ordering::SeqCst) {
+            continue;
+        }
+        for db in 0..DB_COUNT {
+            active_expire_cycle(&expire_state, db, interval / 4 / DB_COUNT as u32);
+        }
+    });
+
+    if let Some(master) = replicaof {
+        set_master(&state, Some(master));
+    }
+
+    for listener in listeners {
+        let state = state.clone();
+        thread::spawn(move || accept_connections(listener.incoming(), state));
+    }
+    if let Some(listener) = unix_listener {
+        let state = state.clone();
+        thread::spawn(move || accept_connections(listener.incoming(), state));
     }
+    wait_for_signal();
+    shutdown(&state);
+}
+
+/// Blocks until the process receives SIGINT or SIGTERM.
+fn wait_for_signal() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    });
+}
 
-    fn parse(&mut self) -> Result<RedisObject, ()> {
-        match Self::parse_object(self.stream) {
-            Ok((Some(object), _)) => Ok(object),
-            Ok(_) => Err(()),
-            Err(_) => Err(()),
+/// Stops accepting clients, waits for the commands being run to finish, then
+/// saves the databases if they're persisted to a file and removes the Unix
+/// socket.
+fn shutdown(state: &State) {
+    state.shutting_down.store(true, Ordering::SeqCst);
+    // Every command but a waiting `BLPOP` holds `exec_lock`, so this lets
+    // them finish and keeps new ones from starting.
+    let _exclusive = state.exec_lock.write().unwrap();
+    let (path, persisted, unixsocket) = {
+        let config = state.config.lock().unwrap();
+        (
+            rdb_path(&config),
+            config.get("dbfilename").is_some(),
+            config.get("unixsocket").map(str::to_string),
+        )
+    };
+    if persisted {
+        // Wait for a `BGSAVE` to finish writing, and stop another starting.
+        while state.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        if let Err(e) = snapshot(state).to_file(&path) {
+            println!("error: could not save RDB file: {}", e);
         }
     }
+    if let Some(unixsocket) = unixsocket {
+        let _ = fs::remove_file(unixsocket);
+    }
+}
+
+/// Binds a listener to each of the `bind` addresses on `port`, exiting if
+/// any of them can't be used.
+fn bind_listeners(config: &Config) -> Vec<TcpListener> {
+    let port = config.port();
+    config
+        .get("bind")
+        .unwrap_or("127.0.0.1")
+        .split_whitespace()
+        .map(|addr| {
+            let Ok(ip) = addr.parse::<IpAddr>() else {
+                println!("error: invalid bind address {}", addr);
+                std::process::exit(1);
+            };
+            let addr = SocketAddr::new(ip, port);
+            TcpListener::bind(addr).unwrap_or_else(|e| {
+                println!("error: could not bind to {}: {}", addr, e);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+/// Binds a listener to the Unix socket at `path`, replacing a file left
+/// behind by a previous run, and exiting if that fails.
+fn bind_unix_listener(path: &str) -> UnixListener {
+    if Path::new(path).exists() {
+        let _ = fs::remove_file(path);
+    }
+    UnixListener::bind(path).unwrap_or_else(|e| {
+        println!("error: could not bind to {}: {}", path, e);
+        std::process::exit(1);
+    })
+}
 
-    fn parse_object(stream: &[u8]) -> Result<(Option<RedisObject>, usize), ()> {
-        if stream[0..2] == *b"\r\n" {
-            return Ok((None, 2));
+fn accept_connections<S, I>(incoming: I, state: Arc<State>)
+where
+    S: Connection + 'static,
+    I: Iterator<Item = io::Result<S>>,
+{
+    for stream in incoming {
+        if state.shutting_down.load(Ordering::SeqCst) {
+            break;
         }
-        match DataType::from_byte(stream[0]) {
-            DataType::Array => RESPParser::parse_array(&stream[1..]),
-            DataType::SimpleString => {
-                let parts = split_by_line(&stream[1..]);
-                Ok((
-                    Some(RedisObject::SimpleString(
-                        String::from_utf8(parts[0].clone()).unwrap(),
-                    )),
-                    parts[0].len(),
-                ))
+        match stream {
+            Ok(mut s) => {
+                let Some(slot) = ConnectionSlot::take(&state) else {
+                    // Like Redis, tell the client why before hanging up.
+                    let _ = s.write_all(&serialize_to_error(
+                        CommandError::MaxClients.to_string().as_bytes(),
+                    ));
+                    s.close();
+                    continue;
+                };
+                let cloned_state = state.clone();
+                state.readers.execute(move || {
+                    let _slot = slot;
+                    handle(s, cloned_state)
+                });
             }
-            DataType::Integer => {
-                let parts = split_by_line(&stream[1..]);
-                Ok((
-                    Some(RedisObject::Integer(
-                        String::from_utf8(parts[0].clone())
-                            .unwrap()
-                            .parse::<i32>()
-                            .unwrap(),
-                    )),
-                    parts[0].len(),
-                ))
+            Err(e) => {
+                println!("error: {}", e);
             }
-            DataType::BulkString => {
-                let parts = split_by_line(&stream[1..]);
-                let Ok(size) = String::from_utf8(parts[0].clone())
-                    .unwrap()
-                    .parse::<usize>()
-                else {
-                    panic!("invalid string");
-                };
-                let string = String::from_utf8(parts[1].clone()).unwrap();
-                assert!(string.len() == size as usize);
-                Ok((
-                    Some(RedisObject::BulkString(size, string)),
-                    parts[0].len() + parts[1].len() + 3,
-                ))
+        }
+    }
+}
+
+/// One of the `maxclients` connections, given back when it's dropped so the
+/// count stays right however the handler ends, even by panicking.
+struct ConnectionSlot {
+    state: Arc<State>,
+}
+
+impl ConnectionSlot {
+    /// Takes a slot for a new connection, or returns `None` if there are
+    /// already `maxclients` connections.
+    fn take(state: &Arc<State>) -> Option<Self> {
+        let max = state.config.lock().unwrap().maxclients();
+        state
+            .connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connections| {
+                (connections < max).then_some(connections + 1)
+            })
+            .ok()
+            .map(|_| Self {
+                state: state.clone(),
+            })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.state.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The state of a single client connection.
+struct Session<'a> {
+    client: ConnectedClient<'a>,
+    /// The database selected with `SELECT`.
+    db: usize,
+    /// The RESP version negotiated with `HELLO`.
+    protocol: i64,
+    /// Set when the client disconnects itself with `CLIENT KILL`.
+    killed_self: bool,
+    /// Commands queued since `MULTI`, along with how they were sent, or
+    /// `None` outside a transaction.
+    queued: Option<Vec<(Command, Vec<u8>)>>,
+    /// Set when a command couldn't be queued, so `EXEC` must fail.
+    aborted: bool,
+    /// Set while `EXEC` runs the queued commands.
+    in_exec: bool,
+    /// Keys watched with `WATCH`, by database, with their versions at the
+    /// time.
+    watched: Vec<(usize, Vec<u8>, u64)>,
+    /// Replies and published messages waiting to be written to the client.
+    sender: Sender<Vec<u8>>,
+    /// Replies to the commands received so far, sent together once every
+    /// complete command received has been handled.
+    replies: Vec<u8>,
+    /// Channels subscribed to with `SUBSCRIBE`.
+    channels: HashSet<Vec<u8>>,
+    /// Patterns subscribed to with `PSUBSCRIBE`.
+    patterns: HashSet<Vec<u8>>,
+    /// Set on the connection to this server's master, whose writes are
+    /// passed on to replicas just as they were received.
+    master: bool,
+    /// Set once the client has run `MONITOR`, after which it only receives
+    /// the commands other clients send.
+    monitoring: bool,
+    /// Set once the client has run `PSYNC`, after which it's a replica that
+    /// only receives the writes propagated to it.
+    replica: bool,
+}
+
+impl Session<'_> {
+    /// Subscribes to a channel, or to a pattern if `pattern` is set.
+    fn subscribe(&mut self, name: Vec<u8>, pattern: bool) {
+        let subscriber = Subscriber {
+            sender: self.sender.clone(),
+            protocol: self.protocol,
+        };
+        let state = self.client.state;
+        let (registry, names) = if pattern {
+            (&state.patterns, &mut self.patterns)
+        } else {
+            (&state.channels, &mut self.channels)
+        };
+        registry
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .insert(self.client.id, subscriber);
+        names.insert(name);
+    }
+
+    /// Unsubscribes from a channel, or from a pattern if `pattern` is set.
+    fn unsubscribe(&mut self, name: &[u8], pattern: bool) {
+        let state = self.client.state;
+        let (registry, names) = if pattern {
+            (&state.patterns, &mut self.patterns)
+        } else {
+            (&state.channels, &mut self.channels)
+        };
+        let mut registry = registry.lock().unwrap();
+        if let Some(subscribers) = registry.get_mut(name) {
+            subscribers.remove(&self.client.id);
+            if subscribers.is_empty() {
+                registry.remove(name);
             }
-            _ => unimplemented!("type not implemented"),
         }
+        names.remove(name);
     }
 
-    fn parse_array(stream: &[u8]) -> Result<(Option<RedisObject>, usize), ()> {
-        let parts = split_by_line(stream);
-        let _size = String::from_utf8(parts[0].clone())
-            .expect("invalid string")
-            .parse::<usize>()
-            .expect("invalid string");
-        let mut objects = vec![];
-        let mut pos: usize = parts[0].len() + 2;
-        loop {
-            match RESPParser::parse_object(&stream[pos..]) {
-                Ok((Some(object), consumed)) => {
-                    objects.push(object);
-                    pos += consumed;
-                    if pos > stream.len() {
-                        break;
-                    }
+    /// Stops watching every key watched with `WATCH`.
+    fn unwatch(&mut self) {
+        let state = self.client.state;
+        for (db, key, _) in self.watched.drain(..) {
+            state.storage[db].unwatch(&key);
+        }
+    }
+
+    /// Sends the replies held back so far, so anything sent straight to
+    /// `sender` afterwards comes after them. Fails once the client is gone.
+    fn flush_replies(&mut self) -> Result<(), mpsc::SendError<Vec<u8>>> {
+        if self.replies.is_empty() {
+            return Ok(());
+        }
+        self.sender.send(std::mem::take(&mut self.replies))
+    }
+
+    /// The number of channels and patterns subscribed to, as reported in
+    /// subscription replies.
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// In RESP2 a client with subscriptions can only manage them.
+    fn is_subscribed_resp2(&self) -> bool {
+        self.protocol == 2 && self.subscriptions() > 0
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let state = self.client.state;
+        let mut replication = state.replication.lock().unwrap();
+        replication.replicas.remove(&self.client.id);
+        state
+            .replica_count
+            .store(replication.replicas.len(), Ordering::Relaxed);
+        drop(replication);
+        let mut monitors = state.monitors.lock().unwrap();
+        monitors.remove(&self.client.id);
+        state.monitor_count.store(monitors.len(), Ordering::Relaxed);
+        drop(monitors);
+        self.unwatch();
+        for channel in self.channels.clone() {
+            self.unsubscribe(&channel, false);
+        }
+        for pattern in self.patterns.clone() {
+            self.unsubscribe(&pattern, true);
+        }
+    }
+}
+
+/// A client subscribed to a Pub/Sub channel.
+struct Subscriber {
+    sender: Sender<Vec<u8>>,
+    /// The RESP version the client used when it subscribed.
+    protocol: i64,
+}
+
+/// Serves a client until it disconnects. Any stream will do, as long as it
+/// can be cloned for writing replies and shut down by `CLIENT KILL`.
+fn handle<S: Connection>(mut stream: S, state: Arc<State>) {
+    // Replies are written by a separate thread, so messages published to the
+    // client's channels can be sent while this one is waiting for commands.
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    // Running out of file descriptors only costs this client its
+    // connection.
+    let (Ok(writer), Ok(killer)) = (stream.try_clone_box(), stream.try_clone_box()) else {
+        stream.close();
+        return;
+    };
+    let mut writer = io::BufWriter::new(writer);
+    state.writers.execute(move || {
+        // Write everything queued so far before flushing, so replies and
+        // messages that pile up go out together.
+        while let Ok(out) = receiver.recv() {
+            let written = iter::once(out)
+                .chain(receiver.try_iter())
+                .try_for_each(|out| writer.write_all(&out))
+                .and_then(|()| writer.flush());
+            if written.is_err() {
+                // The client can't be answered any more, so stop reading
+                // its commands too.
+                writer.get_ref().close();
+                return;
+            }
+        }
+    });
+    let mut session = Session {
+        client: ConnectedClient::new(&state, killer),
+        db: 0,
+        protocol: 2,
+        killed_self: false,
+        queued: None,
+        aborted: false,
+        in_exec: false,
+        watched: Vec::new(),
+        sender,
+        replies: Vec::new(),
+        channels: HashSet::new(),
+        patterns: HashSet::new(),
+        master: false,
+        monitoring: false,
+        replica: false,
+    };
+    let addr = stream.addr();
+    let mut buf = [0u8; 1024];
+    // Bytes received from the client that don't form a complete command yet.
+    let mut pending = Vec::new();
+    let mut parser = RESPParser::default();
+    let mut read_timeout = None;
+    loop {
+        let (object, consumed) = match parser.parse(&pending) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => {
+                if session.flush_replies().is_err() {
+                    return;
                 }
-                Ok((None, consumed)) => {
-                    pos += consumed;
-                    if pos >= stream.len() {
-                        break;
+                // Like Redis, never time out clients that wait for others to
+                // send them something.
+                let waiting = !session.channels.is_empty()
+                    || !session.patterns.is_empty()
+                    || session.monitoring
+                    || session.replica;
+                let timeout = state.timeout().filter(|_| !waiting);
+                if timeout != read_timeout {
+                    if stream.set_read_timeout(timeout).is_err() {
+                        return;
                     }
+                    read_timeout = timeout;
                 }
-                Err(_) => {
-                    return Err(());
+                let idle_since = time::Instant::now();
+                match stream.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(read_count) => pending.extend_from_slice(&buf[..read_count]),
+                    // The timeout may have been raised or turned off since
+                    // the read started.
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) && state
+                            .timeout()
+                            .is_none_or(|timeout| idle_since.elapsed() < timeout) => {}
+                    // A client that has been idle too long gets no reply, the
+                    // connection is just closed.
+                    Err(_) => return,
                 }
+                continue;
             }
+            Err(e) => {
+                session
+                    .replies
+                    .extend(serialize_to_error(e.to_string().as_bytes()));
+                let _ = session.flush_replies();
+                return;
+            }
+        };
+        let raw = pending.drain(..consumed).collect::<Vec<_>>();
+        // Like Redis, a blank inline command or an empty array gets no reply.
+        if session.monitoring || matches!(&object, RedisObject::Array(args) if args.is_empty()) {
+            continue;
+        }
+        // Format the command before parsing it consumes it, but only feed
+        // it to monitors once it's known to be valid.
+        let line = (state.monitor_count.load(Ordering::Relaxed) > 0)
+            .then(|| monitor_line(&object, session.db, &addr));
+        let out = match Command::from_object(object) {
+            Ok(command) => {
+                if let Some(line) = line {
+                    feed_monitors(&state, &line);
+                }
+                // Time spent waiting in a blocking command isn't slow.
+                let blocking = matches!(command, Command::BLPop(..) | Command::Wait(..));
+                let started = time::Instant::now();
+                let out = run_command(command, &raw, &state, &mut session);
+                if !blocking {
+                    log_if_slow(&state, &session, &addr, &raw, started.elapsed());
+                }
+                out
+            }
+            Err(e) => {
+                // A command that can't even be queued dooms the transaction.
+                if session.queued.is_some() {
+                    session.aborted = true;
+                }
+                serialize_to_error(e.to_string().as_bytes())
+            }
+        };
+        session.replies.extend(out);
+        if session.killed_self {
+            let _ = session.flush_replies();
+            return;
         }
-        // println!("{objects:?}");
-        Ok((Some(RedisObject::Array(objects)), pos))
     }
 }
 
-fn split_by_line(stream: &[u8]) -> Vec<Vec<u8>> {
-    let line_positions = stream
-        .windows(2)
-        .enumerate()
-        .filter(|(_, w)| w == b"\r\n")
-        .map(|(i, _)| i)
-        .collect::<Vec<_>>();
-    let mut lines = vec![stream[..line_positions[0]].to_vec()];
-    lines.extend(
-        line_positions
-            .windows(2)
-            .map(|i| stream[i[0] + 2..i[1]].to_vec())
-            .collect::<Vec<_>>(),
-    );
-    lines.push(stream[*line_positions.last().unwrap() + 2..].to_vec());
-    lines
-        .into_iter()
-        .filter(|l| !l.is_empty())
-        .collect::<Vec<_>>()
-}
+/// Runs a command, or queues it if a transaction has been started with
+/// `MULTI`, and returns its reply. `raw` is the command as the client sent
+/// it, for passing on to replicas.
+fn run_command(command: Command, raw: &[u8], state: &Arc<State>, session: &mut Session) -> Vec<u8> {
+    if session.is_subscribed_resp2()
+        && !matches!(
+            command,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::Ping
+        )
+    {
+        return serialize_to_error(CommandError::SubscribedContext.to_string().as_bytes());
+    }
+    if (!written_keys(&command, session.db).is_empty() || matches!(command, Command::BLPop(..)))
+        && state.is_replica.load(Ordering::Relaxed)
+    {
+        // Like any command that can't be queued, this dooms the transaction.
+        if session.queued.is_some() {
+            session.aborted = true;
+        }
+        return serialize_to_error(CommandError::ReadOnly.to_string().as_bytes());
+    }
+    // The master's writes are applied whatever memory they take, so the
+    // replica stays a copy of it.
+    if uses_memory(&command) && !session.master && !free_memory(state) {
+        if session.queued.is_some() {
+            session.aborted = true;
+        }
+        return serialize_to_error(CommandError::OutOfMemory.to_string().as_bytes());
+    }
+    match (command, &mut session.queued) {
+        (Command::Multi, Some(_)) => {
+            serialize_to_error(CommandError::NestedMulti.to_string().as_bytes())
+        }
+        (Command::Multi, None) => {
+            session.queued = Some(Vec::new());
+            serialize_to_simple_string(b"OK")
+        }
+        (Command::Exec, None) => {
+            serialize_to_error(CommandError::WithoutMulti("EXEC").to_string().as_bytes())
+        }
+        (Command::Discard, None) => {
+            serialize_to_error(CommandError::WithoutMulti("DISCARD").to_string().as_bytes())
+        }
+        (Command::Discard, queued @ Some(_)) => {
+            *queued = None;
+            session.aborted = false;
+            session.unwatch();
+            serialize_to_simple_string(b"OK")
+        }
+        (Command::Exec, queued @ Some(_)) => {
+            let queued = queued.take().unwrap();
+            if std::mem::take(&mut session.aborted) {
+                session.unwatch();
+                return serialize_to_error(CommandError::ExecAbort.to_string().as_bytes());
+            }
+            let _exclusive = state.exec_lock.write().unwrap();
+            let changed = session
+                .watched
+                .iter()
+                .any(|(db, key, version)| state.storage[*db].version(key) != Some(*version));
+            session.unwatch();
+            if changed {
+                return serialize_null_array(session.protocol);
+            }
+            session.in_exec = true;
+            let replies = queued
+                .into_iter()
+                .map(|(command, raw)| execute_tracked(command, &raw, state, session))
+                .collect::<Vec<_>>();
+            session.in_exec = false;
+            serialize_to_raw_array(&replies)
+        }
+        (Command::Watch(_), Some(_)) => {
+            serialize_to_error(CommandError::WatchInMulti.to_string().as_bytes())
+        }
+        (Command::Watch(keys), None) => {
+            for key in keys {
+                let version = state.storage[session.db].watch(&key);
+                session.watched.push((session.db, key, version));
+            }
+            serialize_to_simple_string(b"OK")
+        }
+        (Command::PSync, Some(_)) => {
+            serialize_to_error(CommandError::PSyncInMulti.to_string().as_bytes())
+        }
+        (Command::PSync, None) => {
+            // No write may run between taking the snapshot and attaching
+            // the replica, or the replica would miss it or apply it twice.
+            let _exclusive = state.exec_lock.write().unwrap();
+            let rdb = snapshot(state).to_bytes();
+            let mut replication = state.replication.lock().unwrap();
+            let mut out = serialize_to_simple_string(
+                format!("FULLRESYNC {} {}", replication.replid, replication.offset).as_bytes(),
+            );
+            // The snapshot is sent like a bulk string, but without the
+            // trailing CRLF.
+            out.extend(format!("${}\r\n", rdb.len()).as_bytes());
+            out.extend(rdb);
+            // Send it right away, after the replies to the commands before
+            // it, so it reaches the replica before any write propagated to
+            // it.
+            session.replies.extend(out);
+            let _ = session.flush_replies();
+            // The replica starts out in database 0 after loading.
+            replication.db = None;
+            let replica = Replica {
+                sender: session.sender.clone(),
+                ack_offset: replication.offset,
+            };
+            replication.replicas.insert(session.client.id, replica);
+            state
+                .replica_count
+                .store(replication.replicas.len(), Ordering::Relaxed);
+            session.replica = true;
+            Vec::new()
+        }
+        (command, Some(queued)) => {
+            queued.push((command, raw.to_vec()));
+            serialize_to_simple_string(b"QUEUED")
+        }
+        // `BLPOP` and `WAIT` take the lock themselves, so they can let go
+        // of it while they wait, and `DEBUG RELOAD` takes it exclusively.
+        (
+            command @ (Command::BLPop(..)
+            | Command::Wait(..)
+            | Command::Debug(DebugSubcommand::Reload)),
+            None,
+        ) => execute(command, state, session),
+        (command, None) => {
+            let _shared = state.exec_lock.read().unwrap();
+            execute_tracked(command, raw, state, session)
+        }
+    }
+}
+
+/// Executes a command, then bumps the version of the keys it may have
+/// written so transactions watching them abort, and passes it on to
+/// replicas.
+fn execute_tracked(
+    command: Command,
+    raw: &[u8],
+    state: &Arc<State>,
+    session: &mut Session,
+) -> Vec<u8> {
+    let written = written_keys(&command, session.db);
+    let db = session.db;
+    let out = execute(command, state, session);
+    if !written.is_empty() && !out.starts_with(b"-") {
+        if session.master {
+            // The master's stream is passed on as it is, and counted in the
+            // offset as it's received.
+            if state.replica_count.load(Ordering::Relaxed) > 0 {
+                let mut replication = state.replication.lock().unwrap();
+                send_to_replicas(&mut replication, Some(db), raw);
+                state
+                    .replica_count
+                    .store(replication.replicas.len(), Ordering::Relaxed);
+            }
+        } else {
+            propagate(state, Some(db), raw);
+        }
+    }
+    for (db, key) in written {
+        let Some(key) = key else {
+            state.storage[db].touch_all();
+            continue;
+        };
+        // Let the active expire cycle know about keys that were given an
+        // expiry.
+        let storage = state.storage[db].read(&key);
+        if let Some((Some(_), _, _)) = storage.get(&key) {
+            state.storage[db].note_expiring(&key);
+        }
+        drop(storage);
+        state.storage[db].touch(&key);
+    }
+    out
+}
+
+/// The keys a command may write, by database, with `None` standing for
+/// every key in the database.
+fn written_keys(command: &Command, db: usize) -> Vec<(usize, Option<Vec<u8>>)> {
+    let keys = match command {
+        Command::Set(key, ..)
+        | Command::SetNx(key, _)
+        | Command::SetEx(key, ..)
+        | Command::PSetEx(key, ..)
+        | Command::GetSet(key, _)
+        | Command::GetDel(key)
+        | Command::GetEx(key, _)
+        | Command::Persist(key)
+        | Command::Expire(key, _)
+        | Command::PExpire(key, _)
+        | Command::ExpireAt(key, _)
+        | Command::PExpireAt(key, _)
+        | Command::LPush(key, _)
+        | Command::RPush(key, _)
+        | Command::LPop(key, _)
+        | Command::RPop(key, _)
+        | Command::HSet(key, _)
+        | Command::SAdd(key, _)
+        | Command::SRem(key, _)
+        | Command::Incr(key)
+        | Command::Decr(key)
+        | Command::IncrBy(key, _)
+        | Command::DecrBy(key, _)
+        | Command::IncrByFloat(key, _)
+        | Command::HIncrByFloat(key, ..)
+        | Command::HSetNx(key, ..)
+        | Command::HIncrBy(key, ..)
+        | Command::HDel(key, _)
+        | Command::Append(key, _)
+        | Command::SetRange(key, ..)
+        | Command::ZAdd(key, ..)
+        | Command::ZRem(key, _) => vec![key.clone()],
+        Command::MSet(pairs) => pairs.iter().map(|(key, _)| key.clone()).collect(),
+        Command::Del(keys) | Command::Unlink(keys) => keys.clone(),
+        Command::LMove(source, destination, ..) => vec![source.clone(), destination.clone()],
+        Command::Copy(_, destination, options) => {
+            return vec![(options.db.unwrap_or(db), Some(destination.clone()))];
+        }
+        Command::FlushDb => return vec![(db, None)],
+        Command::FlushAll => return (0..DB_COUNT).map(|db| (db, None)).collect(),
+        _ => vec![],
+    };
+    keys.into_iter().map(|key| (db, Some(key))).collect()
+}
+
+/// Whether a command may store more than it frees, and so is refused when
+/// memory is over `maxmemory` and nothing can be evicted, like commands
+/// Redis flags `denyoom`.
+fn uses_memory(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Set(..)
+            | Command::SetNx(..)
+            | Command::SetEx(..)
+            | Command::PSetEx(..)
+            | Command::GetSet(..)
+            | Command::MSet(_)
+            | Command::LPush(..)
+            | Command::RPush(..)
+            | Command::LMove(..)
+            | Command::HSet(..)
+            | Command::HSetNx(..)
+            | Command::HIncrBy(..)
+            | Command::HIncrByFloat(..)
+            | Command::SAdd(..)
+            | Command::ZAdd(..)
+            | Command::Incr(_)
+            | Command::Decr(_)
+            | Command::IncrBy(..)
+            | Command::DecrBy(..)
+            | Command::IncrByFloat(..)
+            | Command::Append(..)
+            | Command::SetRange(..)
+            | Command::Copy(..)
+    )
+}
+
+/// Evicts keys as `maxmemory-policy` says until memory use is back within
+/// `maxmemory`, deleting them from replicas too. Returns whether it is,
+/// which it can't be under `noeviction` or once every key is gone.
+fn free_memory(state: &State) -> bool {
+    /// How many keys `allkeys-lru` picks to evict the least recently used
+    /// of, like Redis's default `maxmemory-samples`.
+    const SAMPLES: usize = 5;
+    let maxmemory = state.maxmemory.load(Ordering::Relaxed) as usize;
+    if maxmemory == 0 || memory::used() <= maxmemory {
+        return true;
+    }
+    let policy = state.config.lock().unwrap().maxmemory_policy();
+    let _shared = state.exec_lock.read().unwrap();
+    while memory::used() > maxmemory {
+        let victim = match policy {
+            MaxMemoryPolicy::NoEviction => None,
+            MaxMemoryPolicy::AllKeysRandom => {
+                random_entry(state, |_| ()).map(|(db, key, _)| (db, key))
+            }
+            MaxMemoryPolicy::AllKeysLru => (0..SAMPLES)
+                .filter_map(|_| random_entry(state, |(_, _, last_access)| last_access.idle()))
+                .max_by_key(|(_, _, idle)| *idle)
+                .map(|(db, key, _)| (db, key)),
+        };
+        let Some((db, key)) = victim else {
+            return false;
+        };
+        if state.storage[db].write(&key).remove(&key).is_none() {
+            continue;
+        }
+        state.storage[db].touch(&key);
+        notify_keyspace_event(state, db, 'e', "evicted", &key);
+        propagate(state, Some(db), &serialize_to_array(&[b"DEL", &key]));
+    }
+    true
+}
+
+/// A key picked at random from any database, along with what `f` makes of
+/// its entry, or `None` if every database is empty.
+fn random_entry<T>(state: &State, f: impl Fn(&Entry) -> T) -> Option<(usize, Vec<u8>, T)> {
+    let start = random_u64() as usize;
+    (0..DB_COUNT)
+        .map(|i| (start + i) % DB_COUNT)
+        .find_map(|db| {
+            state.storage[db]
+                .random_entry(|key, entry| (key.to_vec(), f(entry)))
+                .map(|(key, value)| (db, key, value))
+        })
+}
+
+/// Sends a command to every replica and adds it to the offset. Callers must
+/// hold `State::exec_lock`, so replicas get writes in the order they ran.
+fn propagate(state: &State, db: Option<usize>, command: &[u8]) {
+    // Replicas attached later start from a snapshot, so there's nothing to
+    // keep for them.
+    if state.replica_count.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut replication = state.replication.lock().unwrap();
+    replication.offset += send_to_replicas(&mut replication, db, command) as u64;
+    state
+        .replica_count
+        .store(replication.replicas.len(), Ordering::Relaxed);
+}
+
+/// Sends a command run in database `db` to every replica, preceded by a
+/// `SELECT` if the last one sent was run in another database, and returns
+/// how many bytes were sent. `db` is `None` for commands that don't use a
+/// database.
+fn send_to_replicas(replication: &mut Replication, db: Option<usize>, command: &[u8]) -> usize {
+    let mut out = Vec::new();
+    if let Some(db) = db.filter(|&db| replication.db != Some(db)) {
+        out.extend(serialize_to_array(&[b"SELECT", db.to_string().as_bytes()]));
+        replication.db = Some(db);
+    }
+    out.extend_from_slice(command);
+    replication
+        .replicas
+        .retain(|_, replica| replica.sender.send(out.clone()).is_ok());
+    out.len()
+}
+
+/// Makes this server a replica of `master`, or stops it replicating if
+/// `None`, dropping the link to the master it replicated until now.
+fn set_master(state: &Arc<State>, master: Option<(String, u16)>) {
+    let mut replication = state.replication.lock().unwrap();
+    let previous = replication.master.take();
+    if let Some(link) = previous
+        .as_ref()
+        .and_then(|previous| previous.link.as_ref())
+    {
+        let _ = link.shutdown(Shutdown::Both);
+    }
+    replication.generation += 1;
+    state.is_replica.store(master.is_some(), Ordering::Relaxed);
+    match master {
+        Some((host, port)) => {
+            replication.master = Some(Master {
+                host: host.clone(),
+                port,
+                link: None,
+                synced: false,
+            });
+            let state = state.clone();
+            let generation = replication.generation;
+            thread::spawn(move || replicate(state, host, port, generation));
+        }
+        // Writes accepted from now on make up a new history.
+        None if previous.is_some() => replication.replid = random_replid(),
+        None => {}
+    }
+}
+
+/// Replicates the master at `host`:`port` until `REPLICAOF` replaces it,
+/// reconnecting whenever the link is lost.
+fn replicate(state: Arc<State>, host: String, port: u16, generation: u64) {
+    loop {
+        let result = sync_with_master(&state, &host, port, generation);
+        let mut replication = state.replication.lock().unwrap();
+        if replication.generation != generation {
+            return;
+        }
+        if let Err(e) = result {
+            println!("error: lost link to master {}:{}: {}", host, port, e);
+        }
+        if let Some(master) = replication.master.as_mut() {
+            master.link = None;
+            master.synced = false;
+        }
+        drop(replication);
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+/// Connects to the master, loads its snapshot in place of every database,
+/// then applies the writes it streams until the link is lost.
+fn sync_with_master(
+    state: &Arc<State>,
+    host: &str,
+    port: u16,
+    generation: u64,
+) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect((host, port))?;
+    {
+        let mut replication = state.replication.lock().unwrap();
+        if replication.generation != generation {
+            return Ok(());
+        }
+        if let Some(master) = replication.master.as_mut() {
+            master.link = Some(stream.try_clone()?);
+        }
+    }
+
+    let listening_port = state.config.lock().unwrap().port().to_string();
+    let handshake: [(&[&[u8]], &str); 3] = [
+        (&[b"PING"], "+PONG"),
+        (
+            &[b"REPLCONF", b"listening-port", listening_port.as_bytes()],
+            "+OK",
+        ),
+        (&[b"REPLCONF", b"capa", b"psync2"], "+OK"),
+    ];
+    for (command, expected) in handshake {
+        stream.write_all(&serialize_to_array(command))?;
+        let reply = read_line(&mut stream)?;
+        if reply != expected {
+            anyhow::bail!("unexpected reply to handshake: {}", reply);
+        }
+    }
+    stream.write_all(&serialize_to_array(&[b"PSYNC", b"?", b"-1"]))?;
+    let reply = read_line(&mut stream)?;
+    let Some((replid, offset)) = reply
+        .strip_prefix("+FULLRESYNC ")
+        .and_then(|sync| sync.split_once(' '))
+    else {
+        anyhow::bail!("unexpected reply to PSYNC: {}", reply);
+    };
+    let offset = offset.parse::<u64>()?;
+    // The master sends empty lines to keep the link alive while it saves
+    // the snapshot, which is then sent like a bulk string, but without the
+    // trailing CRLF.
+    let header = loop {
+        match read_line(&mut stream)? {
+            line if line.is_empty() => continue,
+            line => break line,
+        }
+    };
+    let Some(len) = header.strip_prefix('$').and_then(|len| len.parse().ok()) else {
+        anyhow::bail!("unexpected snapshot header: {}", header);
+    };
+    let mut rdb = vec![0; len];
+    stream.read_exact(&mut rdb)?;
+    let Ok(mut rdb) = RDBObject::from_bytes(&rdb) else {
+        anyhow::bail!("could not load the master's snapshot");
+    };
+
+    {
+        let _exclusive = state.exec_lock.write().unwrap();
+        // `REPLICAOF` can't run while the lock is held, so the master can't
+        // change until the snapshot is loaded.
+        if state.replication.lock().unwrap().generation != generation {
+            return Ok(());
+        }
+        for (index, storage) in state.storage.iter().enumerate() {
+            let mut stripes = storage.write_all();
+            stripes.clear();
+            stripes.extend(rdb.databases.remove(&index).unwrap_or_default());
+            drop(stripes);
+            storage.touch_all();
+        }
+        let mut replication = state.replication.lock().unwrap();
+        replication.replid = replid.to_string();
+        replication.offset = offset;
+        if let Some(master) = replication.master.as_mut() {
+            master.synced = true;
+        }
+        // This server's own replicas hold data that's no longer ours, so
+        // make them resync.
+        let clients = state.clients.lock().unwrap();
+        for (id, _) in replication.replicas.drain() {
+            if let Some(client) = clients.get(&id) {
+                client.stream.close();
+            }
+        }
+    }
+
+    let mut session = Session {
+        client: ConnectedClient::new(state, Box::new(stream.try_clone()?)),
+        db: 0,
+        protocol: 2,
+        killed_self: false,
+        queued: None,
+        aborted: false,
+        in_exec: false,
+        watched: Vec::new(),
+        // Replies aren't sent to the master, except for acknowledgements,
+        // which are written directly.
+        sender: mpsc::channel().0,
+        replies: Vec::new(),
+        channels: HashSet::new(),
+        patterns: HashSet::new(),
+        master: true,
+        monitoring: false,
+        replica: false,
+    };
+    let addr = stream.addr();
+    let mut buf = [0u8; 1024];
+    let mut pending = Vec::new();
+    let mut parser = RESPParser::default();
+    loop {
+        let (object, consumed) = match parser.parse(&pending) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => {
+                let read_count = stream.read(&mut buf)?;
+                if read_count == 0 {
+                    anyhow::bail!("connection closed");
+                }
+                pending.extend_from_slice(&buf[..read_count]);
+                continue;
+            }
+            Err(_) => anyhow::bail!("invalid command from master"),
+        };
+        let raw = pending.drain(..consumed).collect::<Vec<_>>();
+        let line = (state.monitor_count.load(Ordering::Relaxed) > 0)
+            .then(|| monitor_line(&object, session.db, &addr));
+        // Commands this server doesn't know are skipped, but still count
+        // towards the offset.
+        if let Ok(command) = Command::from_object(object) {
+            if let Some(line) = line {
+                feed_monitors(state, &line);
+            }
+            let getack = matches!(&command, Command::ReplConf(options)
+                if options.first().is_some_and(|(name, _)| name == "getack"));
+            let out = {
+                let _shared = state.exec_lock.read().unwrap();
+                execute_tracked(command, &raw, state, &mut session)
+            };
+            if getack {
+                stream.write_all(&out)?;
+            }
+        }
+        state.replication.lock().unwrap().offset += raw.len() as u64;
+    }
+}
+
+/// Reads a line of the handshake with the master, without its line ending.
+/// Reads a byte at a time, so nothing sent after the line is consumed.
+fn read_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while byte[0] != b'\n' {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Executes a command and returns its reply. Callers must hold
+/// `State::exec_lock`, except for `BLPOP`.
+fn execute(command: Command, state: &Arc<State>, session: &mut Session) -> Vec<u8> {
+    match command {
+        Command::Ping if session.is_subscribed_resp2() => serialize_to_array(&[b"pong", b""]),
+        Command::Ping => b"+PONG\r\n".to_vec(),
+        Command::Echo(s) => serialize_to_bulk_string(&s),
+        Command::Set(key, value, options) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let result = set_value(&mut storage, key.clone(), value, &options);
+            if let Ok((true, _)) = result {
+                notify_keyspace_event(state, session.db, '$', "set", &key);
+                if options.expiry.is_some() {
+                    notify_keyspace_event(state, session.db, 'g', "expire", &key);
+                }
+            }
+            match result {
+                Ok((_, Some(v))) if options.get => serialize_to_bulk_string(&v),
+                Ok((_, None)) if options.get => serialize_null_bulk(session.protocol),
+                Ok((true, _)) => serialize_to_simple_string("OK".as_bytes()),
+                Ok((false, _)) => serialize_null_bulk(session.protocol),
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::SetEx(key, millis, value) | Command::PSetEx(key, millis, value) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let options = SetOptions {
+                expiry: Some(millis),
+                ..Default::default()
+            };
+            match set_value(&mut storage, key.clone(), value, &options) {
+                Ok(_) => {
+                    notify_keyspace_event(state, session.db, '$', "set", &key);
+                    notify_keyspace_event(state, session.db, 'g', "expire", &key);
+                    serialize_to_simple_string(b"OK")
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::SetNx(key, value) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let options = SetOptions {
+                condition: Some(SetCondition::Nx),
+                ..Default::default()
+            };
+            match set_value(&mut storage, key.clone(), value, &options) {
+                Ok((written, _)) => {
+                    if written {
+                        notify_keyspace_event(state, session.db, '$', "set", &key);
+                    }
+                    serialize_to_integer(written as i64)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::GetSet(key, value) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let options = SetOptions {
+                get: true,
+                ..Default::default()
+            };
+            let result = set_value(&mut storage, key.clone(), value, &options);
+            if result.is_ok() {
+                notify_keyspace_event(state, session.db, '$', "set", &key);
+            }
+            match result {
+                Ok((_, Some(v))) => serialize_to_bulk_string(&v),
+                Ok((_, None)) => serialize_null_bulk(session.protocol),
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::GetDel(key) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_live(&mut storage, &key) {
+                Some((_, RedisValue::String(v), _)) => {
+                    let value = std::mem::take(v);
+                    storage.remove(&key);
+                    notify_keyspace_event(state, session.db, 'g', "del", &key);
+                    serialize_to_bulk_string(&value)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::GetEx(key, expiry) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_ex(&mut storage, &key, expiry.as_ref()) {
+                Ok(Some((value, changed))) => {
+                    if changed {
+                        match expiry {
+                            Some(GetExExpiry::Persist) => {
+                                notify_keyspace_event(state, session.db, 'g', "persist", &key)
+                            }
+                            _ => notify_expire(state, session.db, &storage, &key),
+                        }
+                    }
+                    serialize_to_bulk_string(&value)
+                }
+                Ok(None) => serialize_null_bulk(session.protocol),
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::Get(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::String(v), _)) => serialize_to_bulk_string(v),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::Del(keys) => serialize_to_integer(delete(state, session.db, &keys, false)),
+        Command::Unlink(keys) => serialize_to_integer(delete(state, session.db, &keys, true)),
+        Command::Exists(keys) => {
+            let storage = state.storage[session.db].read_keys(&keys);
+            let count = keys
+                .iter()
+                .filter(|key| peek_untouched(storage.stripe(key), key).is_some())
+                .count();
+            serialize_to_integer(count as i64)
+        }
+        // Looking the keys up is all `TOUCH` needs to update their access
+        // times.
+        Command::Touch(keys) => {
+            let storage = state.storage[session.db].read_keys(&keys);
+            let count = keys
+                .iter()
+                .filter(|key| peek_live(storage.stripe(key), key).is_some())
+                .count();
+            serialize_to_integer(count as i64)
+        }
+        Command::Incr(key) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match increment(&mut storage, key.clone(), 1) {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, '$', "incrby", &key);
+                    serialize_to_integer(n)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::Decr(key) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match increment(&mut storage, key.clone(), -1) {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, '$', "incrby", &key);
+                    serialize_to_integer(n)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::IncrBy(key, amount) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match increment(&mut storage, key.clone(), amount) {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, '$', "incrby", &key);
+                    serialize_to_integer(n)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::IncrByFloat(key, delta) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match increment_float(&mut storage, key.clone(), delta) {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, '$', "incrbyfloat", &key);
+                    serialize_to_bulk_string(n.as_bytes())
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::DecrBy(key, amount) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let result = amount
+                .checked_neg()
+                .ok_or(CommandError::NotInteger)
+                .and_then(|delta| increment(&mut storage, key.clone(), delta));
+            match result {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, '$', "decrby", &key);
+                    serialize_to_integer(n)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::Append(key, value) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_or_insert_with(&mut storage, key.clone(), || RedisValue::String(vec![])) {
+                RedisValue::String(v) => {
+                    v.extend_from_slice(&value);
+                    let out = serialize_to_integer(v.len() as i64);
+                    notify_keyspace_event(state, session.db, '$', "append", &key);
+                    out
+                }
+                _ => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+            }
+        }
+        Command::GetRange(key, start, end) | Command::Substr(key, start, end) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::String(v), _)) => match normalize_range(start, end, v.len()) {
+                    Some((start, end)) => serialize_to_bulk_string(&v[start..=end]),
+                    None => serialize_to_bulk_string(b""),
+                },
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_bulk_string(b""),
+            }
+        }
+        // Writing nothing leaves the value as it is, and doesn't create the
+        // key if it doesn't exist.
+        Command::SetRange(key, _, value) if value.is_empty() => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::String(v), _)) => serialize_to_integer(v.len() as i64),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::SetRange(_, offset, value) if offset + value.len() > STRING_MAX_LEN => {
+            serialize_to_error(CommandError::StringTooLong.to_string().as_bytes())
+        }
+        Command::SetRange(key, offset, value) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_or_insert_with(&mut storage, key.clone(), || RedisValue::String(vec![])) {
+                RedisValue::String(v) => {
+                    let end = offset + value.len();
+                    if v.len() < end {
+                        v.resize(end, 0);
+                    }
+                    v[offset..end].copy_from_slice(&value);
+                    let out = serialize_to_integer(v.len() as i64);
+                    notify_keyspace_event(state, session.db, '$', "setrange", &key);
+                    out
+                }
+                _ => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+            }
+        }
+        Command::StrLen(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::String(v), _)) => serialize_to_integer(v.len() as i64),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::MSet(pairs) => {
+            let mut storage =
+                state.storage[session.db].write_keys(pairs.iter().map(|(key, _)| key));
+            for (key, value) in pairs {
+                notify_keyspace_event(state, session.db, '$', "set", &key);
+                storage.insert(
+                    key,
+                    (None, RedisValue::String(value), LastAccess::default()),
+                );
+            }
+            serialize_to_simple_string("OK".as_bytes())
+        }
+        Command::MGet(keys) => {
+            let storage = state.storage[session.db].read_keys(&keys);
+            let values = keys
+                .iter()
+                .map(|key| match peek_live(storage.stripe(key), key) {
+                    Some((_, RedisValue::String(v), _)) => serialize_to_bulk_string(v),
+                    _ => serialize_null_bulk(session.protocol),
+                })
+                .collect::<Vec<_>>();
+            serialize_to_raw_array(&values)
+        }
+        Command::Type(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_untouched(&storage, &key) {
+                Some((_, v, _)) => serialize_to_simple_string(v.type_name().as_bytes()),
+                None => serialize_to_simple_string(b"none"),
+            }
+        }
+        Command::Ttl(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match ttl_millis(&storage, &key) {
+                millis if millis < 0 => serialize_to_integer(millis),
+                millis => serialize_to_integer((millis + 500) / 1000),
+            }
+        }
+        Command::PTtl(key) => {
+            let storage = state.storage[session.db].read(&key);
+            serialize_to_integer(ttl_millis(&storage, &key))
+        }
+        Command::Persist(key) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match storage.get_mut(&key) {
+                Some((expiry, _, _)) if is_expired(expiry) => {
+                    storage.remove(&key);
+                    serialize_to_integer(0)
+                }
+                Some((expiry, _, _)) => {
+                    let persisted = expiry.take().is_some();
+                    if persisted {
+                        notify_keyspace_event(state, session.db, 'g', "persist", &key);
+                    }
+                    serialize_to_integer(persisted as i64)
+                }
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::Expire(key, seconds) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let result = seconds
+                .checked_mul(1000)
+                .ok_or(CommandError::InvalidExpireTime("expire"))
+                .and_then(|millis| expire_in(&mut storage, &key, millis, "expire"));
+            match result {
+                Ok(applied) => {
+                    if applied {
+                        notify_expire(state, session.db, &storage, &key);
+                    }
+                    serialize_to_integer(applied as i64)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::PExpire(key, millis) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match expire_in(&mut storage, &key, millis, "pexpire") {
+                Ok(applied) => {
+                    if applied {
+                        notify_expire(state, session.db, &storage, &key);
+                    }
+                    serialize_to_integer(applied as i64)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::ExpireAt(key, timestamp) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let result = timestamp
+                .checked_mul(1000)
+                .ok_or(CommandError::InvalidExpireTime("expireat"))
+                .and_then(|millis| {
+                    let millis = millis.saturating_sub(unix_millis_now());
+                    expire_in(&mut storage, &key, millis, "expireat")
+                });
+            match result {
+                Ok(applied) => {
+                    if applied {
+                        notify_expire(state, session.db, &storage, &key);
+                    }
+                    serialize_to_integer(applied as i64)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::PExpireAt(key, timestamp) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let millis = timestamp.saturating_sub(unix_millis_now());
+            match expire_in(&mut storage, &key, millis, "pexpireat") {
+                Ok(applied) => {
+                    if applied {
+                        notify_expire(state, session.db, &storage, &key);
+                    }
+                    serialize_to_integer(applied as i64)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::Keys(pattern) => {
+            let storage = state.storage[session.db].read_all();
+            let keys = storage
+                .iter()
+                .filter(|(key, (expiry, _, _))| !is_expired(expiry) && glob_match(&pattern, key))
+                .map(|(key, _)| key.as_slice())
+                .collect::<Vec<_>>();
+            serialize_to_array(&keys)
+        }
+        Command::LPush(key, elements) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let pushed = elements.len();
+            let list = get_or_insert_with(&mut storage, key.clone(), || {
+                RedisValue::List(VecDeque::new())
+            });
+            match list {
+                RedisValue::List(list) => {
+                    for element in elements {
+                        list.push_front(element);
+                    }
+                    let out = serialize_to_integer(list.len() as i64);
+                    notify_keyspace_event(state, session.db, 'l', "lpush", &key);
+                    wake_blocked(&mut state.blocked[session.db].lock().unwrap(), &key, pushed);
+                    out
+                }
+                _ => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+            }
+        }
+        Command::RPush(key, elements) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let pushed = elements.len();
+            let list = get_or_insert_with(&mut storage, key.clone(), || {
+                RedisValue::List(VecDeque::new())
+            });
+            match list {
+                RedisValue::List(list) => {
+                    list.extend(elements);
+                    let out = serialize_to_integer(list.len() as i64);
+                    notify_keyspace_event(state, session.db, 'l', "rpush", &key);
+                    wake_blocked(&mut state.blocked[session.db].lock().unwrap(), &key, pushed);
+                    out
+                }
+                _ => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+            }
+        }
+        Command::BLPop(keys, timeout) => {
+            let deadline = time::Duration::try_from_secs_f64(timeout)
+                .ok()
+                .filter(|timeout| !timeout.is_zero())
+                .and_then(|timeout| time::Instant::now().checked_add(timeout));
+            // Blocking inside a transaction would hold up every client,
+            // so act as if the timeout had already passed.
+            let deadline = if session.in_exec {
+                Some(time::Instant::now())
+            } else {
+                deadline
+            };
+            let waker = Arc::new(Waker::default());
+            // Hold `exec_lock` while using storage but not while waiting,
+            // so transactions can run in the meantime. `EXEC` already
+            // holds it.
+            let mut shared = (!session.in_exec).then(|| state.exec_lock.read().unwrap());
+            let mut storage = state.storage[session.db].write_keys(&keys);
+            let popped = loop {
+                let mut popped = None;
+                for key in keys.iter() {
+                    match list_pop(storage.stripe_mut(key), key, 1, true) {
+                        Ok(Some(mut v)) => popped = Some(Ok((key, v.remove(0)))),
+                        Ok(None) => continue,
+                        Err(e) => popped = Some(Err(e)),
+                    }
+                    break;
+                }
+                if popped.is_some() {
+                    break popped;
+                }
+                let now = time::Instant::now();
+                if deadline.is_some_and(|deadline| now >= deadline) {
+                    break None;
+                }
+                let mut blocked = state.blocked[session.db].lock().unwrap();
+                for key in keys.iter() {
+                    let waiters = blocked.entry(key.clone()).or_default();
+                    if !waiters.iter().any(|w| Arc::ptr_eq(w, &waker)) {
+                        waiters.push_back(waker.clone());
+                    }
+                }
+                drop(blocked);
+                drop(storage);
+                drop(shared.take());
+                waker.wait(deadline.map(|deadline| deadline - now));
+                shared.replace(state.exec_lock.read().unwrap());
+                storage = state.storage[session.db].write_keys(&keys);
+            };
+            // Stop waiting on every key, and hand any wakeup we may have
+            // swallowed on to the next client blocked on that key.
+            let mut blocked = state.blocked[session.db].lock().unwrap();
+            for key in keys.iter() {
+                if let Some(waiters) = blocked.get_mut(key) {
+                    waiters.retain(|w| !Arc::ptr_eq(w, &waker));
+                    if waiters.is_empty() {
+                        blocked.remove(key);
+                    }
+                }
+                if let Some((_, RedisValue::List(list), _)) =
+                    peek_untouched(storage.stripe(key), key)
+                {
+                    wake_blocked(&mut blocked, key, list.len());
+                }
+            }
+            if let Some(Ok((key, _))) = &popped {
+                state.storage[session.db].touch(key);
+                notify_list_pop(state, session.db, storage.stripe(key), key, "lpop");
+                // Replicas mustn't block, so pass on the pop itself.
+                propagate(
+                    state,
+                    Some(session.db),
+                    &serialize_to_array(&[b"LPOP", key]),
+                );
+            }
+            match popped {
+                Some(Ok((key, value))) => serialize_to_array(&[key, &value]),
+                Some(Err(e)) => serialize_to_error(e.to_string().as_bytes()),
+                None => serialize_null_array(session.protocol),
+            }
+        }
+        Command::LPop(key, count) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let popped = list_pop(&mut storage, &key, count.unwrap_or(1), true);
+            if let Ok(Some(_)) = popped {
+                notify_list_pop(state, session.db, &storage, &key, "lpop");
+            }
+            match (popped, count) {
+                (Ok(Some(popped)), Some(_)) => {
+                    serialize_to_array(&popped.iter().map(|v| v.as_slice()).collect::<Vec<_>>())
+                }
+                (Ok(Some(popped)), None) => serialize_to_bulk_string(&popped[0]),
+                (Ok(None), Some(_)) => serialize_null_array(session.protocol),
+                (Ok(None), None) => serialize_null_bulk(session.protocol),
+                (Err(e), _) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::RPop(key, count) => {
+            let mut storage = state.storage[session.db].write(&key);
+            let popped = list_pop(&mut storage, &key, count.unwrap_or(1), false);
+            if let Ok(Some(_)) = popped {
+                notify_list_pop(state, session.db, &storage, &key, "rpop");
+            }
+            match (popped, count) {
+                (Ok(Some(popped)), Some(_)) => {
+                    serialize_to_array(&popped.iter().map(|v| v.as_slice()).collect::<Vec<_>>())
+                }
+                (Ok(Some(popped)), None) => serialize_to_bulk_string(&popped[0]),
+                (Ok(None), Some(_)) => serialize_null_array(session.protocol),
+                (Ok(None), None) => serialize_null_bulk(session.protocol),
+                (Err(e), _) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::LRange(key, start, stop) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::List(list), _)) => {
+                    match normalize_range(start, stop, list.len()) {
+                        Some((start, stop)) => serialize_to_array(
+                            &list
+                                .range(start..=stop)
+                                .map(|v| v.as_slice())
+                                .collect::<Vec<_>>(),
+                        ),
+                        None => serialize_to_array(&[]),
+                    }
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_array(&[]),
+            }
+        }
+        Command::LLen(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::List(list), _)) => serialize_to_integer(list.len() as i64),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::LIndex(key, index) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::List(list), _)) => {
+                    let index = normalize_index(index, list.len());
+                    match usize::try_from(index).ok().and_then(|i| list.get(i)) {
+                        Some(v) => serialize_to_bulk_string(v),
+                        None => serialize_null_bulk(session.protocol),
+                    }
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::LMove(source, destination, from_front, to_front) => {
+            let mut storage = state.storage[session.db].write_keys([&source, &destination]);
+            match list_move(&mut storage, &source, &destination, from_front, to_front) {
+                Ok(Some(element)) => {
+                    let pop = if from_front { "lpop" } else { "rpop" };
+                    notify_list_pop(state, session.db, storage.stripe(&source), &source, pop);
+                    let push = if to_front { "lpush" } else { "rpush" };
+                    notify_keyspace_event(state, session.db, 'l', push, &destination);
+                    wake_blocked(
+                        &mut state.blocked[session.db].lock().unwrap(),
+                        &destination,
+                        1,
+                    );
+                    serialize_to_bulk_string(&element)
+                }
+                Ok(None) => serialize_null_bulk(session.protocol),
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::LPos(key, element, options) => {
+            let storage = state.storage[session.db].read(&key);
+            let positions = match peek_live(&storage, &key) {
+                Some((_, RedisValue::List(list), _)) => {
+                    Ok(list_positions(list, &element, &options))
+                }
+                Some(_) => Err(CommandError::WrongType),
+                None => Ok(vec![]),
+            };
+            match positions {
+                Ok(positions) if options.count.is_some() => serialize_to_raw_array(
+                    &positions
+                        .into_iter()
+                        .map(|i| serialize_to_integer(i as i64))
+                        .collect::<Vec<_>>(),
+                ),
+                Ok(positions) => match positions.first() {
+                    Some(&i) => serialize_to_integer(i as i64),
+                    None => serialize_null_bulk(session.protocol),
+                },
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::HSet(key, pairs) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_or_insert_with(&mut storage, key.clone(), || {
+                RedisValue::Hash(HashMap::new())
+            }) {
+                RedisValue::Hash(hash) => {
+                    let added = pairs
+                        .into_iter()
+                        .map(|(field, value)| hash.insert(field, value))
+                        .filter(Option::is_none)
+                        .count();
+                    notify_keyspace_event(state, session.db, 'h', "hset", &key);
+                    serialize_to_integer(added as i64)
+                }
+                _ => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+            }
+        }
+        Command::HSetNx(key, field, value) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_live(&mut storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) if hash.contains_key(&field) => {
+                    serialize_to_integer(0)
+                }
+                Some((_, RedisValue::Hash(hash), _)) => {
+                    hash.insert(field, value);
+                    notify_keyspace_event(state, session.db, 'h', "hset", &key);
+                    serialize_to_integer(1)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => {
+                    storage.insert(
+                        key.clone(),
+                        (
+                            None,
+                            RedisValue::Hash(HashMap::from([(field, value)])),
+                            LastAccess::default(),
+                        ),
+                    );
+                    notify_keyspace_event(state, session.db, 'h', "hset", &key);
+                    serialize_to_integer(1)
+                }
+            }
+        }
+        Command::HIncrBy(key, field, delta) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match hash_increment(&mut storage, key.clone(), field, delta) {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, 'h', "hincrby", &key);
+                    serialize_to_integer(n)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::HIncrByFloat(key, field, delta) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match hash_increment_float(&mut storage, key.clone(), field, delta) {
+                Ok(n) => {
+                    notify_keyspace_event(state, session.db, 'h', "hincrbyfloat", &key);
+                    serialize_to_bulk_string(n.as_bytes())
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::HGet(key, field) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => match hash.get(&field) {
+                    Some(v) => serialize_to_bulk_string(v),
+                    None => serialize_null_bulk(session.protocol),
+                },
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::HMGet(key, fields) => {
+            let storage = state.storage[session.db].read(&key);
+            let hash = match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => Ok(Some(hash)),
+                Some(_) => Err(CommandError::WrongType),
+                None => Ok(None),
+            };
+            match hash {
+                Ok(hash) => serialize_to_raw_array(
+                    &fields
+                        .iter()
+                        .map(
+                            |field| match hash.as_ref().and_then(|hash| hash.get(field)) {
+                                Some(value) => serialize_to_bulk_string(value),
+                                None => serialize_null_bulk(session.protocol),
+                            },
+                        )
+                        .collect::<Vec<_>>(),
+                ),
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::HExists(key, field) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => {
+                    serialize_to_integer(hash.contains_key(&field) as i64)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::HDel(key, fields) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_live(&mut storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => {
+                    let removed = fields
+                        .iter()
+                        .filter(|field| hash.remove(*field).is_some())
+                        .count();
+                    if removed > 0 {
+                        notify_keyspace_event(state, session.db, 'h', "hdel", &key);
+                    }
+                    if hash.is_empty() {
+                        storage.remove(&key);
+                        notify_keyspace_event(state, session.db, 'g', "del", &key);
+                    }
+                    serialize_to_integer(removed as i64)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::HGetAll(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => serialize_to_raw_map(
+                    &hash
+                        .iter()
+                        .flat_map(|(field, value)| [field, value])
+                        .map(|s| serialize_to_bulk_string(s))
+                        .collect::<Vec<_>>(),
+                    session.protocol,
+                ),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_raw_map(&[], session.protocol),
+            }
+        }
+        Command::HKeys(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => serialize_to_array(
+                    &hash
+                        .keys()
+                        .map(|field| field.as_slice())
+                        .collect::<Vec<_>>(),
+                ),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_array(&[]),
+            }
+        }
+        Command::HVals(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => serialize_to_array(
+                    &hash
+                        .values()
+                        .map(|value| value.as_slice())
+                        .collect::<Vec<_>>(),
+                ),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_array(&[]),
+            }
+        }
+        Command::HLen(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Hash(hash), _)) => serialize_to_integer(hash.len() as i64),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::SAdd(key, members) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_or_insert_with(
+                &mut storage,
+                key.clone(),
+                || RedisValue::Set(HashSet::new()),
+            ) {
+                RedisValue::Set(set) => {
+                    let added = members
+                        .into_iter()
+                        .map(|member| set.insert(member))
+                        .filter(|&added| added)
+                        .count();
+                    if added > 0 {
+                        notify_keyspace_event(state, session.db, 's', "sadd", &key);
+                    }
+                    serialize_to_integer(added as i64)
+                }
+                _ => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+            }
+        }
+        Command::SRem(key, members) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_live(&mut storage, &key) {
+                Some((_, RedisValue::Set(set), _)) => {
+                    let removed = members.iter().filter(|member| set.remove(*member)).count();
+                    if removed > 0 {
+                        notify_keyspace_event(state, session.db, 's', "srem", &key);
+                    }
+                    if set.is_empty() {
+                        storage.remove(&key);
+                        notify_keyspace_event(state, session.db, 'g', "del", &key);
+                    }
+                    serialize_to_integer(removed as i64)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::SMembers(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Set(set), _)) => serialize_to_array(
+                    &set.iter()
+                        .map(|member| member.as_slice())
+                        .collect::<Vec<_>>(),
+                ),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_array(&[]),
+            }
+        }
+        Command::SIsMember(key, member) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Set(set), _)) => {
+                    serialize_to_integer(set.contains(&member) as i64)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::SCard(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::Set(set), _)) => serialize_to_integer(set.len() as i64),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::SUnion(keys) => {
+            let storage = state.storage[session.db].read_keys(&keys);
+            match get_sets(&storage, &keys) {
+                Ok(sets) => {
+                    let union = sets.into_iter().flatten().flatten().collect::<HashSet<_>>();
+                    serialize_to_array(&union.into_iter().map(|m| m.as_slice()).collect::<Vec<_>>())
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::SInter(keys) => {
+            let storage = state.storage[session.db].read_keys(&keys);
+            match get_sets(&storage, &keys) {
+                Ok(sets) => {
+                    // A missing key is an empty set, which empties the intersection.
+                    let sets = sets
+                        .into_iter()
+                        .collect::<Option<Vec<_>>>()
+                        .unwrap_or_default();
+                    let smallest = sets.iter().min_by_key(|set| set.len());
+                    let intersection = smallest
+                        .into_iter()
+                        .flat_map(|smallest| smallest.iter())
+                        .filter(|member| sets.iter().all(|set| set.contains(*member)))
+                        .map(|member| member.as_slice())
+                        .collect::<Vec<_>>();
+                    serialize_to_array(&intersection)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::SDiff(keys) => {
+            let storage = state.storage[session.db].read_keys(&keys);
+            match get_sets(&storage, &keys) {
+                Ok(sets) => {
+                    let (first, others) = sets.split_first().unwrap();
+                    let difference = first
+                        .iter()
+                        .flat_map(|first| first.iter())
+                        .filter(|member| others.iter().flatten().all(|set| !set.contains(*member)))
+                        .map(|member| member.as_slice())
+                        .collect::<Vec<_>>();
+                    serialize_to_array(&difference)
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::ZAdd(key, options, pairs) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match zadd(&mut storage, &key, &options, pairs) {
+                Ok((added, changed, score)) => {
+                    if added + changed > 0 {
+                        let event = if options.incr { "zincr" } else { "zadd" };
+                        notify_keyspace_event(state, session.db, 'z', event, &key);
+                    }
+                    match score {
+                        _ if !options.incr => {
+                            let changed = if options.ch { changed } else { 0 };
+                            serialize_to_integer((added + changed) as i64)
+                        }
+                        Some(score) => serialize_to_double(score, session.protocol),
+                        None => serialize_null_bulk(session.protocol),
+                    }
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::ZRange(key, start, stop, options) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => {
+                    let len = zset.len();
+                    let members = match normalize_range(start, stop, len) {
+                        Some((start, stop)) if options.rev => {
+                            zset.range(len - 1 - stop..len - start).rev().collect()
+                        }
+                        Some((start, stop)) => zset.range(start..stop + 1).collect(),
+                        None => vec![],
+                    };
+                    serialize_scored_members(&members, options.with_scores, session.protocol)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_array(&[]),
+            }
+        }
+        Command::ZRangeByScore(key, min, max, options) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => {
+                    let ranks = zset.score_ranks(min, max);
+                    let members = match options.limit {
+                        Some((offset, _)) if offset < 0 => vec![],
+                        // A negative count means every member after the offset.
+                        Some((offset, count)) => {
+                            let start = ranks.start.saturating_add(offset as usize).min(ranks.end);
+                            let count = usize::try_from(count).unwrap_or(usize::MAX);
+                            let end = start.saturating_add(count).min(ranks.end);
+                            zset.range(start..end).collect()
+                        }
+                        None => zset.range(ranks).collect(),
+                    };
+                    serialize_scored_members(&members, options.with_scores, session.protocol)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_array(&[]),
+            }
+        }
+        Command::ZCount(key, min, max) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => {
+                    serialize_to_integer(zset.score_ranks(min, max).len() as i64)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::ZScore(key, member) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => match zset.score(&member) {
+                    Some(score) => serialize_to_double(score, session.protocol),
+                    None => serialize_null_bulk(session.protocol),
+                },
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::ZRank(key, member, rev) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => match zset.rank(&member) {
+                    Some(rank) if rev => serialize_to_integer((zset.len() - 1 - rank) as i64),
+                    Some(rank) => serialize_to_integer(rank as i64),
+                    None => serialize_null_bulk(session.protocol),
+                },
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::ZCard(key) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_live(&storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => serialize_to_integer(zset.len() as i64),
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::ZRem(key, members) => {
+            let mut storage = state.storage[session.db].write(&key);
+            match get_live(&mut storage, &key) {
+                Some((_, RedisValue::ZSet(zset), _)) => {
+                    let removed = members.iter().filter(|member| zset.remove(member)).count();
+                    if removed > 0 {
+                        notify_keyspace_event(state, session.db, 'z', "zrem", &key);
+                    }
+                    if zset.is_empty() {
+                        storage.remove(&key);
+                        notify_keyspace_event(state, session.db, 'g', "del", &key);
+                    }
+                    serialize_to_integer(removed as i64)
+                }
+                Some(_) => serialize_to_error(CommandError::WrongType.to_string().as_bytes()),
+                None => serialize_to_integer(0),
+            }
+        }
+        Command::BgSave => {
+            if state.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+                serialize_to_error(CommandError::BgSaveInProgress.to_string().as_bytes())
+            } else {
+                // Clone under the lock so clients aren't held up while
+                // the snapshot is written.
+                let rdb = snapshot(state);
+                let path = rdb_path(&state.config.lock().unwrap());
+                let state = state.clone();
+                thread::spawn(move || {
+                    match rdb.to_file(&path) {
+                        Ok(()) => state
+                            .rdb_last_save_time
+                            .store(unix_millis_now() / 1000, Ordering::SeqCst),
+                        Err(e) => println!("error: background save failed: {}", e),
+                    }
+                    state.bgsave_in_progress.store(false, Ordering::SeqCst);
+                });
+                serialize_to_simple_string(b"Background saving started")
+            }
+        }
+        Command::LastSave => serialize_to_integer(state.rdb_last_save_time.load(Ordering::SeqCst)),
+        Command::Wait(..) if state.is_replica.load(Ordering::Relaxed) => {
+            serialize_to_error(CommandError::WaitOnReplica.to_string().as_bytes())
+        }
+        Command::Wait(count, timeout) => {
+            let deadline = (timeout != 0)
+                .then(|| time::Instant::now().checked_add(time::Duration::from_millis(timeout)))
+                .flatten();
+            // Waiting inside a transaction would hold up every client, so
+            // act as if the timeout had already passed.
+            let deadline = if session.in_exec {
+                Some(time::Instant::now())
+            } else {
+                deadline
+            };
+            let target = state.replication.lock().unwrap().offset;
+            let mut asked = false;
+            loop {
+                let acknowledged = state
+                    .replication
+                    .lock()
+                    .unwrap()
+                    .replicas
+                    .values()
+                    .filter(|replica| replica.ack_offset >= target)
+                    .count();
+                if acknowledged >= count
+                    || deadline.is_some_and(|deadline| time::Instant::now() >= deadline)
+                {
+                    break serialize_to_integer(acknowledged as i64);
+                }
+                if !std::mem::replace(&mut asked, true) {
+                    let _shared = (!session.in_exec).then(|| state.exec_lock.read().unwrap());
+                    propagate(
+                        state,
+                        None,
+                        &serialize_to_array(&[b"REPLCONF", b"GETACK", b"*"]),
+                    );
+                }
+                thread::sleep(time::Duration::from_millis(10));
+            }
+        }
+        // A replica acknowledging the offset it has reached doesn't expect a
+        // reply.
+        Command::ReplConf(options) if options.first().is_some_and(|(name, _)| name == "ack") => {
+            let offset = options[0].1.parse().ok();
+            let mut replication = state.replication.lock().unwrap();
+            if let (Some(replica), Some(offset)) =
+                (replication.replicas.get_mut(&session.client.id), offset)
+            {
+                replica.ack_offset = offset;
+            }
+            Vec::new()
+        }
+        // The master asking how much of its stream has been applied.
+        Command::ReplConf(options) if options.first().is_some_and(|(name, _)| name == "getack") => {
+            let offset = state.replication.lock().unwrap().offset;
+            serialize_to_array(&[b"REPLCONF", b"ACK", offset.to_string().as_bytes()])
+        }
+        // The replica's port and capabilities aren't needed to serve it.
+        Command::ReplConf(_) => serialize_to_simple_string(b"OK"),
+        Command::ReplicaOf(Some((host, port)))
+            if state
+                .replication
+                .lock()
+                .unwrap()
+                .master
+                .as_ref()
+                .is_some_and(|master| master.host == host && master.port == port) =>
+        {
+            serialize_to_simple_string(b"OK Already connected to specified master")
+        }
+        Command::ReplicaOf(master) => {
+            set_master(state, master);
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Time => {
+            let now = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default();
+            serialize_to_array(&[
+                now.as_secs().to_string().as_bytes(),
+                now.subsec_micros().to_string().as_bytes(),
+            ])
+        }
+        Command::Select(index) => match usize::try_from(index) {
+            Ok(index) if index < DB_COUNT => {
+                session.db = index;
+                session.client.update(|client| client.db = index);
+                serialize_to_simple_string(b"OK")
+            }
+            _ => serialize_to_error(CommandError::InvalidDbIndex.to_string().as_bytes()),
+        },
+        Command::FlushDb => {
+            state.storage[session.db].write_all().clear();
+            serialize_to_simple_string(b"OK")
+        }
+        Command::FlushAll => {
+            for storage in state.storage.iter() {
+                storage.write_all().clear();
+            }
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Copy(source, destination, options) => {
+            let dest_db = options.db.unwrap_or(session.db);
+            if dest_db == session.db && source == destination {
+                serialize_to_error(CommandError::SameObject.to_string().as_bytes())
+            } else {
+                // Only one stripe is locked at a time, so copying can't
+                // deadlock.
+                let entry = peek_live(&state.storage[session.db].read(&source), &source).cloned();
+                let mut storage = state.storage[dest_db].write(&destination);
+                match entry {
+                    Some(_)
+                        if !options.replace && get_live(&mut storage, &destination).is_some() =>
+                    {
+                        serialize_to_integer(0)
+                    }
+                    Some(entry) => {
+                        if let (_, RedisValue::List(list), _) = &entry {
+                            let len = list.len();
+                            wake_blocked(
+                                &mut state.blocked[dest_db].lock().unwrap(),
+                                &destination,
+                                len,
+                            );
+                        }
+                        notify_keyspace_event(state, dest_db, 'g', "copy_to", &destination);
+                        storage.insert(destination, entry);
+                        serialize_to_integer(1)
+                    }
+                    None => serialize_to_integer(0),
+                }
+            }
+        }
+        Command::RandomKey => {
+            // Expired keys are skipped and another key picked, leaving
+            // their removal to the active expire cycle so only one stripe
+            // is read at a time.
+            let mut key = None;
+            for _ in 0..RANDOMKEY_MAX_TRIES {
+                let picked = state.storage[session.db].random_entry(|key, (expiry, _, _)| {
+                    (!is_expired(expiry)).then(|| key.to_vec())
+                });
+                match picked {
+                    Some(None) => continue,
+                    Some(live) => key = live,
+                    None => {}
+                }
+                break;
+            }
+            match key {
+                Some(key) => serialize_to_bulk_string(&key),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::Hello(version, name) => match version.unwrap_or(session.protocol) {
+            version @ (2 | 3) => {
+                session.protocol = version;
+                if let Some(name) = name {
+                    session.client.update(|client| client.name = name);
+                }
+                let role: &[u8] = match state.replication.lock().unwrap().master {
+                    Some(_) => b"replica",
+                    None => b"master",
+                };
+                let info = [
+                    serialize_to_bulk_string(b"server"),
+                    serialize_to_bulk_string(b"redis"),
+                    serialize_to_bulk_string(b"version"),
+                    serialize_to_bulk_string(REDIS_VERSION.as_bytes()),
+                    serialize_to_bulk_string(b"proto"),
+                    serialize_to_integer(session.protocol),
+                    serialize_to_bulk_string(b"id"),
+                    serialize_to_integer(session.client.id as i64),
+                    serialize_to_bulk_string(b"mode"),
+                    serialize_to_bulk_string(b"standalone"),
+                    serialize_to_bulk_string(b"role"),
+                    serialize_to_bulk_string(role),
+                    serialize_to_bulk_string(b"modules"),
+                    serialize_to_raw_array(&[]),
+                ];
+                serialize_to_raw_map(&info, session.protocol)
+            }
+            _ => serialize_to_error(CommandError::NoProto.to_string().as_bytes()),
+        },
+        Command::Client(ClientSubcommand::SetName(name)) => {
+            session.client.update(|client| client.name = name);
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Client(ClientSubcommand::GetName) => {
+            let clients = state.clients.lock().unwrap();
+            match clients.get(&session.client.id) {
+                Some(client) if !client.name.is_empty() => {
+                    serialize_to_bulk_string(client.name.as_bytes())
+                }
+                _ => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::SlowLog(SlowLogSubcommand::Get(count)) => {
+            let slowlog = state.slowlog.lock().unwrap();
+            let entries = slowlog
+                .entries
+                .iter()
+                .take(count.unwrap_or(usize::MAX))
+                .map(|entry| {
+                    let args = entry.args.iter().map(|arg| arg.as_slice());
+                    serialize_to_raw_array(&[
+                        serialize_to_integer(entry.id as i64),
+                        serialize_to_integer(entry.timestamp),
+                        serialize_to_integer(entry.duration.as_micros() as i64),
+                        serialize_to_array(&args.collect::<Vec<_>>()),
+                        serialize_to_bulk_string(entry.addr.as_bytes()),
+                        serialize_to_bulk_string(entry.name.as_bytes()),
+                    ])
+                })
+                .collect::<Vec<_>>();
+            serialize_to_raw_array(&entries)
+        }
+        Command::SlowLog(SlowLogSubcommand::Len) => {
+            serialize_to_integer(state.slowlog.lock().unwrap().entries.len() as i64)
+        }
+        Command::SlowLog(SlowLogSubcommand::Reset) => {
+            state.slowlog.lock().unwrap().entries.clear();
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Debug(_) if !state.config.lock().unwrap().debug_command_enabled() => {
+            serialize_to_error(CommandError::DebugDisabled.to_string().as_bytes())
+        }
+        Command::Debug(DebugSubcommand::Sleep(duration)) => {
+            thread::sleep(duration);
+            serialize_to_simple_string(b"OK")
+        }
+        // A no-op in Redis too, but some tools check that it's accepted.
+        Command::Debug(DebugSubcommand::JMap | DebugSubcommand::QuicklistPackedThreshold) => {
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Debug(DebugSubcommand::Reload) => match reload(state, session.in_exec) {
+            Ok(()) => serialize_to_simple_string(b"OK"),
+            Err(e) => serialize_to_error(e.to_string().as_bytes()),
+        },
+        Command::Debug(DebugSubcommand::SetActiveExpire(enabled)) => {
+            state.active_expire.store(enabled, Ordering::SeqCst);
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Debug(DebugSubcommand::Object(key)) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_untouched(&storage, &key) {
+                Some((_, value, last_access)) => {
+                    // Like Redis's LRU clock, the time of the last access in
+                    // seconds, wrapping at 24 bits.
+                    let idle = last_access.idle().as_secs() as i64;
+                    let lru = (unix_millis_now() / 1000 - idle).rem_euclid(1 << 24);
+                    serialize_to_bulk_string(
+                        format!(
+                            "Value at:{:p} refcount:1 encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{}",
+                            value,
+                            value.encoding(),
+                            rdb::encoded_len(value),
+                            lru,
+                            idle,
+                        )
+                        .as_bytes(),
+                    )
+                }
+                None => serialize_to_error(CommandError::NoSuchKey.to_string().as_bytes()),
+            }
+        }
+        Command::MemoryUsage(key, samples) => {
+            let storage = state.storage[session.db].read(&key);
+            match peek_untouched(&storage, &key) {
+                // Along with the value, count the key and the slot holding
+                // both in the database's hash table.
+                Some((_, value, _)) => serialize_to_integer(
+                    (key.len() + size_of::<(Vec<u8>, Entry)>() + 1 + value.memory_usage(samples))
+                        as i64,
+                ),
+                None => serialize_null_bulk(session.protocol),
+            }
+        }
+        Command::Object(subcommand) => {
+            let key = match &subcommand {
+                ObjectSubcommand::Encoding(key)
+                | ObjectSubcommand::RefCount(key)
+                | ObjectSubcommand::IdleTime(key)
+                | ObjectSubcommand::Freq(key) => key,
+            };
+            let storage = state.storage[session.db].read(key);
+            match (&subcommand, peek_untouched(&storage, key)) {
+                (_, None) => serialize_to_error(CommandError::NoSuchKey.to_string().as_bytes()),
+                (ObjectSubcommand::Encoding(_), Some((_, value, _))) => {
+                    serialize_to_bulk_string(value.encoding().as_bytes())
+                }
+                // Values are never shared between keys.
+                (ObjectSubcommand::RefCount(_), Some(_)) => serialize_to_integer(1),
+                (ObjectSubcommand::IdleTime(_), Some((_, _, last_access))) => {
+                    serialize_to_integer(last_access.idle().as_secs() as i64)
+                }
+                // Access frequency is only tracked under an LFU eviction
+                // policy, and none is supported.
+                (ObjectSubcommand::Freq(_), Some(_)) => {
+                    serialize_to_error(CommandError::LfuNotSelected.to_string().as_bytes())
+                }
+            }
+        }
+        Command::Client(ClientSubcommand::Id) => serialize_to_integer(session.client.id as i64),
+        Command::Client(ClientSubcommand::List) => {
+            let clients = state.clients.lock().unwrap();
+            let mut ids = clients.keys().collect::<Vec<_>>();
+            ids.sort();
+            let list = ids
+                .into_iter()
+                .map(|id| {
+                    let client = &clients[id];
+                    format!(
+                        "id={} addr={} name={} age={} db={}\n",
+                        id,
+                        client.addr,
+                        client.name,
+                        client.connected_at.elapsed().as_secs(),
+                        client.db
+                    )
+                })
+                .collect::<String>();
+            serialize_to_bulk_string(list.as_bytes())
+        }
+        Command::Client(ClientSubcommand::Kill(filter)) => {
+            let mut clients = state.clients.lock().unwrap();
+            let killed = clients
+                .iter()
+                .filter(|(id, target)| {
+                    filter.id.is_none_or(|filter_id| filter_id == **id)
+                        && filter.addr.as_ref().is_none_or(|addr| *addr == target.addr)
+                        && !(filter.skip_me && **id == session.client.id)
+                })
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            for id in killed.iter() {
+                if let Some(target) = clients.remove(id) {
+                    // Killing ourselves waits until the reply is sent.
+                    if *id == session.client.id {
+                        session.killed_self = true;
+                    } else {
+                        target.stream.close();
+                    }
+                }
+            }
+            match (filter.legacy, killed.len()) {
+                (true, 0) => serialize_to_error(CommandError::NoSuchClient.to_string().as_bytes()),
+                (true, _) => serialize_to_simple_string(b"OK"),
+                (false, count) => serialize_to_integer(count as i64),
+            }
+        }
+        Command::Info(section) => {
+            serialize_to_bulk_string(info(state, section.as_deref()).as_bytes())
+        }
+        // Clients only ask for command details to enhance their own
+        // behavior, so there is no need to describe each command.
+        Command::Commands(CommandSubcommand::Count) => {
+            serialize_to_integer(COMMAND_NAMES.len() as i64)
+        }
+        Command::Commands(CommandSubcommand::Docs) => serialize_to_raw_map(&[], session.protocol),
+        Command::Commands(CommandSubcommand::Info(names)) => serialize_to_raw_array(
+            &names
+                .iter()
+                .map(|_| serialize_null_array(session.protocol))
+                .collect::<Vec<_>>(),
+        ),
+        Command::ConfigSet(name, value) => {
+            let mut config = state.config.lock().unwrap();
+            match config.set(&name, value) {
+                Ok(()) => {
+                    state.apply_config(&config);
+                    serialize_to_simple_string(b"OK")
+                }
+                Err(e) => serialize_to_error(e.to_string().as_bytes()),
+            }
+        }
+        Command::ConfigGet(patterns) => {
+            let config = state.config.lock().unwrap();
+            let matches = CONFIG_PARAMETERS
+                .iter()
+                .filter(|name| {
+                    patterns.iter().any(|pattern| {
+                        glob_match(pattern.to_lowercase().as_bytes(), name.as_bytes())
+                    })
+                })
+                .filter_map(|name| Some((*name, config.get(name)?)))
+                .flat_map(|(name, value)| [name.as_bytes(), value.as_bytes()])
+                .map(serialize_to_bulk_string)
+                .collect::<Vec<_>>();
+            serialize_to_raw_map(&matches, session.protocol)
+        }
+        Command::Subscribe(channels) => subscribe(session, channels, false),
+        Command::PSubscribe(patterns) => subscribe(session, patterns, true),
+        Command::Unsubscribe(channels) => unsubscribe(session, channels, false),
+        Command::PUnsubscribe(patterns) => unsubscribe(session, patterns, true),
+        Command::Publish(channel, message) => {
+            serialize_to_integer(publish(state, &channel, &message))
+        }
+        Command::Unwatch => {
+            session.unwatch();
+            serialize_to_simple_string(b"OK")
+        }
+        Command::Multi | Command::Exec | Command::Discard | Command::Watch(_) => {
+            unreachable!("transactions are handled by run_command")
+        }
+        Command::PSync => {
+            unreachable!("replicas are attached by run_command")
+        }
+        // A transaction expects a reply to each of its commands.
+        Command::Monitor if session.in_exec => {
+            serialize_to_error(CommandError::MonitorInTransaction.to_string().as_bytes())
+        }
+        Command::Monitor => {
+            let mut monitors = state.monitors.lock().unwrap();
+            // Send the reply right away, after the replies to the commands
+            // before it, so it comes before any command fed to the client.
+            session.replies.extend(serialize_to_simple_string(b"OK"));
+            let _ = session.flush_replies();
+            monitors.insert(session.client.id, session.sender.clone());
+            state.monitor_count.store(monitors.len(), Ordering::Relaxed);
+            session.monitoring = true;
+            Vec::new()
+        }
+    }
+}
+
+/// How many keys `RANDOMKEY` picks before giving up when they've all
+/// expired, like Redis.
+const RANDOMKEY_MAX_TRIES: usize = 100;
+
+fn is_expired(expiry: &Option<time::Instant>) -> bool {
+    match expiry {
+        Some(expiry) => time::Instant::now() >= *expiry,
+        None => false,
+    }
+}
+
+/// Removes expired keys that are never accessed again, like Redis does: take
+/// a sample of keys that may have an expiry, remove the expired ones, and go
+/// again if more than a quarter of the sample had expired, until `budget`
+/// runs out. Only the stripe being sampled is locked, and only while its
+/// sample is taken, so other keys can be used in the meantime. Like
+/// eviction, each removal counts as a write, and is passed on to replicas
+/// as a `DEL`. Replicas leave it to their master.
+fn active_expire_cycle(state: &State, db: usize, budget: time::Duration) {
+    const SAMPLE_SIZE: usize = 20;
+    if state.is_replica.load(Ordering::Relaxed) {
+        return;
+    }
+    let deadline = time::Instant::now() + budget;
+    // Start at a random stripe so every one gets sampled eventually.
+    let start = random_u64() as usize % keyspace::STRIPES;
+    for index in (start..keyspace::STRIPES).chain(0..start) {
+        loop {
+            let _shared = state.exec_lock.read().unwrap();
+            let (sampled, expired) = state.storage[db].with_expiring(index, |storage, expiring| {
+                if expiring.is_empty() {
+                    return (0, Vec::new());
+                }
+                // Start at a random key, so keys that don't expire for a
+                // long time can't keep the rest from being sampled.
+                let skip = random_u64() as usize % expiring.len();
+                let sample = expiring
+                    .iter()
+                    .skip(skip)
+                    .chain(expiring.iter().take(skip))
+                    .take(SAMPLE_SIZE)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut expired = Vec::new();
+                for key in &sample {
+                    match storage.get(key) {
+                        Some((Some(expiry), _, _)) if time::Instant::now() >= *expiry => {
+                            storage.remove(key);
+                            expiring.remove(key);
+                            expired.push(key.clone());
+                        }
+                        Some((Some(_), _, _)) => {}
+                        // Deleted or persisted since it was recorded.
+                        _ => {
+                            expiring.remove(key);
+                        }
+                    }
+                }
+                (sample.len(), expired)
+            });
+            for key in &expired {
+                state.storage[db].touch(key);
+                notify_keyspace_event(state, db, 'x', "expired", key);
+                propagate(state, Some(db), &serialize_to_array(&[b"DEL", key]));
+            }
+            if time::Instant::now() >= deadline {
+                return;
+            }
+            if expired.len() * 4 <= sampled {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns a pseudo-random number from a splitmix64 generator, seeded once per
+/// process from the clock.
+fn random_u64() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = *SEED.get_or_init(|| {
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    });
+    let step = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut z = seed.wrapping_add(step.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Looks up `key`, removing it first if it has expired.
+fn get_live<'a>(storage: &'a mut Storage, key: &[u8]) -> Option<&'a mut Entry> {
+    if storage
+        .get(key)
+        .is_some_and(|(expiry, _, _)| is_expired(expiry))
+    {
+        storage.remove(key);
+    }
+    let entry = storage.get_mut(key)?;
+    entry.2.touch();
+    Some(entry)
+}
+
+/// Looks up `key` for a command that only reads, treating it as missing if
+/// it has expired. The expired entry is left for the next write or the
+/// active expire cycle to remove, so readers can share the lock.
+fn peek_live<'a>(storage: &'a Storage, key: &[u8]) -> Option<&'a Entry> {
+    let entry = peek_untouched(storage, key)?;
+    entry.2.touch();
+    Some(entry)
+}
+
+/// Like `peek_live`, but without counting as an access to the key, for
+/// commands that only inspect it.
+fn peek_untouched<'a>(storage: &'a Storage, key: &[u8]) -> Option<&'a Entry> {
+    storage
+        .get(key)
+        .filter(|(expiry, _, _)| !is_expired(expiry))
+}
+
+/// Looks up the value at `key`, replacing a missing or expired entry with a
+/// new one created by `default` that has no expiry.
+fn get_or_insert_with<F>(storage: &mut Storage, key: Vec<u8>, default: F) -> &mut RedisValue
+where
+    F: Fn() -> RedisValue,
+{
+    let entry = storage
+        .entry(key)
+        .or_insert_with(|| (None, default(), LastAccess::default()));
+    if is_expired(&entry.0) {
+        *entry = (None, default(), LastAccess::default());
+    }
+    entry.2.touch();
+    &mut entry.1
+}
+
+/// Pops up to `count` elements from the front (or back) of the list at `key`,
+/// deleting the key once the list is empty. Returns `None` if there is no list.
+fn list_pop(
+    storage: &mut Storage,
+    key: &[u8],
+    count: usize,
+    front: bool,
+) -> Result<Option<Vec<Vec<u8>>>, CommandError> {
+    let list = match get_live(storage, key) {
+        Some((_, RedisValue::List(list), _)) => list,
+        Some(_) => return Err(CommandError::WrongType),
+        None => return Ok(None),
+    };
+    let count = count.min(list.len());
+    let popped = if front {
+        list.drain(..count).collect::<Vec<_>>()
+    } else {
+        list.drain(list.len() - count..).rev().collect::<Vec<_>>()
+    };
+    if list.is_empty() {
+        storage.remove(key);
+    }
+    Ok(Some(popped))
+}
+
+/// Pops an element from one end of the list at `source` and pushes it onto
+/// one end of the list at `destination`, which may be the same list, as
+/// `LMOVE` does. Returns `None` if there is no list at `source`.
+fn list_move(
+    storage: &mut WriteStripes,
+    source: &[u8],
+    destination: &[u8],
+    from_front: bool,
+    to_front: bool,
+) -> Result<Option<Vec<u8>>, CommandError> {
+    match get_live(storage.stripe_mut(source), source) {
+        Some((_, RedisValue::List(_), _)) => {}
+        Some(_) => return Err(CommandError::WrongType),
+        None => return Ok(None),
+    }
+    if let Some((_, value, _)) = get_live(storage.stripe_mut(destination), destination) {
+        if !matches!(value, RedisValue::List(_)) {
+            return Err(CommandError::WrongType);
+        }
+    }
+    let Some((_, RedisValue::List(list), _)) = storage.stripe_mut(source).get_mut(source) else {
+        return Ok(None);
+    };
+    let Some(element) = (if from_front {
+        list.pop_front()
+    } else {
+        list.pop_back()
+    }) else {
+        return Ok(None);
+    };
+    // Only remove the source once the element is pushed, so a list rotated
+    // onto itself keeps its expiry.
+    let list = get_or_insert_with(
+        storage.stripe_mut(destination),
+        destination.to_vec(),
+        || RedisValue::List(VecDeque::new()),
+    );
+    if let RedisValue::List(list) = list {
+        if to_front {
+            list.push_front(element.clone());
+        } else {
+            list.push_back(element.clone());
+        }
+    }
+    if let Some((_, RedisValue::List(list), _)) = storage.get(source) {
+        if list.is_empty() {
+            storage.remove(source);
+        }
+    }
+    Ok(Some(element))
+}
+
+/// Turns a possibly negative index, counting from the end, into an offset
+/// from the start of a sequence of `len` elements.
+fn normalize_index(index: i64, len: usize) -> i64 {
+    if index < 0 {
+        index + len as i64
+    } else {
+        index
+    }
+}
+
+/// Resolves an inclusive `start..=stop` range with negative index support,
+/// clamped to a sequence of `len` elements. Returns `None` if it is empty.
+fn normalize_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let start = normalize_index(start, len).max(0);
+    let stop = normalize_index(stop, len).min(len as i64 - 1);
+    if start > stop {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Finds the indices of the elements of `list` equal to `element`, as `LPOS`
+/// does, counting from the head even when searching from the tail.
+fn list_positions(list: &VecDeque<Vec<u8>>, element: &[u8], options: &LPosOptions) -> Vec<usize> {
+    let items: Box<dyn Iterator<Item = (usize, &Vec<u8>)>> = if options.rank < 0 {
+        Box::new(list.iter().enumerate().rev())
+    } else {
+        Box::new(list.iter().enumerate())
+    };
+    let max_len = match options.max_len {
+        0 => list.len(),
+        max_len => max_len,
+    };
+    let count = match options.count {
+        Some(0) => list.len(),
+        Some(count) => count,
+        None => 1,
+    };
+    items
+        .take(max_len)
+        .filter(|(_, item)| item.as_slice() == element)
+        .map(|(i, _)| i)
+        .skip(options.rank.unsigned_abs() as usize - 1)
+        .take(count)
+        .collect()
+}
+
+/// Wakes up to `count` clients blocked on `key`, longest waiting first.
+fn wake_blocked(blocked: &mut Blocked, key: &[u8], count: usize) {
+    if let Some(waiters) = blocked.get_mut(key) {
+        for waker in waiters.drain(..count.min(waiters.len())) {
+            waker.wake();
+        }
+        if waiters.is_empty() {
+            blocked.remove(key);
+        }
+    }
+}
+
+/// Looks up the sets stored at `keys`, with `None` standing in for missing keys.
+fn get_sets<'a>(
+    storage: &'a ReadStripes,
+    keys: &[Vec<u8>],
+) -> Result<Vec<Option<&'a HashSet<Vec<u8>>>>, CommandError> {
+    keys.iter()
+        .map(|key| match peek_live(storage.stripe(key), key) {
+            Some((_, RedisValue::Set(set), _)) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Remaining time to live of `key` in milliseconds, or -2 if the key does
+/// not exist and -1 if it has no expiry.
+fn ttl_millis(storage: &Storage, key: &[u8]) -> i64 {
+    match peek_untouched(storage, key) {
+        Some((Some(expiry), _, _)) => expiry
+            .saturating_duration_since(time::Instant::now())
+            .as_millis() as i64,
+        Some((None, _, _)) => -1,
+        None => -2,
+    }
+}
+
+/// Current wall-clock time as milliseconds since the Unix epoch.
+fn unix_millis_now() -> i64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Sets `key` to expire `millis` milliseconds from now, deleting it right
+/// away if that is not in the future. Returns whether the key existed.
+fn expire_in(
+    storage: &mut Storage,
+    key: &[u8],
+    millis: i64,
+    command: &'static str,
+) -> Result<bool, CommandError> {
+    match storage.get_mut(key) {
+        Some((expiry, _, _)) if is_expired(expiry) => {
+            storage.remove(key);
+            Ok(false)
+        }
+        Some(_) if millis <= 0 => {
+            storage.remove(key);
+            Ok(true)
+        }
+        Some((expiry, _, _)) => {
+            let deadline = time::Instant::now()
+                .checked_add(time::Duration::from_millis(millis as u64))
+                .ok_or(CommandError::InvalidExpireTime(command))?;
+            *expiry = Some(deadline);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Writes `value` to `key` according to `options`. Returns whether the write
+/// took place along with the previous value of the key when `options.get` is
+/// set and the key was live.
+fn set_value(
+    storage: &mut Storage,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    options: &SetOptions,
+) -> Result<(bool, Option<Vec<u8>>), CommandError> {
+    let (exists, previous_expiry, previous) = match get_live(storage, &key) {
+        Some((expiry, RedisValue::String(v), _)) => (true, *expiry, options.get.then(|| v.clone())),
+        Some(_) if options.get => return Err(CommandError::WrongType),
+        Some((expiry, _, _)) => (true, *expiry, None),
+        None => (false, None, None),
+    };
+    let allowed = match options.condition {
+        Some(SetCondition::Nx) => !exists,
+        Some(SetCondition::Xx) => exists,
+        None => true,
+    };
+    if allowed {
+        let expiry = if options.keep_ttl {
+            previous_expiry
+        } else {
+            options
+                .expiry
+                .map(|t| time::Instant::now() + time::Duration::from_millis(t))
+        };
+        storage.insert(
+            key,
+            (expiry, RedisValue::String(value), LastAccess::default()),
+        );
+    }
+    Ok((allowed, previous))
+}
+
+/// Removes `keys` from database `db` and returns how many existed. With
+/// `lazy`, large collections are freed on another thread, so the caller
+/// doesn't wait for it.
+fn delete(state: &State, db: usize, keys: &[Vec<u8>], lazy: bool) -> i64 {
+    let mut storage = state.storage[db].write_keys(keys);
+    let mut removed = 0;
+    for key in keys {
+        if let Some((expiry, value, _)) = storage.remove(key) {
+            if !is_expired(&expiry) {
+                notify_keyspace_event(state, db, 'g', "del", key);
+                removed += 1;
+            }
+            let len = match &value {
+                RedisValue::String(_) => 1,
+                RedisValue::List(list) => list.len(),
+                RedisValue::Hash(hash) => hash.len(),
+                RedisValue::Set(set) => set.len(),
+                RedisValue::ZSet(zset) => zset.len(),
+            };
+            if lazy && len > LAZYFREE_THRESHOLD {
+                // The thread lives as long as the state, so this can't fail.
+                state.lazyfree.send(value).unwrap();
+            }
+        }
+    }
+    removed
+}
+
+/// Returns the string stored at `key`, and if the key is live updates its
+/// expiry as `GETEX` does, along with whether the expiry changed.
+fn get_ex(
+    storage: &mut Storage,
+    key: &[u8],
+    expiry: Option<&GetExExpiry>,
+) -> Result<Option<(Vec<u8>, bool)>, CommandError> {
+    let value = match get_live(storage, key) {
+        Some((_, RedisValue::String(v), _)) => v.clone(),
+        Some(_) => return Err(CommandError::WrongType),
+        None => return Ok(None),
+    };
+    let changed = match expiry {
+        None => false,
+        Some(GetExExpiry::Persist) => storage
+            .get_mut(key)
+            .is_some_and(|(expiry, _, _)| expiry.take().is_some()),
+        Some(GetExExpiry::In(millis)) => expire_in(storage, key, *millis, "getex")?,
+        Some(GetExExpiry::At(timestamp)) => {
+            let millis = timestamp.saturating_sub(unix_millis_now());
+            expire_in(storage, key, millis, "getex")?
+        }
+    };
+    Ok(Some((value, changed)))
+}
+
+/// Adds `delta` to the integer stored at `key`, treating a missing or expired
+/// key as 0. The existing expiry of the key is kept.
+fn increment(storage: &mut Storage, key: Vec<u8>, delta: i64) -> Result<i64, CommandError> {
+    let RedisValue::String(value) =
+        get_or_insert_with(storage, key, || RedisValue::String(b"0".to_vec()))
+    else {
+        return Err(CommandError::WrongType);
+    };
+    let current = std::str::from_utf8(value)
+        .map_err(|_| CommandError::NotInteger)?
+        .parse::<i64>()
+        .map_err(|_| CommandError::NotInteger)?;
+    let new = current.checked_add(delta).ok_or(CommandError::NotInteger)?;
+    *value = new.to_string().into_bytes();
+    Ok(new)
+}
+
+/// Adds `delta` to the float stored at `key`, treating a missing or expired
+/// key as 0, and returns the result as it's stored. The existing expiry of the
+/// key is kept. A missing key is only created if the result is valid.
+fn increment_float(
+    storage: &mut Storage,
+    key: Vec<u8>,
+    delta: f64,
+) -> Result<String, CommandError> {
+    match get_live(storage, &key) {
+        Some((_, RedisValue::String(value), _)) => {
+            let new = add_float(parse_float(value)?, delta)?;
+            *value = new.clone().into_bytes();
+            Ok(new)
+        }
+        Some(_) => Err(CommandError::WrongType),
+        None => {
+            let new = add_float(0.0, delta)?;
+            let value = RedisValue::String(new.clone().into_bytes());
+            storage.insert(key, (None, value, LastAccess::default()));
+            Ok(new)
+        }
+    }
+}
+
+/// Adds `delta` to the integer stored in `field` of the hash at `key`,
+/// treating a missing field as 0.
+fn hash_increment(
+    storage: &mut Storage,
+    key: Vec<u8>,
+    field: Vec<u8>,
+    delta: i64,
+) -> Result<i64, CommandError> {
+    let RedisValue::Hash(hash) =
+        get_or_insert_with(storage, key, || RedisValue::Hash(HashMap::new()))
+    else {
+        return Err(CommandError::WrongType);
+    };
+    let current = match hash.get(&field) {
+        Some(value) => parse_integer(value).map_err(|_| CommandError::HashNotInteger)?,
+        None => 0,
+    };
+    let new = current.checked_add(delta).ok_or(CommandError::NotInteger)?;
+    hash.insert(field, new.to_string().into_bytes());
+    Ok(new)
+}
+
+/// Adds `delta` to the float stored in `field` of the hash at `key`, treating
+/// a missing field as 0, and returns the result as it's stored. A missing
+/// hash is only created if the result is valid.
+fn hash_increment_float(
+    storage: &mut Storage,
+    key: Vec<u8>,
+    field: Vec<u8>,
+    delta: f64,
+) -> Result<String, CommandError> {
+    let current = match get_live(storage, &key) {
+        Some((_, RedisValue::Hash(hash), _)) => match hash.get(&field) {
+            Some(value) => parse_float(value).map_err(|_| CommandError::HashNotFloat)?,
+            None => 0.0,
+        },
+        Some(_) => return Err(CommandError::WrongType),
+        None => 0.0,
+    };
+    let new = add_float(current, delta)?;
+    if let RedisValue::Hash(hash) =
+        get_or_insert_with(storage, key, || RedisValue::Hash(HashMap::new()))
+    {
+        hash.insert(field, new.clone().into_bytes());
+    }
+    Ok(new)
+}
+
+/// Adds or updates members of the sorted set at `key` as `ZADD` does, and
+/// returns how many were added, how many had their score changed, and the
+/// score of the last member, or `None` if it was skipped.
+fn zadd(
+    storage: &mut Storage,
+    key: &[u8],
+    options: &ZAddOptions,
+    pairs: ScoredMembers,
+) -> Result<(usize, usize, Option<f64>), CommandError> {
+    let RedisValue::ZSet(zset) = get_or_insert_with(storage, key.to_vec(), || {
+        RedisValue::ZSet(SortedSet::default())
+    }) else {
+        return Err(CommandError::WrongType);
+    };
+    let (mut added, mut changed, mut last) = (0, 0, None);
+    for (score, member) in pairs {
+        let current = zset.score(&member);
+        let score = match current {
+            Some(current) if options.incr => current + score,
+            _ => score,
+        };
+        // Only incrementing an existing member can get here, so the sorted
+        // set isn't left empty.
+        if score.is_nan() {
+            return Err(CommandError::ScoreNan);
+        }
+        last = match current {
+            Some(_) if options.nx => None,
+            None if options.xx => None,
+            // GT and LT only stop existing members from being updated.
+            Some(current)
+                if (options.gt && score <= current) || (options.lt && score >= current) =>
+            {
+                None
+            }
+            Some(current) => {
+                if score != current {
+                    zset.insert(member, score);
+                    changed += 1;
+                }
+                Some(score)
+            }
+            None => {
+                zset.insert(member, score);
+                added += 1;
+                Some(score)
+            }
+        };
+    }
+    // Don't leave an empty sorted set behind when nothing was added.
+    if zset.is_empty() {
+        storage.remove(key);
+    }
+    Ok((added, changed, last))
+}
+
+/// Adds two floats for `INCRBYFLOAT` and `HINCRBYFLOAT`, and formats the
+/// result as it's stored. Redis adds them as `long double`s and keeps 17
+/// decimal places, so that 0.1 plus 0.2 makes 0.3 rather than
+/// 0.30000000000000004. Adding the shortest decimals that read back as each
+/// float gives the same result, for all but very large numbers.
+fn add_float(current: f64, delta: f64) -> Result<String, CommandError> {
+    let new = current + delta;
+    if !new.is_finite() {
+        return Err(CommandError::NanOrInfinity);
+    }
+    let sum = to_fixed(current)
+        .zip(to_fixed(delta))
+        .and_then(|(current, delta)| current.checked_add(delta));
+    Ok(match sum {
+        Some(sum) => {
+            let sign = if sum < 0 { "-" } else { "" };
+            let (whole, fraction) = (
+                sum.unsigned_abs() / FIXED_ONE,
+                sum.unsigned_abs() % FIXED_ONE,
+            );
+            trim_fraction(format!("{}{}.{:017}", sign, whole, fraction))
+        }
+        None => trim_fraction(format!("{:.17}", new)),
+    })
+}
+
+/// One in the fixed point numbers `INCRBYFLOAT` adds, which have 17 decimal
+/// places.
+const FIXED_ONE: u128 = 10u128.pow(17);
+
+/// The shortest decimal that reads back as `n`, in 17 decimal places, or
+/// `None` if it's too large to hold.
+fn to_fixed(n: f64) -> Option<i128> {
+    // Like "-1.25e3", with as few digits as it takes.
+    let shortest = format!("{:e}", n);
+    let (mantissa, exponent) = shortest.split_once('e')?;
+    let exponent = exponent.parse::<i32>().ok()?;
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => (-1, mantissa),
+        None => (1, mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+    let value = digits.parse::<i128>().ok()?;
+    // How many places the digits must move to be in 10^-17ths.
+    let shift = exponent - (digits.len() as i32 - 1) + 17;
+    let fixed = if shift >= 0 {
+        value.checked_mul(10i128.checked_pow(shift as u32)?)?
+    } else {
+        // Round away anything past the 17th decimal place.
+        match 10i128.checked_pow(shift.unsigned_abs()) {
+            Some(divisor) => (value + divisor / 2) / divisor,
+            None => 0,
+        }
+    };
+    Some(sign * fixed)
+}
+
+/// Drops the zeros at the end of a number's decimal places, and the point
+/// too if nothing's left after it.
+fn trim_fraction(n: String) -> String {
+    if !n.contains('.') {
+        return n;
+    }
+    n.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Sends `message` to the clients subscribed to `channel`, directly or by
+/// pattern, and returns how many received it.
+fn publish(state: &State, channel: &[u8], message: &[u8]) -> i64 {
+    let mut receivers = 0;
+    let channels = state.channels.lock().unwrap();
+    for subscriber in channels.get(channel).into_iter().flat_map(|s| s.values()) {
+        let out = serialize_to_push(
+            &[
+                serialize_to_bulk_string(b"message"),
+                serialize_to_bulk_string(channel),
+                serialize_to_bulk_string(message),
+            ],
+            subscriber.protocol,
+        );
+        if subscriber.sender.send(out).is_ok() {
+            receivers += 1;
+        }
+    }
+    drop(channels);
+    let patterns = state.patterns.lock().unwrap();
+    for (pattern, subscribers) in patterns.iter() {
+        if !glob_match(pattern, channel) {
+            continue;
+        }
+        for subscriber in subscribers.values() {
+            let out = serialize_to_push(
+                &[
+                    serialize_to_bulk_string(b"pmessage"),
+                    serialize_to_bulk_string(pattern),
+                    serialize_to_bulk_string(channel),
+                    serialize_to_bulk_string(message),
+                ],
+                subscriber.protocol,
+            );
+            if subscriber.sender.send(out).is_ok() {
+                receivers += 1;
+            }
+        }
+    }
+    receivers
+}
+
+/// Publishes `event` on `key` to the keyspace and keyevent channels, as far
+/// as `notify-keyspace-events` enables them and the event's `class`.
+fn notify_keyspace_event(state: &State, db: usize, class: char, event: &str, key: &[u8]) {
+    let flags = state.keyspace_events.load(Ordering::Relaxed);
+    if flags & keyspace_event_flag(class).unwrap_or(0) == 0 {
+        return;
+    }
+    if flags & keyspace_event_flag('K').unwrap_or(0) != 0 {
+        let mut channel = format!("__keyspace@{db}__:").into_bytes();
+        channel.extend_from_slice(key);
+        publish(state, &channel, event.as_bytes());
+    }
+    if flags & keyspace_event_flag('E').unwrap_or(0) != 0 {
+        publish(state, format!("__keyevent@{db}__:{event}").as_bytes(), key);
+    }
+}
+
+/// Notifies an applied expire, which deletes the key if the time has
+/// already passed.
+fn notify_expire(state: &State, db: usize, storage: &Storage, key: &[u8]) {
+    if storage.contains_key(key) {
+        notify_keyspace_event(state, db, 'g', "expire", key);
+    } else {
+        notify_keyspace_event(state, db, 'g', "del", key);
+    }
+}
+
+/// Notifies a pop from a list, and the list's removal if it's now empty.
+fn notify_list_pop(state: &State, db: usize, storage: &Storage, key: &[u8], event: &str) {
+    notify_keyspace_event(state, db, 'l', event, key);
+    if !storage.contains_key(key) {
+        notify_keyspace_event(state, db, 'g', "del", key);
+    }
+}
+
+/// Formats a command the way `MONITOR` shows it: the time it was received,
+/// the database and address of the client that sent it, then each argument
+/// quoted.
+fn monitor_line(object: &RedisObject, db: usize, addr: &str) -> Vec<u8> {
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!(
+        "{}.{:06} [{} {}]",
+        now.as_secs(),
+        now.subsec_micros(),
+        db,
+        addr
+    );
+    for arg in command_args(object) {
+        line.push(' ');
+        line.push_str(&quote(&arg));
+    }
+    serialize_to_simple_string(line.as_bytes())
+}
+
+/// The arguments of a command as it was sent, including its name.
+fn command_args(object: &RedisObject) -> Vec<Vec<u8>> {
+    let args = match object {
+        RedisObject::Array(args) => args.as_slice(),
+        object => std::slice::from_ref(object),
+    };
+    args.iter()
+        .map(|arg| match arg {
+            RedisObject::BulkString(s) => s.clone(),
+            RedisObject::Array(_) => Vec::new(),
+        })
+        .collect()
+}
+
+/// Records a command in the slow log if it took longer than
+/// `slowlog-log-slower-than`. `raw` is the command as the client sent it,
+/// only parsed again if it was slow.
+fn log_if_slow(state: &State, session: &Session, addr: &str, raw: &[u8], duration: time::Duration) {
+    let (threshold, max_len) = {
+        let config = state.config.lock().unwrap();
+        (config.slowlog_log_slower_than(), config.slowlog_max_len())
+    };
+    if threshold.is_none_or(|threshold| duration < threshold) {
+        return;
+    }
+    let Ok(Some((object, _))) = RESPParser::default().parse(raw) else {
+        return;
+    };
+    let name = state
+        .clients
+        .lock()
+        .unwrap()
+        .get(&session.client.id)
+        .map(|client| client.name.clone())
+        .unwrap_or_default();
+    let mut slowlog = state.slowlog.lock().unwrap();
+    let entry = SlowLogEntry {
+        id: slowlog.next_id,
+        timestamp: unix_millis_now() / 1000,
+        duration,
+        args: slowlog_args(command_args(&object)),
+        addr: addr.to_string(),
+        name,
+    };
+    slowlog.next_id += 1;
+    slowlog.entries.push_front(entry);
+    slowlog.entries.truncate(max_len);
+}
+
+/// Shortens the arguments of a command for the slow log like Redis does,
+/// replacing those past the limit with a count of how many were left out,
+/// and cutting long ones short.
+fn slowlog_args(mut args: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if args.len() > SLOWLOG_ENTRY_MAX_ARGC {
+        let more = args.len() - (SLOWLOG_ENTRY_MAX_ARGC - 1);
+        args.truncate(SLOWLOG_ENTRY_MAX_ARGC - 1);
+        args.push(format!("... ({} more arguments)", more).into_bytes());
+    }
+    for arg in args.iter_mut() {
+        if arg.len() > SLOWLOG_ENTRY_MAX_STRING {
+            let more = arg.len() - SLOWLOG_ENTRY_MAX_STRING;
+            arg.truncate(SLOWLOG_ENTRY_MAX_STRING);
+            arg.extend_from_slice(format!("... ({} more bytes)", more).as_bytes());
+        }
+    }
+    args
+}
+
+/// Quotes a string, escaping quotes, backslashes and unprintable bytes, as
+/// Redis does when showing binary data.
+fn quote(s: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in s {
+        match byte {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Sends a command formatted by `monitor_line` to every client that ran
+/// `MONITOR`.
+fn feed_monitors(state: &State, line: &[u8]) {
+    for sender in state.monitors.lock().unwrap().values() {
+        let _ = sender.send(line.to_vec());
+    }
+}
+
+/// Subscribes to each channel, or pattern if `pattern` is set, replying with
+/// a confirmation for each.
+fn subscribe(session: &mut Session, names: Vec<Vec<u8>>, pattern: bool) -> Vec<u8> {
+    let kind: &[u8] = if pattern { b"psubscribe" } else { b"subscribe" };
+    let mut out = Vec::new();
+    for name in names {
+        session.subscribe(name.clone(), pattern);
+        out.extend(serialize_to_push(
+            &[
+                serialize_to_bulk_string(kind),
+                serialize_to_bulk_string(&name),
+                serialize_to_integer(session.subscriptions() as i64),
+            ],
+            session.protocol,
+        ));
+    }
+    out
+}
+
+/// Unsubscribes from each channel, or pattern if `pattern` is set, or from
+/// all of them if none are given, replying with a confirmation for each.
+fn unsubscribe(session: &mut Session, names: Vec<Vec<u8>>, pattern: bool) -> Vec<u8> {
+    let kind: &[u8] = if pattern {
+        b"punsubscribe"
+    } else {
+        b"unsubscribe"
+    };
+    let names = match (names.is_empty(), pattern) {
+        (false, _) => names,
+        (true, false) => session.channels.iter().cloned().collect(),
+        (true, true) => session.patterns.iter().cloned().collect(),
+    };
+    if names.is_empty() {
+        return serialize_to_push(
+            &[
+                serialize_to_bulk_string(kind),
+                serialize_null_bulk(session.protocol),
+                serialize_to_integer(session.subscriptions() as i64),
+            ],
+            session.protocol,
+        );
+    }
+    let mut out = Vec::new();
+    for name in names {
+        session.unsubscribe(&name, pattern);
+        out.extend(serialize_to_push(
+            &[
+                serialize_to_bulk_string(kind),
+                serialize_to_bulk_string(&name),
+                serialize_to_integer(session.subscriptions() as i64),
+            ],
+            session.protocol,
+        ));
+    }
+    out
+}
+
+/// Matches `key` against a glob-style `pattern` supporting `*`, `?`,
+/// character classes such as `[abc]`, `[a-z]` and `[^x]`, and `\` escapes.
+/// When the rest of the pattern fails to match, only the last `*` seen
+/// tries matching one more byte, since earlier ones can't do any better,
+/// so matching takes at most the lengths of the two multiplied.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // The pattern just after the last `*`, and how much of the key that
+    // `*` matches so far.
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], key[k]) {
+            p += len;
+            k += 1;
+            continue;
+        }
+        let Some((after_star, matched)) = star else {
+            return false;
+        };
+        p = after_star;
+        k = matched + 1;
+        star = Some((after_star, k));
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches a byte against the start of a glob pattern, other than `*`.
+/// Returns how much of the pattern the match took up, or `None` if the
+/// byte doesn't match or the pattern is empty.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        class = tail;
+                        break;
+                    }
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= *escaped == c;
+                        class = tail;
+                    }
+                    [start, b'-', end, tail @ ..] if *end != b']' => {
+                        let (low, high) = if start <= end {
+                            (*start, *end)
+                        } else {
+                            (*end, *start)
+                        };
+                        matched |= (low..=high).contains(&c);
+                        class = tail;
+                    }
+                    [literal, tail @ ..] => {
+                        matched |= *literal == c;
+                        class = tail;
+                    }
+                }
+            }
+            (matched != negate).then_some(pattern.len() - class.len())
+        }
+        [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+        [literal, ..] => (*literal == c).then_some(1),
+    }
+}
+
+fn serialize_to_array(strings: &[&[u8]]) -> Vec<u8> {
+    [
+        b"*",
+        format!("{}", strings.len()).as_bytes(),
+        b"\r\n",
+        strings
+            .iter()
+            .map(|s| serialize_to_bulk_string(s))
+            .collect::<Vec<_>>()
+            .concat()
+            .as_slice(),
+    ]
+    .concat()
+}
+/// Builds an array out of elements that are already serialized.
+fn serialize_to_raw_array(elements: &[Vec<u8>]) -> Vec<u8> {
+    [
+        b"*",
+        format!("{}", elements.len()).as_bytes(),
+        b"\r\n",
+        elements.concat().as_slice(),
+    ]
+    .concat()
+}
+
+/// Builds a map out of alternating keys and values that are already
+/// serialized: a RESP3 map, or a flat array for RESP2 clients.
+fn serialize_to_raw_map(elements: &[Vec<u8>], protocol: i64) -> Vec<u8> {
+    if protocol != 3 {
+        return serialize_to_raw_array(elements);
+    }
+    [
+        b"%",
+        format!("{}", elements.len() / 2).as_bytes(),
+        b"\r\n",
+        elements.concat().as_slice(),
+    ]
+    .concat()
+}
+
+/// Builds an out-of-band message such as a published message out of
+/// elements that are already serialized: a RESP3 push, or an array for RESP2
+/// clients.
+fn serialize_to_push(elements: &[Vec<u8>], protocol: i64) -> Vec<u8> {
+    if protocol != 3 {
+        return serialize_to_raw_array(elements);
+    }
+    [
+        b">",
+        format!("{}", elements.len()).as_bytes(),
+        b"\r\n",
+        elements.concat().as_slice(),
+    ]
+    .concat()
+}
+
+fn serialize_to_simple_string(s: &[u8]) -> Vec<u8> {
+    [b"+", s, b"\r\n"].concat()
+}
+
+fn serialize_to_error(s: &[u8]) -> Vec<u8> {
+    [b"-", s, b"\r\n"].concat()
+}
+
+fn serialize_to_bulk_string(s: &[u8]) -> Vec<u8> {
+    [b"$", format!("{}", s.len()).as_bytes(), b"\r\n", s, b"\r\n"].concat()
+}
+
+fn serialize_to_integer(n: i64) -> Vec<u8> {
+    [b":", format!("{}", n).as_bytes(), b"\r\n"].concat()
+}
+
+fn serialize_null_bulk(protocol: i64) -> Vec<u8> {
+    if protocol == 3 {
+        b"_\r\n".to_vec()
+    } else {
+        b"$-1\r\n".to_vec()
+    }
+}
+
+fn serialize_null_array(protocol: i64) -> Vec<u8> {
+    if protocol == 3 {
+        b"_\r\n".to_vec()
+    } else {
+        b"*-1\r\n".to_vec()
+    }
+}
+
+/// Formats a double the way Redis replies with it, like C's `%.17g`: 17
+/// significant digits without trailing zeros, in exponent form if the number
+/// is very large or small.
+fn format_double(n: f64) -> String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    let scientific = format!("{:.16e}", n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+    if (-4..17).contains(&exponent) {
+        trim_fraction(format!("{:.*}", (16 - exponent) as usize, n))
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{}{:02}",
+            trim_fraction(mantissa.to_string()),
+            sign,
+            exponent.abs()
+        )
+    }
+}
+
+/// A RESP3 double, or a bulk string for RESP2 clients.
+fn serialize_to_double(n: f64, protocol: i64) -> Vec<u8> {
+    if protocol == 3 {
+        [b",", format_double(n).as_bytes(), b"\r\n"].concat()
+    } else {
+        serialize_to_bulk_string(format_double(n).as_bytes())
+    }
+}
+
+/// Sorted set members, optionally with their scores: as a flat array of
+/// members and scores for RESP2 clients, or an array of pairs for RESP3.
+fn serialize_scored_members(
+    members: &[(&Vec<u8>, f64)],
+    with_scores: bool,
+    protocol: i64,
+) -> Vec<u8> {
+    let mut elements = Vec::new();
+    for (member, score) in members {
+        let member = serialize_to_bulk_string(member);
+        if !with_scores {
+            elements.push(member);
+        } else if protocol == 3 {
+            let pair = [member, serialize_to_double(*score, protocol)];
+            elements.push(serialize_to_raw_array(&pair));
+        } else {
+            elements.extend([member, serialize_to_double(*score, protocol)]);
+        }
+    }
+    serialize_to_raw_array(&elements)
+}
+
+/// Builds the reply to `INFO`: `field:value` lines grouped into sections,
+/// either every section or only the one named.
+fn info(state: &State, section: Option<&str>) -> String {
+    let uptime = state.start_time.elapsed().as_secs();
+    let keyspace = state
+        .storage
+        .iter()
+        .enumerate()
+        .filter_map(|(index, storage)| {
+            let storage = storage.read_all();
+            let expires = storage
+                .iter()
+                .filter(|(_, (expiry, _, _))| expiry.is_some());
+            (!storage.is_empty()).then(|| {
+                format!(
+                    "db{}:keys={},expires={},avg_ttl=0",
+                    index,
+                    storage.len(),
+                    expires.count()
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+    let sections = [
+        (
+            "Server",
+            vec![
+                format!("redis_version:{}", REDIS_VERSION),
+                "redis_mode:standalone".to_string(),
+                format!("process_id:{}", std::process::id()),
+                format!("tcp_port:{}", state.config.lock().unwrap().port()),
+                format!("uptime_in_seconds:{}", uptime),
+                format!("uptime_in_days:{}", uptime / (24 * 60 * 60)),
+            ],
+        ),
+        (
+            "Clients",
+            vec![format!(
+                "connected_clients:{}",
+                state.clients.lock().unwrap().len()
+            )],
+        ),
+        ("Memory", {
+            let config = state.config.lock().unwrap();
+            let maxmemory = config.maxmemory().unwrap_or(0);
+            vec![
+                format!("used_memory:{}", memory::used()),
+                format!("used_memory_human:{}", bytes_to_human(memory::used())),
+                format!("used_memory_peak:{}", memory::peak()),
+                format!("used_memory_peak_human:{}", bytes_to_human(memory::peak())),
+                format!("maxmemory:{}", maxmemory),
+                format!("maxmemory_human:{}", bytes_to_human(maxmemory)),
+                format!("maxmemory_policy:{}", config.maxmemory_policy().name()),
+            ]
+        }),
+        (
+            "Persistence",
+            vec![
+                format!(
+                    "rdb_bgsave_in_progress:{}",
+                    state.bgsave_in_progress.load(Ordering::SeqCst) as u8
+                ),
+                format!(
+                    "rdb_last_save_time:{}",
+                    state.rdb_last_save_time.load(Ordering::SeqCst)
+                ),
+            ],
+        ),
+        ("Replication", {
+            let replication = state.replication.lock().unwrap();
+            let mut lines = match &replication.master {
+                Some(master) => vec![
+                    "role:slave".to_string(),
+                    format!("master_host:{}", master.host),
+                    format!("master_port:{}", master.port),
+                    format!(
+                        "master_link_status:{}",
+                        if master.synced { "up" } else { "down" }
+                    ),
+                    format!("slave_repl_offset:{}", replication.offset),
+                ],
+                None => vec!["role:master".to_string()],
+            };
+            lines.extend([
+                format!("connected_slaves:{}", replication.replicas.len()),
+                format!("master_replid:{}", replication.replid),
+                format!("master_repl_offset:{}", replication.offset),
+            ]);
+            lines
+        }),
+        ("Keyspace", keyspace),
+    ];
+    let section = section.map(|section| section.to_lowercase());
+    sections
+        .iter()
+        .filter(|(name, _)| match section.as_deref() {
+            None | Some("all" | "default" | "everything") => true,
+            Some(section) => name.to_lowercase() == section,
+        })
+        .map(|(name, lines)| {
+            let lines = lines.iter().map(|line| format!("{}\r\n", line));
+            format!("# {}\r\n{}", name, lines.collect::<String>())
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Formats a number of bytes the way `INFO` does, like `1.50M`.
+fn bytes_to_human(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, UNITS[unit])
+}
+
+/// Saves every database to the RDB file and replaces them with what loading
+/// it back gives, for `DEBUG RELOAD`, so anything the file doesn't preserve
+/// goes missing right away. Nothing else runs in the meantime; `in_exec` says
+/// the caller is running a transaction and so already has the lock to
+/// itself. The databases are left as they were if either step fails.
+fn reload(state: &State, in_exec: bool) -> Result<(), CommandError> {
+    let _exclusive = (!in_exec).then(|| state.exec_lock.write().unwrap());
+    let path = rdb_path(&state.config.lock().unwrap());
+    // Wait for a `BGSAVE` to finish writing, and stop another starting.
+    while state.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    let saved = snapshot(state).to_file(&path);
+    state.bgsave_in_progress.store(false, Ordering::SeqCst);
+    if let Err(e) = saved {
+        println!("error: could not save RDB file: {}", e);
+        return Err(CommandError::SaveFailed);
+    }
+    state
+        .rdb_last_save_time
+        .store(unix_millis_now() / 1000, Ordering::SeqCst);
+    let mut rdb = RDBObject::from_file(&path).map_err(|_| {
+        println!("error: could not load RDB file {}", path.display());
+        CommandError::LoadFailed
+    })?;
+    for (index, storage) in state.storage.iter().enumerate() {
+        let mut stripes = storage.write_all();
+        stripes.clear();
+        stripes.extend(rdb.databases.remove(&index).unwrap_or_default());
+        drop(stripes);
+        storage.touch_all();
+    }
+    Ok(())
+}
+
+/// Copies every database into an RDB object that can be written out.
+fn snapshot(state: &State) -> RDBObject {
+    RDBObject {
+        metadata: HashMap::new(),
+        databases: state
+            .storage
+            .iter()
+            .map(|storage| {
+                let storage = storage.read_all();
+                storage
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), entry.clone()))
+                    .collect::<Storage>()
+            })
+            .enumerate()
+            .collect(),
+    }
+}
+
+/// Path of the RDB file, using the same defaults as Redis when `--dir` or
+/// `--dbfilename` weren't given.
+fn rdb_path(config: &Config) -> PathBuf {
+    let dir = config.get("dir").unwrap_or(".");
+    let db_filename = config.get("dbfilename").unwrap_or("dump.rdb");
+    Path::new(dir).join(db_filename)
+}
+
+/// Parameters that can be read and changed with `CONFIG`.
+const CONFIG_PARAMETERS: [&str; 15] = [
+    "dir",
+    "dbfilename",
+    "active-expire-interval",
+    "notify-keyspace-events",
+    "bind",
+    "port",
+    "unixsocket",
+    "enable-debug-command",
+    "slowlog-log-slower-than",
+    "slowlog-max-len",
+    "maxclients",
+    "io-threads",
+    "timeout",
+    "maxmemory",
+    "maxmemory-policy",
+];
+
+/// Parameters that can be read with `CONFIG GET` but only set at startup.
+const IMMUTABLE_CONFIG_PARAMETERS: [&str; 5] = [
+    "bind",
+    "port",
+    "unixsocket",
+    "enable-debug-command",
+    "io-threads",
+];
+
+/// The flags accepted by `notify-keyspace-events`: `K` and `E` select the
+/// keyspace and keyevent channels, `A` stands for every event class, and the
+/// rest are the event classes themselves.
+const KEYSPACE_EVENT_FLAGS: &str = "KEAg$lshzxe";
+
+/// The bit standing for `flag`, or `None` if it isn't one of
+/// `KEYSPACE_EVENT_FLAGS`.
+fn keyspace_event_flag(flag: char) -> Option<u32> {
+    KEYSPACE_EVENT_FLAGS.find(flag).map(|bit| 1 << bit)
+}
+
+/// Checks that every character of a `notify-keyspace-events` value is one
+/// of `KEYSPACE_EVENT_FLAGS`.
+fn parse_keyspace_events(value: &str) -> Result<String, String> {
+    match value
+        .chars()
+        .find(|&flag| keyspace_event_flag(flag).is_none())
+    {
+        Some(flag) => Err(format!("unknown keyspace event flag '{}'", flag)),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Server configuration, keyed by parameter name. Parameters that haven't
+/// been given a value are left out.
+struct Config {
+    params: HashMap<String, String>,
+}
+
+impl Config {
+    fn new() -> Self {
+        Self {
+            params: HashMap::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    fn set(&mut self, name: &str, value: String) -> Result<(), CommandError> {
+        let name = name.to_lowercase();
+        if !CONFIG_PARAMETERS.contains(&name.as_str()) {
+            return Err(CommandError::UnknownOption(name));
+        }
+        if IMMUTABLE_CONFIG_PARAMETERS.contains(&name.as_str()) {
+            return Err(CommandError::ImmutableConfig(name));
+        }
+        if matches!(
+            name.as_str(),
+            "active-expire-interval" | "slowlog-max-len" | "maxclients" | "timeout"
+        ) && value.parse::<u64>().is_err()
+        {
+            return Err(CommandError::InvalidConfigValue(value, name));
+        }
+        // An expire cycle every 0ms would never let go of the locks.
+        if name == "active-expire-interval" && value.parse::<u64>() == Ok(0) {
+            return Err(CommandError::InvalidConfigValue(value, name));
+        }
+        if name == "slowlog-log-slower-than" && value.parse::<i64>().is_err() {
+            return Err(CommandError::InvalidConfigValue(value, name));
+        }
+        // Stored in bytes, as `CONFIG GET` reports it.
+        let value = match name.as_str() {
+            "maxmemory" => match parse_memory(&value) {
+                Ok(bytes) => bytes.to_string(),
+                Err(_) => return Err(CommandError::InvalidConfigValue(value, name)),
+            },
+            "maxmemory-policy" => match MaxMemoryPolicy::parse(&value) {
+                Some(policy) => policy.name().to_string(),
+                None => return Err(CommandError::InvalidConfigValue(value, name)),
+            },
+            _ => value,
+        };
+        if name == "notify-keyspace-events" && parse_keyspace_events(&value).is_err() {
+            return Err(CommandError::InvalidConfigValue(value, name));
+        }
+        self.params.insert(name, value);
+        Ok(())
+    }
+
+    fn debug_command_enabled(&self) -> bool {
+        self.get("enable-debug-command") == Some("yes")
+    }
+
+    fn port(&self) -> u16 {
+        self.get("port")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(6379)
+    }
+
+    fn active_expire_interval(&self) -> time::Duration {
+        let millis = self
+            .get("active-expire-interval")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(100);
+        time::Duration::from_millis(millis)
+    }
+
+    /// How long a command must take to be recorded in the slow log, or
+    /// `None` if the slow log is disabled.
+    fn slowlog_log_slower_than(&self) -> Option<time::Duration> {
+        let micros = self
+            .get("slowlog-log-slower-than")
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(10000);
+        u64::try_from(micros).ok().map(time::Duration::from_micros)
+    }
+
+    fn slowlog_max_len(&self) -> usize {
+        self.get("slowlog-max-len")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(128)
+    }
+
+    /// How long a client can stay idle before it's disconnected, or `None`
+    /// if clients are never disconnected for being idle.
+    fn timeout(&self) -> Option<time::Duration> {
+        self.get("timeout")
+            .and_then(|value| value.parse().ok())
+            .filter(|&seconds| seconds > 0)
+            .map(time::Duration::from_secs)
+    }
+
+    fn io_threads(&self) -> usize {
+        self.get("io-threads")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1024)
+    }
+
+    fn maxclients(&self) -> u64 {
+        self.get("maxclients")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(10000)
+    }
+
+    /// How many bytes the server may use, or `None` if there's no limit.
+    fn maxmemory(&self) -> Option<usize> {
+        self.get("maxmemory")
+            .and_then(|value| value.parse().ok())
+            .filter(|&bytes| bytes > 0)
+    }
+
+    fn maxmemory_policy(&self) -> MaxMemoryPolicy {
+        self.get("maxmemory-policy")
+            .and_then(MaxMemoryPolicy::parse)
+            .unwrap_or(MaxMemoryPolicy::NoEviction)
+    }
+
+    /// The `notify-keyspace-events` flags as `keyspace_event_flag` bits,
+    /// with `A` expanded to the event classes it stands for. Nothing is
+    /// notified unless `K` or `E` is set.
+    fn notify_keyspace_events(&self) -> u32 {
+        self.get("notify-keyspace-events")
+            .unwrap_or("")
+            .replace('A', "g$lshzxe")
+            .chars()
+            .filter_map(keyspace_event_flag)
+            .fold(0, |flags, flag| flags | flag)
+    }
+}
+
+/// How room is made when a write needs memory beyond `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MaxMemoryPolicy {
+    /// Refuse the write.
+    NoEviction,
+    /// Evict the key accessed longest ago, of a few picked at random.
+    AllKeysLru,
+    /// Evict a key picked at random.
+    AllKeysRandom,
+}
+
+impl MaxMemoryPolicy {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Some(Self::NoEviction),
+            "allkeys-lru" => Some(Self::AllKeysLru),
+            "allkeys-random" => Some(Self::AllKeysRandom),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+            Self::AllKeysRandom => "allkeys-random",
+        }
+    }
+}
+
+/// Parses a number of bytes, optionally followed by a unit like Redis
+/// accepts: `k`, `m` and `g` for powers of 1000, and `kb`, `mb` and `gb` for
+/// powers of 1024, in any case.
+fn parse_memory(value: &str) -> Result<u64, String> {
+    let value = value.to_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &value[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        unit => return Err(format!("unknown unit '{}'", unit)),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory size '{}'", value))
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CommandError {
+    #[error("Error")]
+    Invalid,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(&'static str),
+    #[error("ERR timeout is not a float or out of range")]
+    InvalidTimeout,
+    #[error("ERR timeout is negative")]
+    NegativeTimeout,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(&'static str),
+    #[error("ERR Background save already in progress")]
+    BgSaveInProgress,
+    #[error("ERR DB index is out of range")]
+    InvalidDbIndex,
+    #[error("ERR source and destination objects are the same")]
+    SameObject,
+    #[error("ERR Protocol version is not an integer or out of range")]
+    InvalidProtocolVersion,
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("ERR Client names cannot contain spaces, newlines or special characters.")]
+    InvalidClientName,
+    #[error("ERR No such client")]
+    NoSuchClient,
+    #[error("ERR MULTI calls can not be nested")]
+    NestedMulti,
+    #[error("ERR {0} without MULTI")]
+    WithoutMulti(&'static str),
+    #[error("EXECABORT Transaction discarded because of previous errors.")]
+    ExecAbort,
+    #[error("ERR WATCH inside MULTI is not allowed")]
+    WatchInMulti,
+    #[error("ERR Can't execute this command: only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context")]
+    SubscribedContext,
+    #[error("ERR Unknown option '{0}'")]
+    UnknownOption(String),
+    #[error("ERR Invalid argument '{0}' for CONFIG SET '{1}'")]
+    InvalidConfigValue(String, String),
+    #[error(
+        "ERR CONFIG SET failed (possibly related to argument '{0}') - can't set immutable config"
+    )]
+    ImmutableConfig(String),
+    #[error(
+        "ERR DEBUG command not allowed. Start the server with --enable-debug-command to allow it"
+    )]
+    DebugDisabled,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR PSYNC inside MULTI is not allowed")]
+    PSyncInMulti,
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("ERR WAIT cannot be used with replica instances.")]
+    WaitOnReplica,
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.")]
+    LfuNotSelected,
+    #[error("ERR offset is out of range")]
+    OffsetOutOfRange,
+    #[error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")]
+    StringTooLong,
+    #[error("ERR hash value is not an integer")]
+    HashNotInteger,
+    #[error("ERR hash value is not a float")]
+    HashNotFloat,
+    #[error("ERR increment would produce NaN or Infinity")]
+    NanOrInfinity,
+    #[error("ERR XX and NX options at the same time are not compatible")]
+    ZAddNxAndXx,
+    #[error("ERR GT, LT, and/or NX options at the same time are not compatible")]
+    ZAddGtLtNx,
+    #[error("ERR INCR option supports a single increment-element pair")]
+    ZAddIncrPairs,
+    #[error("ERR resulting score is not a number (NaN)")]
+    ScoreNan,
+    #[error("ERR min or max is not a float")]
+    MinMaxNotFloat,
+    #[error("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the last match")]
+    LPosZeroRank,
+    #[error("ERR {0} can't be negative")]
+    Negative(&'static str),
+    #[error("ERR MONITOR isn't allowed for DENY BLOCKING client")]
+    MonitorInTransaction,
+    #[error("ERR count should be greater than or equal to -1")]
+    SlowLogCount,
+    #[error("ERR Error trying to save the DB")]
+    SaveFailed,
+    #[error("ERR Error trying to load the RDB dump, check server logs.")]
+    LoadFailed,
+    #[error("OOM command not allowed when used memory > 'maxmemory'")]
+    OutOfMemory,
+    #[error("ERR max number of clients reached")]
+    MaxClients,
+}
+
+/// Bytes from a client that aren't a valid command, after which the
+/// connection is closed, like in Redis.
+#[derive(Debug, thiserror::Error)]
+enum ProtocolError {
+    #[error("ERR Protocol error: too big inline request")]
+    InlineTooBig,
+    #[error("ERR Protocol error: unbalanced quotes in request")]
+    UnbalancedQuotes,
+    #[error("ERR Protocol error: too big mbulk count string")]
+    MultibulkCountTooBig,
+    #[error("ERR Protocol error: invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("ERR Protocol error: too big bulk count string")]
+    BulkCountTooBig,
+    #[error("ERR Protocol error: expected '$', got '{0}'")]
+    ExpectedBulk(char),
+    #[error("ERR Protocol error: invalid bulk length")]
+    InvalidBulkLength,
+}
+
+#[derive(Debug)]
+enum SetCondition {
+    /// Only set the key if it does not already exist.
+    Nx,
+    /// Only set the key if it already exists.
+    Xx,
+}
+
+#[derive(Debug, Default)]
+struct SetOptions {
+    expiry: Option<u64>,
+    condition: Option<SetCondition>,
+    /// Reply with the previous value instead of `OK`.
+    get: bool,
+    /// Retain the time to live of the existing key.
+    keep_ttl: bool,
+}
+
+/// How `GETEX` changes the expiry of a key.
+#[derive(Debug)]
+enum GetExExpiry {
+    /// Expire this many milliseconds from now.
+    In(i64),
+    /// Expire at a Unix time in milliseconds.
+    At(i64),
+    /// Remove the expiry.
+    Persist,
+}
+
+#[derive(Debug, Default)]
+struct CopyOptions {
+    /// Database to copy into, instead of the current one.
+    db: Option<usize>,
+    /// Overwrite the destination if it already exists.
+    replace: bool,
+}
+
+/// Members of a sorted set along with their scores.
+type ScoredMembers = Vec<(f64, Vec<u8>)>;
+
+/// Conditions and reply modes of `ZADD`.
+#[derive(Debug, Default)]
+struct ZAddOptions {
+    /// Only add new members.
+    nx: bool,
+    /// Only update existing members.
+    xx: bool,
+    /// Only update existing members to a greater score.
+    gt: bool,
+    /// Only update existing members to a lower score.
+    lt: bool,
+    /// Count changed members in the reply as well as added ones.
+    ch: bool,
+    /// Increment the score of a single member, replying with the new score.
+    incr: bool,
+}
+
+/// Options of `LPOS`.
+#[derive(Debug)]
+struct LPosOptions {
+    /// Which match to start from, counting from the tail if negative.
+    rank: i64,
+    /// How many matches to reply with, all of them if 0. Without a count the
+    /// reply is a single index rather than an array.
+    count: Option<usize>,
+    /// How many elements to compare at most, all of them if 0.
+    max_len: usize,
+}
+
+/// Options of `ZRANGE`.
+#[derive(Debug, Default)]
+struct ZRangeOptions {
+    /// Count from the highest score instead of the lowest.
+    rev: bool,
+    /// Reply with each member's score as well.
+    with_scores: bool,
+}
+
+/// Options of `ZRANGEBYSCORE`.
+#[derive(Debug, Default)]
+struct ZRangeByScoreOptions {
+    /// Reply with each member's score as well.
+    with_scores: bool,
+    /// How many matching members to skip, and how many to reply with.
+    limit: Option<(i64, i64)>,
+}
+
+#[derive(Debug)]
+enum ClientSubcommand {
+    SetName(String),
+    GetName,
+    Id,
+    List,
+    Kill(KillFilter),
+}
+
+#[derive(Debug)]
+enum DebugSubcommand {
+    Sleep(time::Duration),
+    JMap,
+    Object(Vec<u8>),
+    SetActiveExpire(bool),
+    QuicklistPackedThreshold,
+    /// Save the dataset to the RDB file and load it back in its place.
+    Reload,
+}
+
+/// `OBJECT` subcommands, each about the value at a key.
+#[derive(Debug)]
+enum ObjectSubcommand {
+    Encoding(Vec<u8>),
+    RefCount(Vec<u8>),
+    IdleTime(Vec<u8>),
+    Freq(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum SlowLogSubcommand {
+    /// The newest entries, up to a count or all of them if `None`.
+    Get(Option<usize>),
+    Len,
+    Reset,
+}
+
+/// The clients to disconnect with `CLIENT KILL`.
+#[derive(Debug, Default)]
+struct KillFilter {
+    id: Option<u64>,
+    addr: Option<String>,
+    /// Leave the client running the command connected.
+    skip_me: bool,
+    /// Given in the old `CLIENT KILL addr` form, which replies `OK` instead of
+    /// the number of clients killed.
+    legacy: bool,
+}
+
+#[derive(Debug)]
+enum CommandSubcommand {
+    Count,
+    Docs,
+    /// Details of the named commands, or of every command if none are named.
+    Info(Vec<String>),
+}
+
+#[derive(Debug)]
+enum Command {
+    Ping,
+    Echo(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>, SetOptions),
+    SetNx(Vec<u8>, Vec<u8>),
+    /// Set a key to expire in a number of seconds, converted to
+    /// milliseconds.
+    SetEx(Vec<u8>, u64, Vec<u8>),
+    /// Set a key to expire in a number of milliseconds.
+    PSetEx(Vec<u8>, u64, Vec<u8>),
+    GetSet(Vec<u8>, Vec<u8>),
+    GetDel(Vec<u8>),
+    GetEx(Vec<u8>, Option<GetExExpiry>),
+    MSet(Vec<(Vec<u8>, Vec<u8>)>),
+    MGet(Vec<Vec<u8>>),
+    Type(Vec<u8>),
+    Ttl(Vec<u8>),
+    PTtl(Vec<u8>),
+    Persist(Vec<u8>),
+    Expire(Vec<u8>, i64),
+    PExpire(Vec<u8>, i64),
+    ExpireAt(Vec<u8>, i64),
+    PExpireAt(Vec<u8>, i64),
+    Keys(Vec<u8>),
+    LPush(Vec<u8>, Vec<Vec<u8>>),
+    RPush(Vec<u8>, Vec<Vec<u8>>),
+    LPop(Vec<u8>, Option<usize>),
+    RPop(Vec<u8>, Option<usize>),
+    LRange(Vec<u8>, i64, i64),
+    LLen(Vec<u8>),
+    LIndex(Vec<u8>, i64),
+    LPos(Vec<u8>, Vec<u8>, LPosOptions),
+    /// Move an element from the source list to the destination list,
+    /// popping from and pushing to the front of each if set.
+    LMove(Vec<u8>, Vec<u8>, bool, bool),
+    BLPop(Vec<Vec<u8>>, f64),
+    HSet(Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>),
+    HSetNx(Vec<u8>, Vec<u8>, Vec<u8>),
+    HIncrBy(Vec<u8>, Vec<u8>, i64),
+    HIncrByFloat(Vec<u8>, Vec<u8>, f64),
+    HMGet(Vec<u8>, Vec<Vec<u8>>),
+    HExists(Vec<u8>, Vec<u8>),
+    HDel(Vec<u8>, Vec<Vec<u8>>),
+    HGet(Vec<u8>, Vec<u8>),
+    HGetAll(Vec<u8>),
+    HKeys(Vec<u8>),
+    HVals(Vec<u8>),
+    HLen(Vec<u8>),
+    SAdd(Vec<u8>, Vec<Vec<u8>>),
+    SRem(Vec<u8>, Vec<Vec<u8>>),
+    SMembers(Vec<u8>),
+    SIsMember(Vec<u8>, Vec<u8>),
+    SCard(Vec<u8>),
+    SUnion(Vec<Vec<u8>>),
+    SInter(Vec<Vec<u8>>),
+    SDiff(Vec<Vec<u8>>),
+    ZAdd(Vec<u8>, ZAddOptions, ScoredMembers),
+    ZRange(Vec<u8>, i64, i64, ZRangeOptions),
+    ZRangeByScore(Vec<u8>, (f64, bool), (f64, bool), ZRangeByScoreOptions),
+    ZCount(Vec<u8>, (f64, bool), (f64, bool)),
+    ZScore(Vec<u8>, Vec<u8>),
+    /// Get the rank of a member, counting from the highest score if set.
+    ZRank(Vec<u8>, Vec<u8>, bool),
+    ZCard(Vec<u8>),
+    ZRem(Vec<u8>, Vec<Vec<u8>>),
+    BgSave,
+    LastSave,
+    Select(i64),
+    FlushDb,
+    FlushAll,
+    Copy(Vec<u8>, Vec<u8>, CopyOptions),
+    RandomKey,
+    Hello(Option<i64>, Option<String>),
+    Client(ClientSubcommand),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Vec<u8>>),
+    Unwatch,
+    Subscribe(Vec<Vec<u8>>),
+    Unsubscribe(Vec<Vec<u8>>),
+    Publish(Vec<u8>, Vec<u8>),
+    PSubscribe(Vec<Vec<u8>>),
+    PUnsubscribe(Vec<Vec<u8>>),
+    Info(Option<String>),
+    Commands(CommandSubcommand),
+    Debug(DebugSubcommand),
+    Object(ObjectSubcommand),
+    /// A key and how many elements of its value to sample, or `None` for
+    /// all of them.
+    MemoryUsage(Vec<u8>, Option<usize>),
+    SlowLog(SlowLogSubcommand),
+    Time,
+    Wait(usize, u64),
+    ReplConf(Vec<(String, String)>),
+    PSync,
+    Monitor,
+    /// Replicate the master at a host and port, or stop replicating if
+    /// `None`.
+    ReplicaOf(Option<(String, u16)>),
+    Get(Vec<u8>),
+    Del(Vec<Vec<u8>>),
+    Unlink(Vec<Vec<u8>>),
+    Exists(Vec<Vec<u8>>),
+    Touch(Vec<Vec<u8>>),
+    Incr(Vec<u8>),
+    Decr(Vec<u8>),
+    IncrBy(Vec<u8>, i64),
+    IncrByFloat(Vec<u8>, f64),
+    DecrBy(Vec<u8>, i64),
+    Append(Vec<u8>, Vec<u8>),
+    GetRange(Vec<u8>, i64, i64),
+    /// The name `GETRANGE` had before Redis 2.0.
+    Substr(Vec<u8>, i64, i64),
+    SetRange(Vec<u8>, usize, Vec<u8>),
+    StrLen(Vec<u8>),
+    ConfigGet(Vec<String>),
+    ConfigSet(String, String),
+}
+
+#[derive(Debug)]
+enum RedisObject {
+    BulkString(Vec<u8>),
+    Array(Vec<RedisObject>),
+}
+
+/// Parses the commands a client sends out of the bytes received from it. It
+/// keeps its place between reads, so a command that arrives in pieces isn't
+/// parsed again from the start every time more of it comes in.
+#[derive(Default)]
+struct RESPParser {
+    /// The arguments of the command parsed so far.
+    args: Vec<RedisObject>,
+    /// How many more arguments that command has, or `None` before its
+    /// length has been read.
+    remaining: Option<usize>,
+    /// The length of the bulk string being received, once it's been read.
+    bulk_len: Option<usize>,
+    /// How much of the stream has been parsed.
+    pos: usize,
+}
+
+impl RESPParser {
+    /// Parses the next command in the stream. Returns `None` if the stream
+    /// doesn't hold a complete command yet, otherwise the command along with
+    /// the number of bytes it takes up. Until a command is returned, each
+    /// call must be given the same stream with any bytes received since
+    /// appended. A stream that doesn't start with `*` holds an inline
+    /// command, as typed into `telnet`.
+    fn parse(&mut self, stream: &[u8]) -> Result<Option<(RedisObject, usize)>, ProtocolError> {
+        let parsed = match stream.first() {
+            None => return Ok(None),
+            Some(b'*') => self.parse_multibulk(stream)?,
+            Some(_) => self.parse_inline(stream)?,
+        };
+        Ok(parsed.map(|object| {
+            let consumed = self.pos;
+            *self = Self::default();
+            (object, consumed)
+        }))
+    }
+
+    /// Parses a line of space-separated arguments, which may be quoted, into
+    /// an array of bulk strings. The line ends at LF, with or without a CR
+    /// before it.
+    fn parse_inline(&mut self, stream: &[u8]) -> Result<Option<RedisObject>, ProtocolError> {
+        let Some(end) = stream.iter().position(|&b| b == b'\n') else {
+            return if stream.len() > INLINE_MAX_LEN {
+                Err(ProtocolError::InlineTooBig)
+            } else {
+                Ok(None)
+            };
+        };
+        let line = stream[..end].strip_suffix(b"\r").unwrap_or(&stream[..end]);
+        let args = split_inline_args(line).ok_or(ProtocolError::UnbalancedQuotes)?;
+        self.pos = end + 1;
+        Ok(Some(RedisObject::Array(
+            args.into_iter().map(RedisObject::BulkString).collect(),
+        )))
+    }
+
+    /// Parses an array of bulk strings, the only kind of array commands are
+    /// sent as, like in Redis.
+    fn parse_multibulk(&mut self, stream: &[u8]) -> Result<Option<RedisObject>, ProtocolError> {
+        if self.remaining.is_none() {
+            let Some((count, header_len)) = self.header(stream) else {
+                return if stream.len() > INLINE_MAX_LEN {
+                    Err(ProtocolError::MultibulkCountTooBig)
+                } else {
+                    Ok(None)
+                };
+            };
+            let count = count
+                .filter(|&count| count <= MULTIBULK_MAX_LEN as i64)
+                .ok_or(ProtocolError::InvalidMultibulkLength)?;
+            // Like in Redis, a count of zero or less is an empty command.
+            let count = count.max(0) as usize;
+            self.pos = header_len;
+            self.remaining = Some(count);
+            self.args = Vec::with_capacity(count.min(1024));
+        }
+        while let Some(remaining @ 1..) = self.remaining {
+            let len = match self.bulk_len {
+                Some(len) => len,
+                None => {
+                    match stream.get(self.pos) {
+                        None => return Ok(None),
+                        Some(b'$') => {}
+                        Some(&other) => return Err(ProtocolError::ExpectedBulk(printable(other))),
+                    }
+                    let Some((len, header_len)) = self.header(stream) else {
+                        return if stream.len() - self.pos > INLINE_MAX_LEN {
+                            Err(ProtocolError::BulkCountTooBig)
+                        } else {
+                            Ok(None)
+                        };
+                    };
+                    let len = len
+                        .filter(|len| (0..=STRING_MAX_LEN as i64).contains(len))
+                        .ok_or(ProtocolError::InvalidBulkLength)?
+                        as usize;
+                    self.pos += header_len;
+                    self.bulk_len = Some(len);
+                    len
+                }
+            };
+            // Wait for the whole string, and the CRLF after it, which is
+            // skipped without being checked, like in Redis.
+            if stream.len() - self.pos < len + 2 {
+                return Ok(None);
+            }
+            let arg = stream[self.pos..self.pos + len].to_vec();
+            self.args.push(RedisObject::BulkString(arg));
+            self.pos += len + 2;
+            self.bulk_len = None;
+            self.remaining = Some(remaining - 1);
+        }
+        Ok(Some(RedisObject::Array(std::mem::take(&mut self.args))))
+    }
+
+    /// Reads the length line at the parsed part of the stream, a type marker
+    /// followed by a number. Returns `None` if the line isn't complete yet,
+    /// otherwise the number, if it is one, along with the length of the line.
+    fn header(&self, stream: &[u8]) -> Option<(Option<i64>, usize)> {
+        let (header, _) = split_by_line(&stream[self.pos + 1..])?;
+        let len = std::str::from_utf8(header)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok());
+        Some((len, header.len() + 3))
+    }
+}
+
+/// Shows a byte in a protocol error, which has to fit on one line.
+fn printable(byte: u8) -> char {
+    match byte {
+        b'\r' | b'\n' => ' ',
+        byte => byte as char,
+    }
+}
+
+/// Splits off the first CRLF-terminated line of `stream`, returning the line
+/// without its terminator and everything after it. Returns `None` if the
+/// stream doesn't hold a complete line yet.
+fn split_by_line(stream: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = stream.windows(2).position(|w| w == b"\r\n")?;
+    Some((&stream[..end], &stream[end + 2..]))
+}
+
+/// Splits an inline command into its arguments the way Redis does. Arguments
+/// are separated by whitespace, and can be put in double quotes, which allow
+/// escapes like `\n` and `\x41`, or in single quotes, which only allow `\'`.
+/// Returns `None` if a quote isn't closed or is followed by more of the
+/// argument.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut rest = line;
+    loop {
+        rest = rest.trim_ascii_start();
+        let Some(&first) = rest.first() else {
+            return Some(args);
+        };
+        let mut arg = Vec::new();
+        match first {
+            b'"' => {
+                rest = &rest[1..];
+                loop {
+                    match *rest {
+                        [b'\\', b'x', high, low, ref tail @ ..]
+                            if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() =>
+                        {
+                            let hex = [high, low];
+                            let hex = std::str::from_utf8(&hex).unwrap();
+                            arg.push(u8::from_str_radix(hex, 16).unwrap());
+                            rest = tail;
+                        }
+                        [b'\\', escaped, ref tail @ ..] => {
+                            arg.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => other,
+                            });
+                            rest = tail;
+                        }
+                        [b'"', ref tail @ ..] => {
+                            rest = tail;
+                            break;
+                        }
+                        [byte, ref tail @ ..] => {
+                            arg.push(byte);
+                            rest = tail;
+                        }
+                        [] => return None,
+                    }
+                }
+            }
+            b'\'' => {
+                rest = &rest[1..];
+                loop {
+                    match *rest {
+                        [b'\\', b'\'', ref tail @ ..] => {
+                            arg.push(b'\'');
+                            rest = tail;
+                        }
+                        [b'\'', ref tail @ ..] => {
+                            rest = tail;
+                            break;
+                        }
+                        [byte, ref tail @ ..] => {
+                            arg.push(byte);
+                            rest = tail;
+                        }
+                        [] => return None,
+                    }
+                }
+            }
+            _ => {
+                let len = rest
+                    .iter()
+                    .position(|b| b.is_ascii_whitespace())
+                    .unwrap_or(rest.len());
+                arg.extend_from_slice(&rest[..len]);
+                rest = &rest[len..];
+            }
+        }
+        // A closing quote must end the argument.
+        if rest.first().is_some_and(|b| !b.is_ascii_whitespace()) {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+fn bulk_strings(objects: &[RedisObject]) -> Result<Vec<String>, CommandError> {
+    objects
+        .iter()
+        .map(|o| match o {
+            RedisObject::BulkString(s) => bulk_string(s),
+            _ => Err(CommandError::Invalid),
+        })
+        .collect()
+}
+
+/// Reads a bulk string argument that must be text, such as an option name.
+fn bulk_string(bytes: &[u8]) -> Result<String, CommandError> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| CommandError::Invalid)
+}
+
+fn bulk_bytes(objects: &[RedisObject]) -> Result<Vec<Vec<u8>>, CommandError> {
+    objects
+        .iter()
+        .map(|o| match o {
+            RedisObject::BulkString(s) => Ok(s.clone()),
+            _ => Err(CommandError::Invalid),
+        })
+        .collect()
+}
+
+/// Checks the optional `ASYNC` or `SYNC` argument of `FLUSHDB` and
+/// `FLUSHALL`. Both modes flush synchronously.
+fn parse_flush_mode(args: &[RedisObject]) -> Result<(), CommandError> {
+    match args {
+        [] => Ok(()),
+        [RedisObject::BulkString(mode)]
+            if mode.eq_ignore_ascii_case(b"ASYNC") || mode.eq_ignore_ascii_case(b"SYNC") =>
+        {
+            Ok(())
+        }
+        _ => Err(CommandError::Syntax),
+    }
+}
+
+fn parse_integer(s: &[u8]) -> Result<i64, CommandError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or(CommandError::NotInteger)
+}
+
+fn parse_float(s: &[u8]) -> Result<f64, CommandError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|n| !n.is_nan())
+        .ok_or(CommandError::NotFloat)
+}
+
+fn parse_set_options(objects: &[RedisObject]) -> Result<SetOptions, CommandError> {
+    let options = bulk_strings(objects)?;
+    let mut options = options.iter();
+    let mut result = SetOptions::default();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            unit @ ("PX" | "EX") if result.expiry.is_none() && !result.keep_ttl => {
+                let duration =
+                    parse_integer(options.next().ok_or(CommandError::Syntax)?.as_bytes())?;
+                if duration <= 0 {
+                    return Err(CommandError::InvalidExpireTime("set"));
+                }
+                let millis = if unit == "EX" {
+                    duration
+                        .checked_mul(1000)
+                        .ok_or(CommandError::InvalidExpireTime("set"))?
+                } else {
+                    duration
+                };
+                result.expiry = Some(millis as u64);
+            }
+            "NX" if result.condition.is_none() => result.condition = Some(SetCondition::Nx),
+            "XX" if result.condition.is_none() => result.condition = Some(SetCondition::Xx),
+            "GET" => result.get = true,
+            "KEEPTTL" if result.expiry.is_none() => result.keep_ttl = true,
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(result)
+}
+
+/// Parses the option of `GETEX` that changes the expiry of the key, if one
+/// was given.
+fn parse_getex_expiry(objects: &[RedisObject]) -> Result<Option<GetExExpiry>, CommandError> {
+    let options = bulk_strings(objects)?;
+    let (option, time) = match options.as_slice() {
+        [] => return Ok(None),
+        [option] if option.eq_ignore_ascii_case("PERSIST") => {
+            return Ok(Some(GetExExpiry::Persist));
+        }
+        [option, time] => (option.to_uppercase(), time),
+        _ => return Err(CommandError::Syntax),
+    };
+    let (absolute, unit) = match option.as_str() {
+        "EX" => (false, 1000),
+        "PX" => (false, 1),
+        "EXAT" => (true, 1000),
+        "PXAT" => (true, 1),
+        _ => return Err(CommandError::Syntax),
+    };
+    let millis = parse_integer(time.as_bytes())?
+        .checked_mul(unit)
+        .filter(|&millis| millis > 0)
+        .ok_or(CommandError::InvalidExpireTime("getex"))?;
+    Ok(Some(if absolute {
+        GetExExpiry::At(millis)
+    } else {
+        GetExExpiry::In(millis)
+    }))
+}
+
+/// Parses the arguments of `HELLO`, returning the requested protocol version
+/// and the client name to set. There is no authentication, so `AUTH` is
+/// accepted but has no effect.
+fn parse_hello_args(
+    objects: &[RedisObject],
+) -> Result<(Option<i64>, Option<String>), CommandError> {
+    let args = bulk_strings(objects)?;
+    let Some((version, options)) = args.split_first() else {
+        return Ok((None, None));
+    };
+    let version = version
+        .parse::<i64>()
+        .map_err(|_| CommandError::InvalidProtocolVersion)?;
+    let mut name = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "AUTH" => {
+                options.next().ok_or(CommandError::Syntax)?;
+                options.next().ok_or(CommandError::Syntax)?;
+            }
+            "SETNAME" => {
+                let value = options.next().ok_or(CommandError::Syntax)?;
+                name = Some(parse_client_name(value)?);
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok((Some(version), name))
+}
+
+fn parse_kill_filter(args: &[String]) -> Result<KillFilter, CommandError> {
+    if let [addr] = args {
+        return Ok(KillFilter {
+            addr: Some(addr.clone()),
+            legacy: true,
+            ..Default::default()
+        });
+    }
+    let mut result = KillFilter {
+        skip_me: true,
+        ..Default::default()
+    };
+    let mut args = args.iter();
+    while let Some(filter) = args.next() {
+        let value = args.next().ok_or(CommandError::Syntax)?;
+        match filter.to_uppercase().as_str() {
+            "ID" => result.id = Some(value.parse().map_err(|_| CommandError::NotInteger)?),
+            "ADDR" => result.addr = Some(value.clone()),
+            "SKIPME" => {
+                result.skip_me = match value.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(CommandError::Syntax),
+                }
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    if result.id.is_none() && result.addr.is_none() {
+        return Err(CommandError::Syntax);
+    }
+    Ok(result)
+}
+
+/// Checks a name given to `CLIENT SETNAME`, which can't contain anything that
+/// would break up a line of `CLIENT LIST`.
+fn parse_client_name(name: &str) -> Result<String, CommandError> {
+    if name.bytes().all(|b| (b'!'..=b'~').contains(&b)) {
+        Ok(name.to_string())
+    } else {
+        Err(CommandError::InvalidClientName)
+    }
+}
+
+fn parse_copy_options(objects: &[RedisObject]) -> Result<CopyOptions, CommandError> {
+    let options = bulk_strings(objects)?;
+    let mut options = options.iter();
+    let mut result = CopyOptions::default();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "DB" => {
+                let index = parse_integer(options.next().ok_or(CommandError::Syntax)?.as_bytes())?;
+                match usize::try_from(index) {
+                    Ok(index) if index < DB_COUNT => result.db = Some(index),
+                    _ => return Err(CommandError::InvalidDbIndex),
+                }
+            }
+            "REPLACE" => result.replace = true,
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(result)
+}
+
+/// Parses the options of `ZADD` and the score and member pairs that follow
+/// them.
+fn parse_zadd(objects: &[RedisObject]) -> Result<(ZAddOptions, ScoredMembers), CommandError> {
+    let args = bulk_bytes(objects)?;
+    let mut args = args.as_slice();
+    let mut options = ZAddOptions::default();
+    while let Some((option, rest)) = args.split_first() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => options.nx = true,
+            b"XX" => options.xx = true,
+            b"GT" => options.gt = true,
+            b"LT" => options.lt = true,
+            b"CH" => options.ch = true,
+            b"INCR" => options.incr = true,
+            _ => break,
+        }
+        args = rest;
+    }
+    if args.is_empty() || args.len() % 2 != 0 {
+        return Err(CommandError::Syntax);
+    }
+    if options.nx && options.xx {
+        return Err(CommandError::ZAddNxAndXx);
+    }
+    if [options.nx, options.gt, options.lt]
+        .iter()
+        .filter(|&&o| o)
+        .count()
+        > 1
+    {
+        return Err(CommandError::ZAddGtLtNx);
+    }
+    if options.incr && args.len() > 2 {
+        return Err(CommandError::ZAddIncrPairs);
+    }
+    let pairs = args
+        .chunks(2)
+        .map(|pair| Ok((parse_float(&pair[0])?, pair[1].clone())))
+        .collect::<Result<_, CommandError>>()?;
+    Ok((options, pairs))
+}
+
+/// Parses the `LEFT` or `RIGHT` argument of `LMOVE`, returning whether it's
+/// the front of the list.
+fn parse_list_end(s: &[u8]) -> Result<bool, CommandError> {
+    if s.eq_ignore_ascii_case(b"LEFT") {
+        Ok(true)
+    } else if s.eq_ignore_ascii_case(b"RIGHT") {
+        Ok(false)
+    } else {
+        Err(CommandError::Syntax)
+    }
+}
+
+fn parse_lpos_options(objects: &[RedisObject]) -> Result<LPosOptions, CommandError> {
+    let options = bulk_strings(objects)?;
+    let mut options = options.iter();
+    let mut result = LPosOptions {
+        rank: 1,
+        count: None,
+        max_len: 0,
+    };
+    while let Some(option) = options.next() {
+        let value = parse_integer(options.next().ok_or(CommandError::Syntax)?.as_bytes())?;
+        match option.to_uppercase().as_str() {
+            "RANK" if value == 0 => return Err(CommandError::LPosZeroRank),
+            "RANK" => result.rank = value,
+            "COUNT" => {
+                result.count =
+                    Some(usize::try_from(value).map_err(|_| CommandError::Negative("COUNT"))?)
+            }
+            "MAXLEN" => {
+                result.max_len =
+                    usize::try_from(value).map_err(|_| CommandError::Negative("MAXLEN"))?
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(result)
+}
+
+fn parse_zrange_options(objects: &[RedisObject]) -> Result<ZRangeOptions, CommandError> {
+    let mut result = ZRangeOptions::default();
+    for option in bulk_strings(objects)? {
+        match option.to_uppercase().as_str() {
+            "REV" => result.rev = true,
+            "WITHSCORES" => result.with_scores = true,
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(result)
+}
+
+fn parse_zrangebyscore_options(
+    objects: &[RedisObject],
+) -> Result<ZRangeByScoreOptions, CommandError> {
+    let options = bulk_strings(objects)?;
+    let mut options = options.iter();
+    let mut result = ZRangeByScoreOptions::default();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "WITHSCORES" => result.with_scores = true,
+            "LIMIT" => {
+                let mut next = || options.next().ok_or(CommandError::Syntax);
+                let offset = next()?;
+                let count = next()?;
+                result.limit = Some((
+                    parse_integer(offset.as_bytes())?,
+                    parse_integer(count.as_bytes())?,
+                ));
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(result)
+}
+
+/// Parses a bound of a score range: a score, or `-inf` or `+inf`, which
+/// excludes members with exactly that score if it starts with `(`. Returns
+/// the score and whether the bound is inclusive.
+fn parse_score_bound(s: &[u8]) -> Result<(f64, bool), CommandError> {
+    let (s, inclusive) = match s.strip_prefix(b"(") {
+        Some(s) => (s, false),
+        None => (s, true),
+    };
+    let score = parse_float(s).map_err(|_| CommandError::MinMaxNotFloat)?;
+    Ok((score, inclusive))
+}
+
+/// Parses the optional trailing count argument of commands such as `LPOP`.
+fn parse_count(objects: &[RedisObject]) -> Result<Option<usize>, CommandError> {
+    match objects {
+        [] => Ok(None),
+        [RedisObject::BulkString(count)] => std::str::from_utf8(count)
+            .ok()
+            .and_then(|count| count.parse::<usize>().ok())
+            .map(Some)
+            .ok_or(CommandError::NotInteger),
+        _ => Err(CommandError::Invalid),
+    }
+}
 
 impl Command {
-    fn from_buffer(buf: &[u8]) -> Result<Self, ()> {
-        let mut p = RESPParser::new(buf);
-        match p.parse() {
-            Ok(object) => match object {
-                RedisObject::Array(arr) => match arr.as_slice() {
-                    [RedisObject::BulkString(4, s)] => {
-                        if s.to_uppercase() == "PING".to_string() {
-                            Ok(Command::Ping)
-                        } else {
-                            Err(())
-                        }
+    fn from_object(object: RedisObject) -> Result<Self, CommandError> {
+        match object {
+            RedisObject::Array(arr) => match arr.as_slice() {
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"DEL") && !keys.is_empty() =>
+                {
+                    Ok(Command::Del(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"UNLINK") && !keys.is_empty() =>
+                {
+                    Ok(Command::Unlink(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"EXISTS") && !keys.is_empty() =>
+                {
+                    Ok(Command::Exists(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"TOUCH") && !keys.is_empty() =>
+                {
+                    Ok(Command::Touch(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"INCR") =>
+                {
+                    Ok(Command::Incr(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"DECR") =>
+                {
+                    Ok(Command::Decr(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(amount)]
+                    if s.eq_ignore_ascii_case(b"INCRBY") =>
+                {
+                    Ok(Command::IncrBy(key.clone(), parse_integer(amount)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(amount)]
+                    if s.eq_ignore_ascii_case(b"INCRBYFLOAT") =>
+                {
+                    Ok(Command::IncrByFloat(key.clone(), parse_float(amount)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(amount)]
+                    if s.eq_ignore_ascii_case(b"DECRBY") =>
+                {
+                    Ok(Command::DecrBy(key.clone(), parse_integer(amount)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"APPEND") =>
+                {
+                    Ok(Command::Append(key.clone(), value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(start), RedisObject::BulkString(end)]
+                    if s.eq_ignore_ascii_case(b"GETRANGE") =>
+                {
+                    Ok(Command::GetRange(
+                        key.clone(),
+                        parse_integer(start)?,
+                        parse_integer(end)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(start), RedisObject::BulkString(end)]
+                    if s.eq_ignore_ascii_case(b"SUBSTR") =>
+                {
+                    Ok(Command::Substr(
+                        key.clone(),
+                        parse_integer(start)?,
+                        parse_integer(end)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(offset), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"SETRANGE") =>
+                {
+                    let offset = usize::try_from(parse_integer(offset)?)
+                        .map_err(|_| CommandError::OffsetOutOfRange)?;
+                    Ok(Command::SetRange(key.clone(), offset, value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"STRLEN") =>
+                {
+                    Ok(Command::StrLen(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"SETNX") =>
+                {
+                    Ok(Command::SetNx(key.clone(), value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(seconds), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"SETEX") =>
+                {
+                    let millis = parse_integer(seconds)?
+                        .checked_mul(1000)
+                        .and_then(|millis| u64::try_from(millis).ok())
+                        .filter(|&millis| millis > 0)
+                        .ok_or(CommandError::InvalidExpireTime("setex"))?;
+                    Ok(Command::SetEx(key.clone(), millis, value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(millis), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"PSETEX") =>
+                {
+                    let millis = u64::try_from(parse_integer(millis)?)
+                        .ok()
+                        .filter(|&millis| millis > 0)
+                        .ok_or(CommandError::InvalidExpireTime("psetex"))?;
+                    Ok(Command::PSetEx(key.clone(), millis, value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"GETSET") =>
+                {
+                    Ok(Command::GetSet(key.clone(), value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"GETDEL") =>
+                {
+                    Ok(Command::GetDel(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), options @ ..]
+                    if s.eq_ignore_ascii_case(b"GETEX") =>
+                {
+                    Ok(Command::GetEx(key.clone(), parse_getex_expiry(options)?))
+                }
+                [RedisObject::BulkString(s), pairs @ ..] if s.eq_ignore_ascii_case(b"MSET") => {
+                    if pairs.is_empty() || pairs.len() % 2 != 0 {
+                        return Err(CommandError::WrongArity("mset"));
                     }
-                    [RedisObject::BulkString(4, s), RedisObject::BulkString(_, o)] => {
-                        if s.to_uppercase() == "ECHO".to_string() {
-                            Ok(Command::Echo(o.to_string()))
-                        } else {
-                            Err(())
-                        }
+                    let pairs = bulk_bytes(pairs)?;
+                    Ok(Command::MSet(
+                        pairs
+                            .chunks(2)
+                            .map(|pair| (pair[0].clone(), pair[1].clone()))
+                            .collect(),
+                    ))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"MGET") && !keys.is_empty() =>
+                {
+                    Ok(Command::MGet(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"TYPE") =>
+                {
+                    Ok(Command::Type(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"TTL") =>
+                {
+                    Ok(Command::Ttl(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"PTTL") =>
+                {
+                    Ok(Command::PTtl(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"PERSIST") =>
+                {
+                    Ok(Command::Persist(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(seconds)]
+                    if s.eq_ignore_ascii_case(b"EXPIRE") =>
+                {
+                    Ok(Command::Expire(key.clone(), parse_integer(seconds)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(millis)]
+                    if s.eq_ignore_ascii_case(b"PEXPIRE") =>
+                {
+                    Ok(Command::PExpire(key.clone(), parse_integer(millis)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(timestamp)]
+                    if s.eq_ignore_ascii_case(b"EXPIREAT") =>
+                {
+                    Ok(Command::ExpireAt(key.clone(), parse_integer(timestamp)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(timestamp)]
+                    if s.eq_ignore_ascii_case(b"PEXPIREAT") =>
+                {
+                    Ok(Command::PExpireAt(key.clone(), parse_integer(timestamp)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(pattern)]
+                    if s.eq_ignore_ascii_case(b"KEYS") =>
+                {
+                    Ok(Command::Keys(pattern.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), elements @ ..]
+                    if s.eq_ignore_ascii_case(b"LPUSH") && !elements.is_empty() =>
+                {
+                    Ok(Command::LPush(key.clone(), bulk_bytes(elements)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), elements @ ..]
+                    if s.eq_ignore_ascii_case(b"RPUSH") && !elements.is_empty() =>
+                {
+                    Ok(Command::RPush(key.clone(), bulk_bytes(elements)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), count @ ..]
+                    if s.eq_ignore_ascii_case(b"LPOP") && count.len() <= 1 =>
+                {
+                    Ok(Command::LPop(key.clone(), parse_count(count)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), count @ ..]
+                    if s.eq_ignore_ascii_case(b"RPOP") && count.len() <= 1 =>
+                {
+                    Ok(Command::RPop(key.clone(), parse_count(count)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(start), RedisObject::BulkString(stop)]
+                    if s.eq_ignore_ascii_case(b"LRANGE") =>
+                {
+                    Ok(Command::LRange(
+                        key.clone(),
+                        parse_integer(start)?,
+                        parse_integer(stop)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"LLEN") =>
+                {
+                    Ok(Command::LLen(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(index)]
+                    if s.eq_ignore_ascii_case(b"LINDEX") =>
+                {
+                    Ok(Command::LIndex(key.clone(), parse_integer(index)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(source), RedisObject::BulkString(destination), RedisObject::BulkString(from), RedisObject::BulkString(to)]
+                    if s.eq_ignore_ascii_case(b"LMOVE") =>
+                {
+                    Ok(Command::LMove(
+                        source.clone(),
+                        destination.clone(),
+                        parse_list_end(from)?,
+                        parse_list_end(to)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(source), RedisObject::BulkString(destination)]
+                    if s.eq_ignore_ascii_case(b"RPOPLPUSH") =>
+                {
+                    Ok(Command::LMove(
+                        source.clone(),
+                        destination.clone(),
+                        false,
+                        true,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(element), options @ ..]
+                    if s.eq_ignore_ascii_case(b"LPOS") =>
+                {
+                    Ok(Command::LPos(
+                        key.clone(),
+                        element.clone(),
+                        parse_lpos_options(options)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), args @ ..]
+                    if s.eq_ignore_ascii_case(b"BLPOP") && args.len() >= 2 =>
+                {
+                    let mut args = bulk_bytes(args)?;
+                    let timeout = bulk_string(&args.pop().unwrap())
+                        .ok()
+                        .and_then(|t| t.parse::<f64>().ok())
+                        .filter(|t| t.is_finite())
+                        .ok_or(CommandError::InvalidTimeout)?;
+                    if timeout < 0.0 {
+                        return Err(CommandError::NegativeTimeout);
                     }
-                    [RedisObject::BulkString(3, s), RedisObject::BulkString(_, key)] => {
-                        if s.to_uppercase() == "GET".to_string() {
-                            Ok(Command::Get(key.to_string()))
-                        } else {
-                            Err(())
-                        }
+                    Ok(Command::BLPop(args, timeout))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(field), RedisObject::BulkString(delta)]
+                    if s.eq_ignore_ascii_case(b"HINCRBYFLOAT") =>
+                {
+                    Ok(Command::HIncrByFloat(
+                        key.clone(),
+                        field.clone(),
+                        parse_float(delta)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), pairs @ ..]
+                    if s.eq_ignore_ascii_case(b"HSET") =>
+                {
+                    if pairs.is_empty() || pairs.len() % 2 != 0 {
+                        return Err(CommandError::WrongArity("hset"));
                     }
-                    [RedisObject::BulkString(3, s), RedisObject::BulkString(_, key), RedisObject::BulkString(_, value), RedisObject::BulkString(2, ex), RedisObject::BulkString(_, duration)] => {
-                        if s.to_uppercase() == "SET".to_string()
-                            && ex.to_uppercase() == "PX"
-                            && duration.parse::<u64>().is_ok()
-                        {
-                            Ok(Command::Set(
-                                key.to_string(),
-                                value.as_bytes().to_vec(),
-                                Some(duration.parse::<u64>().unwrap()),
-                            ))
-                        } else {
-                            Err(())
-                        }
+                    let pairs = bulk_bytes(pairs)?;
+                    Ok(Command::HSet(
+                        key.clone(),
+                        pairs
+                            .chunks(2)
+                            .map(|pair| (pair[0].clone(), pair[1].clone()))
+                            .collect(),
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(field)]
+                    if s.eq_ignore_ascii_case(b"HGET") =>
+                {
+                    Ok(Command::HGet(key.clone(), field.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(field), RedisObject::BulkString(value)]
+                    if s.eq_ignore_ascii_case(b"HSETNX") =>
+                {
+                    Ok(Command::HSetNx(key.clone(), field.clone(), value.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(field), RedisObject::BulkString(delta)]
+                    if s.eq_ignore_ascii_case(b"HINCRBY") =>
+                {
+                    Ok(Command::HIncrBy(
+                        key.clone(),
+                        field.clone(),
+                        parse_integer(delta)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), fields @ ..]
+                    if s.eq_ignore_ascii_case(b"HMGET") && !fields.is_empty() =>
+                {
+                    Ok(Command::HMGet(key.clone(), bulk_bytes(fields)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(field)]
+                    if s.eq_ignore_ascii_case(b"HEXISTS") =>
+                {
+                    Ok(Command::HExists(key.clone(), field.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), fields @ ..]
+                    if s.eq_ignore_ascii_case(b"HDEL") && !fields.is_empty() =>
+                {
+                    Ok(Command::HDel(key.clone(), bulk_bytes(fields)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"HGETALL") =>
+                {
+                    Ok(Command::HGetAll(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"HKEYS") =>
+                {
+                    Ok(Command::HKeys(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"HVALS") =>
+                {
+                    Ok(Command::HVals(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"HLEN") =>
+                {
+                    Ok(Command::HLen(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), members @ ..]
+                    if s.eq_ignore_ascii_case(b"SADD") && !members.is_empty() =>
+                {
+                    Ok(Command::SAdd(key.clone(), bulk_bytes(members)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), members @ ..]
+                    if s.eq_ignore_ascii_case(b"SREM") && !members.is_empty() =>
+                {
+                    Ok(Command::SRem(key.clone(), bulk_bytes(members)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"SMEMBERS") =>
+                {
+                    Ok(Command::SMembers(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(member)]
+                    if s.eq_ignore_ascii_case(b"SISMEMBER") =>
+                {
+                    Ok(Command::SIsMember(key.clone(), member.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"SCARD") =>
+                {
+                    Ok(Command::SCard(key.clone()))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"SUNION") && !keys.is_empty() =>
+                {
+                    Ok(Command::SUnion(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"SINTER") && !keys.is_empty() =>
+                {
+                    Ok(Command::SInter(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"SDIFF") && !keys.is_empty() =>
+                {
+                    Ok(Command::SDiff(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), args @ ..]
+                    if s.eq_ignore_ascii_case(b"ZADD") =>
+                {
+                    if args.len() < 2 {
+                        return Err(CommandError::WrongArity("zadd"));
                     }
-                    [RedisObject::BulkString(3, s), RedisObject::BulkString(_, key), RedisObject::BulkString(_, value)] => {
-                        if s.to_uppercase() == "SET".to_string() {
-                            Ok(Command::Set(
-                                key.to_string(),
-                                value.as_bytes().to_vec(),
-                                None,
-                            ))
-                        } else {
-                            Err(())
+                    let (options, pairs) = parse_zadd(args)?;
+                    Ok(Command::ZAdd(key.clone(), options, pairs))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(start), RedisObject::BulkString(stop), options @ ..]
+                    if s.eq_ignore_ascii_case(b"ZRANGE") =>
+                {
+                    Ok(Command::ZRange(
+                        key.clone(),
+                        parse_integer(start)?,
+                        parse_integer(stop)?,
+                        parse_zrange_options(options)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(min), RedisObject::BulkString(max), options @ ..]
+                    if s.eq_ignore_ascii_case(b"ZRANGEBYSCORE") =>
+                {
+                    Ok(Command::ZRangeByScore(
+                        key.clone(),
+                        parse_score_bound(min)?,
+                        parse_score_bound(max)?,
+                        parse_zrangebyscore_options(options)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(min), RedisObject::BulkString(max)]
+                    if s.eq_ignore_ascii_case(b"ZCOUNT") =>
+                {
+                    Ok(Command::ZCount(
+                        key.clone(),
+                        parse_score_bound(min)?,
+                        parse_score_bound(max)?,
+                    ))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(member)]
+                    if s.eq_ignore_ascii_case(b"ZSCORE") =>
+                {
+                    Ok(Command::ZScore(key.clone(), member.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(member)]
+                    if s.eq_ignore_ascii_case(b"ZRANK") =>
+                {
+                    Ok(Command::ZRank(key.clone(), member.clone(), false))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(member)]
+                    if s.eq_ignore_ascii_case(b"ZREVRANK") =>
+                {
+                    Ok(Command::ZRank(key.clone(), member.clone(), true))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"ZCARD") =>
+                {
+                    Ok(Command::ZCard(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), members @ ..]
+                    if s.eq_ignore_ascii_case(b"ZREM") && !members.is_empty() =>
+                {
+                    Ok(Command::ZRem(key.clone(), bulk_bytes(members)?))
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"BGSAVE") => {
+                    Ok(Command::BgSave)
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"LASTSAVE") => {
+                    Ok(Command::LastSave)
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(replicas), RedisObject::BulkString(timeout)]
+                    if s.eq_ignore_ascii_case(b"WAIT") =>
+                {
+                    let replicas = usize::try_from(parse_integer(replicas)?)
+                        .map_err(|_| CommandError::NotInteger)?;
+                    let timeout = u64::try_from(parse_integer(timeout)?)
+                        .map_err(|_| CommandError::NegativeTimeout)?;
+                    Ok(Command::Wait(replicas, timeout))
+                }
+                [RedisObject::BulkString(s), options @ ..]
+                    if s.eq_ignore_ascii_case(b"REPLCONF") =>
+                {
+                    if options.len() % 2 != 0 {
+                        return Err(CommandError::Syntax);
+                    }
+                    let options = bulk_strings(options)?;
+                    Ok(Command::ReplConf(
+                        options
+                            .chunks(2)
+                            .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                            .collect(),
+                    ))
+                }
+                // Every sync is a full resync, so the replication id and
+                // offset the replica asks for don't matter.
+                [RedisObject::BulkString(s), RedisObject::BulkString(_), RedisObject::BulkString(_)]
+                    if s.eq_ignore_ascii_case(b"PSYNC") =>
+                {
+                    Ok(Command::PSync)
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"MONITOR") => {
+                    Ok(Command::Monitor)
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(host), RedisObject::BulkString(port)]
+                    if s.eq_ignore_ascii_case(b"REPLICAOF")
+                        || s.eq_ignore_ascii_case(b"SLAVEOF") =>
+                {
+                    if host.eq_ignore_ascii_case(b"NO") && port.eq_ignore_ascii_case(b"ONE") {
+                        return Ok(Command::ReplicaOf(None));
+                    }
+                    let port = u16::try_from(parse_integer(port)?)
+                        .map_err(|_| CommandError::NotInteger)?;
+                    Ok(Command::ReplicaOf(Some((bulk_string(host)?, port))))
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"TIME") => {
+                    Ok(Command::Time)
+                }
+                [RedisObject::BulkString(config), RedisObject::BulkString(s), RedisObject::BulkString(name), RedisObject::BulkString(value)]
+                    if config.eq_ignore_ascii_case(b"CONFIG") && s.eq_ignore_ascii_case(b"SET") =>
+                {
+                    Ok(Command::ConfigSet(bulk_string(name)?, bulk_string(value)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(index)]
+                    if s.eq_ignore_ascii_case(b"SELECT") =>
+                {
+                    Ok(Command::Select(parse_integer(index)?))
+                }
+                [RedisObject::BulkString(s), mode @ ..] if s.eq_ignore_ascii_case(b"FLUSHDB") => {
+                    parse_flush_mode(mode)?;
+                    Ok(Command::FlushDb)
+                }
+                [RedisObject::BulkString(s), mode @ ..] if s.eq_ignore_ascii_case(b"FLUSHALL") => {
+                    parse_flush_mode(mode)?;
+                    Ok(Command::FlushAll)
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(source), RedisObject::BulkString(destination), options @ ..]
+                    if s.eq_ignore_ascii_case(b"COPY") =>
+                {
+                    Ok(Command::Copy(
+                        source.clone(),
+                        destination.clone(),
+                        parse_copy_options(options)?,
+                    ))
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"RANDOMKEY") => {
+                    Ok(Command::RandomKey)
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"HELLO") => {
+                    let (version, name) = parse_hello_args(args)?;
+                    Ok(Command::Hello(version, name))
+                }
+                [RedisObject::BulkString(s), section @ ..]
+                    if s.eq_ignore_ascii_case(b"INFO") && section.len() <= 1 =>
+                {
+                    Ok(Command::Info(bulk_strings(section)?.pop()))
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"COMMAND") => {
+                    let args = bulk_strings(args)?;
+                    match args.split_first() {
+                        None => Ok(Command::Commands(CommandSubcommand::Info(vec![]))),
+                        Some((subcommand, names)) => match subcommand.to_uppercase().as_str() {
+                            "COUNT" if names.is_empty() => {
+                                Ok(Command::Commands(CommandSubcommand::Count))
+                            }
+                            "DOCS" => Ok(Command::Commands(CommandSubcommand::Docs)),
+                            "INFO" => {
+                                Ok(Command::Commands(CommandSubcommand::Info(names.to_vec())))
+                            }
+                            _ => Err(CommandError::Syntax),
+                        },
+                    }
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"CLIENT") => {
+                    let args = bulk_strings(args)?;
+                    let Some((subcommand, args)) = args.split_first() else {
+                        return Err(CommandError::WrongArity("client"));
+                    };
+                    let subcommand = match (subcommand.to_uppercase().as_str(), args) {
+                        ("SETNAME", [name]) => ClientSubcommand::SetName(parse_client_name(name)?),
+                        ("GETNAME", []) => ClientSubcommand::GetName,
+                        ("ID", []) => ClientSubcommand::Id,
+                        ("LIST", []) => ClientSubcommand::List,
+                        ("KILL", filter) => ClientSubcommand::Kill(parse_kill_filter(filter)?),
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    Ok(Command::Client(subcommand))
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"DEBUG") => {
+                    let args = bulk_bytes(args)?;
+                    let Some((subcommand, args)) = args.split_first() else {
+                        return Err(CommandError::WrongArity("debug"));
+                    };
+                    let subcommand = String::from_utf8_lossy(subcommand).to_uppercase();
+                    let subcommand = match (subcommand.as_str(), args) {
+                        ("SLEEP", [seconds]) => DebugSubcommand::Sleep(
+                            std::str::from_utf8(seconds)
+                                .ok()
+                                .and_then(|seconds| seconds.parse::<f64>().ok())
+                                .and_then(|seconds| time::Duration::try_from_secs_f64(seconds).ok())
+                                .ok_or(CommandError::NotFloat)?,
+                        ),
+                        ("JMAP", []) => DebugSubcommand::JMap,
+                        ("OBJECT", [key]) => DebugSubcommand::Object(key.clone()),
+                        ("SET-ACTIVE-EXPIRE", [enabled]) => {
+                            DebugSubcommand::SetActiveExpire(parse_integer(enabled)? != 0)
                         }
+                        ("RELOAD", []) => DebugSubcommand::Reload,
+                        ("QUICKLIST-PACKED-THRESHOLD", [_]) => {
+                            DebugSubcommand::QuicklistPackedThreshold
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    Ok(Command::Debug(subcommand))
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"OBJECT") => {
+                    let args = bulk_bytes(args)?;
+                    let Some((subcommand, args)) = args.split_first() else {
+                        return Err(CommandError::WrongArity("object"));
+                    };
+                    let subcommand = String::from_utf8_lossy(subcommand).to_uppercase();
+                    let subcommand = match (subcommand.as_str(), args) {
+                        ("ENCODING", [key]) => ObjectSubcommand::Encoding(key.clone()),
+                        ("REFCOUNT", [key]) => ObjectSubcommand::RefCount(key.clone()),
+                        ("IDLETIME", [key]) => ObjectSubcommand::IdleTime(key.clone()),
+                        ("FREQ", [key]) => ObjectSubcommand::Freq(key.clone()),
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    Ok(Command::Object(subcommand))
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"MEMORY") => {
+                    let args = bulk_bytes(args)?;
+                    let Some((subcommand, args)) = args.split_first() else {
+                        return Err(CommandError::WrongArity("memory"));
+                    };
+                    if !subcommand.eq_ignore_ascii_case(b"USAGE") {
+                        return Err(CommandError::Syntax);
                     }
-                    [RedisObject::BulkString(6, config), RedisObject::BulkString(3, s), RedisObject::BulkString(_, key)] => {
-                        if s.to_uppercase() == "GET".to_string()
-                            || config.to_uppercase() == "CONFIG"
-                        {
-                            Ok(Command::ConfigGet(key.to_string()))
-                        } else {
-                            Err(())
+                    match args {
+                        [key] => Ok(Command::MemoryUsage(key.clone(), None)),
+                        [key, option, count] if option.eq_ignore_ascii_case(b"SAMPLES") => {
+                            // 0 samples every element, like in Redis.
+                            let count = usize::try_from(parse_integer(count)?)
+                                .map_err(|_| CommandError::NotInteger)?;
+                            Ok(Command::MemoryUsage(
+                                key.clone(),
+                                Some(count).filter(|&count| count > 0),
+                            ))
                         }
+                        _ => Err(CommandError::Syntax),
                     }
-                    _ => Err(()),
-                },
-                _ => Err(()),
+                }
+                [RedisObject::BulkString(s), args @ ..] if s.eq_ignore_ascii_case(b"SLOWLOG") => {
+                    let args = bulk_strings(args)?;
+                    let Some((subcommand, args)) = args.split_first() else {
+                        return Err(CommandError::WrongArity("slowlog"));
+                    };
+                    let subcommand = match (subcommand.to_uppercase().as_str(), args) {
+                        ("GET", []) => SlowLogSubcommand::Get(Some(10)),
+                        ("GET", [count]) => match parse_integer(count.as_bytes())? {
+                            -1 => SlowLogSubcommand::Get(None),
+                            count => SlowLogSubcommand::Get(Some(
+                                usize::try_from(count).map_err(|_| CommandError::SlowLogCount)?,
+                            )),
+                        },
+                        ("LEN", []) => SlowLogSubcommand::Len,
+                        ("RESET", []) => SlowLogSubcommand::Reset,
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    Ok(Command::SlowLog(subcommand))
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"MULTI") => {
+                    Ok(Command::Multi)
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"EXEC") => {
+                    Ok(Command::Exec)
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"DISCARD") => {
+                    Ok(Command::Discard)
+                }
+                [RedisObject::BulkString(s), keys @ ..]
+                    if s.eq_ignore_ascii_case(b"WATCH") && !keys.is_empty() =>
+                {
+                    Ok(Command::Watch(bulk_bytes(keys)?))
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"UNWATCH") => {
+                    Ok(Command::Unwatch)
+                }
+                [RedisObject::BulkString(s), channels @ ..]
+                    if s.eq_ignore_ascii_case(b"SUBSCRIBE") && !channels.is_empty() =>
+                {
+                    Ok(Command::Subscribe(bulk_bytes(channels)?))
+                }
+                [RedisObject::BulkString(s), channels @ ..]
+                    if s.eq_ignore_ascii_case(b"UNSUBSCRIBE") =>
+                {
+                    Ok(Command::Unsubscribe(bulk_bytes(channels)?))
+                }
+                [RedisObject::BulkString(s), patterns @ ..]
+                    if s.eq_ignore_ascii_case(b"PSUBSCRIBE") && !patterns.is_empty() =>
+                {
+                    Ok(Command::PSubscribe(bulk_bytes(patterns)?))
+                }
+                [RedisObject::BulkString(s), patterns @ ..]
+                    if s.eq_ignore_ascii_case(b"PUNSUBSCRIBE") =>
+                {
+                    Ok(Command::PUnsubscribe(bulk_bytes(patterns)?))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(channel), RedisObject::BulkString(message)]
+                    if s.eq_ignore_ascii_case(b"PUBLISH") =>
+                {
+                    Ok(Command::Publish(channel.clone(), message.clone()))
+                }
+                [RedisObject::BulkString(s)] if s.eq_ignore_ascii_case(b"PING") => {
+                    Ok(Command::Ping)
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(o)]
+                    if s.eq_ignore_ascii_case(b"ECHO") =>
+                {
+                    Ok(Command::Echo(o.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key)]
+                    if s.eq_ignore_ascii_case(b"GET") =>
+                {
+                    Ok(Command::Get(key.clone()))
+                }
+                [RedisObject::BulkString(s), RedisObject::BulkString(key), RedisObject::BulkString(value), options @ ..]
+                    if s.eq_ignore_ascii_case(b"SET") =>
+                {
+                    Ok(Command::Set(
+                        key.clone(),
+                        value.clone(),
+                        parse_set_options(options)?,
+                    ))
+                }
+                [RedisObject::BulkString(config), RedisObject::BulkString(s), patterns @ ..]
+                    if config.eq_ignore_ascii_case(b"CONFIG")
+                        && s.eq_ignore_ascii_case(b"GET")
+                        && !patterns.is_empty() =>
+                {
+                    Ok(Command::ConfigGet(bulk_strings(patterns)?))
+                }
+                _ => Err(CommandError::Invalid),
             },
-            Err(_) => Err(()),
+            _ => Err(CommandError::Invalid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small deterministic generator, so a failing case can be replayed.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn parse(stream: &[u8]) -> Result<Option<(RedisObject, usize)>, ProtocolError> {
+        RESPParser::default().parse(stream)
+    }
+
+    /// The state of a server with the default config and no keys.
+    fn new_state() -> Arc<State> {
+        let databases = (0..DB_COUNT).map(|_| Storage::new()).collect();
+        Arc::new(State::new(Config::new(), databases, 0))
+    }
+
+    /// Starts a server with the default config on a free port.
+    fn start_server() -> (Arc<State>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = new_state();
+        let server = state.clone();
+        thread::spawn(move || accept_connections(listener.incoming(), server));
+        (state, addr)
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    /// Sends `request` and checks that exactly `expected` comes back.
+    fn assert_reply(stream: &mut TcpStream, request: &[u8], expected: &[u8]) {
+        stream.write_all(request).unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    /// A connection that reads `input` and then the end of the stream, and
+    /// collects what's written to it. Clones share all of it.
+    #[derive(Clone, Default)]
+    struct MockConnection {
+        input: Arc<Mutex<io::Cursor<Vec<u8>>>>,
+        output: Arc<Mutex<Vec<u8>>>,
+        closed: Arc<AtomicBool>,
+        /// Makes cloning fail, as it does when file descriptors run out.
+        unclonable: bool,
+    }
+
+    impl MockConnection {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: Arc::new(Mutex::new(io::Cursor::new(input.to_vec()))),
+                ..Self::default()
+            }
+        }
+
+        /// Waits for the replies to everything read to be written.
+        fn wait_for_output(&self, expected: &[u8]) {
+            let deadline = time::Instant::now() + time::Duration::from_secs(5);
+            while *self.output.lock().unwrap() != expected {
+                assert!(
+                    time::Instant::now() < deadline,
+                    "wrote {:?}",
+                    String::from_utf8_lossy(&self.output.lock().unwrap())
+                );
+                thread::sleep(time::Duration::from_millis(10));
+            }
+        }
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.lock().unwrap().read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn try_clone_box(&self) -> io::Result<Box<dyn Connection>> {
+            if self.unclonable {
+                return Err(io::Error::other("too many open files"));
+            }
+            Ok(Box::new(self.clone()))
+        }
+
+        fn set_read_timeout(&self, _: Option<time::Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&self) {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+
+        fn addr(&self) -> String {
+            "mock".to_string()
+        }
+    }
+
+    #[test]
+    fn serves_any_connection() {
+        let connection = MockConnection::new(b"SET k v\r\nGET k\r\nECHO hi\r\n");
+        handle(connection.clone(), new_state());
+        connection.wait_for_output(b"+OK\r\n$1\r\nv\r\n$2\r\nhi\r\n");
+    }
+
+    #[test]
+    fn closes_connections_it_cant_clone() {
+        let connection = MockConnection {
+            unclonable: true,
+            ..MockConnection::new(b"PING\r\n")
+        };
+        handle(connection.clone(), new_state());
+        assert!(connection.closed.load(Ordering::SeqCst));
+        assert!(connection.output.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queues_connections_beyond_io_threads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = Config::new();
+        config
+            .params
+            .insert("io-threads".to_string(), "1".to_string());
+        let databases = (0..DB_COUNT).map(|_| Storage::new()).collect();
+        let state = Arc::new(State::new(config, databases, 0));
+        thread::spawn(move || accept_connections(listener.incoming(), state));
+        let mut first = connect(addr);
+        assert_reply(&mut first, b"PING\r\n", b"+PONG\r\n");
+        // Accepted, but left waiting until the only reader frees up.
+        let mut second = connect(addr);
+        second
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .unwrap();
+        second.write_all(b"PING\r\n").unwrap();
+        assert!(second.read(&mut [0; 7]).is_err());
+        drop(first);
+        second
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut reply = [0; 7];
+        second.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+
+    #[test]
+    fn reads_whole_hashes() {
+        let (_, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(&mut client, b"HSET h f1 a f2 b\r\n", b":2\r\n");
+        // Reads an array of `len` bytes and returns its elements. Like in
+        // Redis, fields come back in no particular order, so callers
+        // compare them as sets.
+        let mut elements = |request: &[u8], len: usize| {
+            client.write_all(request).unwrap();
+            let mut reply = vec![0; len];
+            client.read_exact(&mut reply).unwrap();
+            let reply = String::from_utf8(reply).unwrap();
+            reply
+                .split("\r\n")
+                .skip(2)
+                .step_by(2)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let pairs = elements(b"HGETALL h\r\n", 34)
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect::<HashSet<_>>();
+        let expected =
+            [("f1", "a"), ("f2", "b")].map(|(field, value)| (field.to_string(), value.to_string()));
+        assert_eq!(pairs, HashSet::from(expected));
+        let fields = elements(b"HKEYS h\r\n", 20);
+        assert_eq!(
+            fields.into_iter().collect::<HashSet<_>>(),
+            HashSet::from(["f1".to_string(), "f2".to_string()])
+        );
+        let values = elements(b"HVALS h\r\n", 18);
+        assert_eq!(
+            values.into_iter().collect::<HashSet<_>>(),
+            HashSet::from(["a".to_string(), "b".to_string()])
+        );
+        assert_reply(&mut client, b"HLEN h\r\n", b":2\r\n");
+        assert_reply(&mut client, b"HGETALL missing\r\n", b"*0\r\n");
+        assert_reply(&mut client, b"HKEYS missing\r\n", b"*0\r\n");
+        assert_reply(&mut client, b"HVALS missing\r\n", b"*0\r\n");
+        assert_reply(&mut client, b"HLEN missing\r\n", b":0\r\n");
+    }
+
+    #[test]
+    fn serializes_integers() {
+        assert_eq!(serialize_to_integer(0), b":0\r\n");
+        assert_eq!(serialize_to_integer(-1), b":-1\r\n");
+        assert_eq!(serialize_to_integer(42), b":42\r\n");
+        assert_eq!(serialize_to_integer(i64::MIN), b":-9223372036854775808\r\n");
+    }
+
+    #[test]
+    fn survives_a_client_closing_mid_reply() {
+        let (state, addr) = start_server();
+        let mut client = connect(addr);
+        let value = vec![b'x'; 1024 * 1024];
+        let mut set = format!("*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n${}\r\n", value.len()).into_bytes();
+        set.extend(&value);
+        set.extend(b"\r\n");
+        assert_reply(&mut client, &set, b"+OK\r\n");
+        // Ask for far more than the socket buffers hold, and hang up once
+        // the replies have started coming, so the writer's writes fail.
+        client.write_all(&b"GET big\r\n".repeat(64)).unwrap();
+        client.read_exact(&mut [0; 16]).unwrap();
+        drop(client);
+        let mut other = connect(addr);
+        assert_reply(&mut other, b"PING\r\n", b"+PONG\r\n");
+        // The first client's connection ends rather than hanging around.
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while state.clients.lock().unwrap().len() > 1 {
+            assert!(time::Instant::now() < deadline, "client never went away");
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn lrange_takes_negative_indices() {
+        let (_, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(
+            &mut client,
+            b"*5\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            b":3\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$2\r\n-1\r\n",
+            b"*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$2\r\n-2\r\n$2\r\n-1\r\n",
+            b"*2\r\n$1\r\nb\r\n$1\r\nc\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$4\r\n-100\r\n$2\r\n-4\r\n",
+            b"*0\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*4\r\n$6\r\nLRANGE\r\n$7\r\nmissing\r\n$1\r\n0\r\n$2\r\n-1\r\n",
+            b"*0\r\n",
+        );
+    }
+
+    #[test]
+    fn answers_commands_sent_in_one_read() {
+        let (_, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(
+            &mut client,
+            b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPING\r\n",
+            b"+PONG\r\n+PONG\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"PING\r\nECHO hi\r\n",
+            b"+PONG\r\n$2\r\nhi\r\n",
+        );
+    }
+
+    #[test]
+    fn forgets_watched_keys_once_unwatched() {
+        let (state, addr) = start_server();
+        let mut client = connect(addr);
+        let mut other = connect(addr);
+        assert_reply(&mut client, b"WATCH a b\r\n", b"+OK\r\n");
+        assert_reply(&mut other, b"WATCH a\r\n", b"+OK\r\n");
+        assert_reply(&mut client, b"UNWATCH\r\n", b"+OK\r\n");
+        assert!(state.storage[0].version(b"b").is_none());
+        // Still watched by the other client, so a write must still abort
+        // its transaction.
+        assert_reply(&mut client, b"SET a 1\r\n", b"+OK\r\n");
+        assert_reply(&mut other, b"MULTI\r\n", b"+OK\r\n");
+        assert_reply(&mut other, b"GET a\r\n", b"+QUEUED\r\n");
+        assert_reply(&mut other, b"EXEC\r\n", b"*-1\r\n");
+        assert!(state.storage[0].version(b"a").is_none());
+        assert_reply(&mut client, b"WATCH c\r\n", b"+OK\r\n");
+        assert_reply(&mut other, b"WATCH c\r\n", b"+OK\r\n");
+        assert_reply(&mut other, b"MULTI\r\n", b"+OK\r\n");
+        assert_reply(&mut other, b"DISCARD\r\n", b"+OK\r\n");
+        assert!(state.storage[0].version(b"c").is_some());
+        drop(client);
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while state.storage[0].version(b"c").is_some() {
+            assert!(time::Instant::now() < deadline, "watch never went away");
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn randomkey_skips_expired_keys() {
+        let (state, addr) = start_server();
+        state.active_expire.store(false, Ordering::SeqCst);
+        let mut client = connect(addr);
+        assert_reply(&mut client, b"RANDOMKEY\r\n", b"$-1\r\n");
+        assert_reply(&mut client, b"SET gone 1 PX 1\r\n", b"+OK\r\n");
+        thread::sleep(time::Duration::from_millis(5));
+        assert_reply(&mut client, b"RANDOMKEY\r\n", b"$-1\r\n");
+        assert_reply(&mut client, b"SET live 1\r\n", b"+OK\r\n");
+        for _ in 0..10 {
+            assert_reply(&mut client, b"RANDOMKEY\r\n", b"$4\r\nlive\r\n");
+        }
+        // The expired key is left for the active expire cycle.
+        assert_eq!(state.storage[0].read_all().len(), 2);
+    }
+
+    #[test]
+    fn only_touch_counts_as_an_access() {
+        let (state, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(&mut client, b"SET c 1\r\n", b"+OK\r\n");
+        let last_access = || {
+            let storage = state.storage[0].read(b"c");
+            let (_, _, last_access) = storage.get(&b"c"[..]).unwrap();
+            last_access.0.load(Ordering::Relaxed)
+        };
+        let set_at = last_access();
+        thread::sleep(time::Duration::from_millis(5));
+        assert_reply(&mut client, b"TTL c\r\n", b":-1\r\n");
+        assert_reply(&mut client, b"PTTL c\r\n", b":-1\r\n");
+        assert_reply(&mut client, b"TYPE c\r\n", b"+string\r\n");
+        assert_reply(&mut client, b"EXISTS c\r\n", b":1\r\n");
+        assert_eq!(last_access(), set_at);
+        assert_reply(&mut client, b"TOUCH c\r\n", b":1\r\n");
+        assert!(last_access() > set_at);
+    }
+
+    #[test]
+    fn del_and_unlink_skip_expired_keys() {
+        let (state, addr) = start_server();
+        state.active_expire.store(false, Ordering::SeqCst);
+        let mut client = connect(addr);
+        assert_reply(&mut client, b"SET a 1 PX 1\r\n", b"+OK\r\n");
+        assert_reply(&mut client, b"SET b 1 PX 1\r\n", b"+OK\r\n");
+        thread::sleep(time::Duration::from_millis(5));
+        assert_reply(&mut client, b"DEL a\r\n", b":0\r\n");
+        assert_reply(&mut client, b"UNLINK b\r\n", b":0\r\n");
+        assert_eq!(state.storage[0].read_all().len(), 0);
+    }
+
+    #[test]
+    fn active_expiry_counts_as_a_write() {
+        let (state, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(&mut client, b"SET k v PX 1\r\n", b"+OK\r\n");
+        assert_reply(&mut client, b"WATCH k\r\n", b"+OK\r\n");
+        let (sender, receiver) = mpsc::channel();
+        let replica = Replica {
+            sender,
+            ack_offset: 0,
+        };
+        state
+            .replication
+            .lock()
+            .unwrap()
+            .replicas
+            .insert(0, replica);
+        state.replica_count.store(1, Ordering::SeqCst);
+        thread::sleep(time::Duration::from_millis(5));
+        // A replica leaves expiry to its master.
+        state.is_replica.store(true, Ordering::SeqCst);
+        active_expire_cycle(&state, 0, time::Duration::from_secs(1));
+        assert_eq!(state.storage[0].read_all().len(), 1);
+        state.is_replica.store(false, Ordering::SeqCst);
+        active_expire_cycle(&state, 0, time::Duration::from_secs(1));
+        assert_eq!(state.storage[0].read_all().len(), 0);
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n"
+        );
+        assert_reply(&mut client, b"MULTI\r\n", b"+OK\r\n");
+        assert_reply(&mut client, b"EXEC\r\n", b"*-1\r\n");
+    }
+
+    #[test]
+    fn hello_reports_the_role() {
+        let (state, addr) = start_server();
+        let mut client = connect(addr);
+        let hello = |role: &str| {
+            format!(
+                "*14\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n$5\r\n7.2.0\r\n\
+                 $5\r\nproto\r\n:2\r\n$2\r\nid\r\n:1\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+                 $4\r\nrole\r\n${}\r\n{}\r\n$7\r\nmodules\r\n*0\r\n",
+                role.len(),
+                role
+            )
+        };
+        assert_reply(&mut client, b"HELLO\r\n", hello("master").as_bytes());
+        state.replication.lock().unwrap().master = Some(Master {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            link: None,
+            synced: false,
+        });
+        assert_reply(&mut client, b"HELLO\r\n", hello("replica").as_bytes());
+    }
+
+    #[test]
+    fn refuses_an_active_expire_interval_of_zero() {
+        let (_, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(
+            &mut client,
+            b"CONFIG SET active-expire-interval 0\r\n",
+            b"-ERR Invalid argument '0' for CONFIG SET 'active-expire-interval'\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"CONFIG SET active-expire-interval 1\r\n",
+            b"+OK\r\n",
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_keyspace_event_flags_at_startup() {
+        let args = |flags| Args::try_parse_from(["redis", "--notify-keyspace-events", flags]);
+        assert!(args("KEA").is_ok());
+        assert!(args("Q").is_err());
+        assert!(args("K$Q").is_err());
+    }
+
+    #[test]
+    fn notifies_binary_keys_intact() {
+        let (_, addr) = start_server();
+        let mut client = connect(addr);
+        let mut subscriber = connect(addr);
+        assert_reply(
+            &mut client,
+            b"CONFIG SET notify-keyspace-events K$\r\n",
+            b"+OK\r\n",
+        );
+        assert_reply(
+            &mut subscriber,
+            b"*2\r\n$9\r\nSUBSCRIBE\r\n$16\r\n__keyspace@0__:\xff\r\n",
+            b"*3\r\n$9\r\nsubscribe\r\n$16\r\n__keyspace@0__:\xff\r\n:1\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*3\r\n$3\r\nSET\r\n$1\r\n\xff\r\n$1\r\nv\r\n",
+            b"+OK\r\n",
+        );
+        let mut reply = [0; 49];
+        subscriber.read_exact(&mut reply).unwrap();
+        assert_eq!(
+            &reply[..],
+            b"*3\r\n$7\r\nmessage\r\n$16\r\n__keyspace@0__:\xff\r\n$3\r\nset\r\n"
+        );
+    }
+
+    #[test]
+    fn keeps_binary_keys_intact() {
+        let (_, addr) = start_server();
+        let mut client = connect(addr);
+        assert_reply(
+            &mut client,
+            b"*3\r\n$3\r\nSET\r\n$3\r\n\x00k\xff\r\n$1\r\nv\r\n",
+            b"+OK\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*2\r\n$3\r\nGET\r\n$3\r\n\x00k\xff\r\n",
+            b"$1\r\nv\r\n",
+        );
+        // Not the same key once the bytes are read as UTF-8.
+        assert_reply(
+            &mut client,
+            "*2\r\n$3\r\nGET\r\n$5\r\n\0k\u{fffd}\r\n".as_bytes(),
+            b"$-1\r\n",
+        );
+        assert_reply(
+            &mut client,
+            b"*2\r\n$4\r\nKEYS\r\n$1\r\n*\r\n",
+            b"*1\r\n$3\r\n\x00k\xff\r\n",
+        );
+    }
+
+    #[test]
+    fn formats_doubles_like_printf() {
+        let cases = [
+            (0.1, "0.10000000000000001"),
+            (1.5, "1.5"),
+            (100.0, "100"),
+            (-2.5, "-2.5"),
+            (0.0, "0"),
+            (1e16, "10000000000000000"),
+            (1e17, "1e+17"),
+            (1e-4, "0.0001"),
+            (1.5e-5, "1.5e-05"),
+            (1e300, "1.0000000000000001e+300"),
+            (10.0 / 3.0, "3.3333333333333335"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(format_double(n), expected);
+        }
+    }
+
+    #[test]
+    fn adds_floats_like_redis() {
+        assert_eq!(add_float(0.1, 0.2).unwrap(), "0.3");
+        assert_eq!(add_float(10.5, 0.1).unwrap(), "10.6");
+        assert_eq!(add_float(0.0, 5.0e3).unwrap(), "5000");
+        assert_eq!(add_float(3.0, -3.0).unwrap(), "0");
+        assert_eq!(add_float(0.1, -0.6).unwrap(), "-0.5");
+        assert_eq!(add_float(0.0, 1e-20).unwrap(), "0");
+        assert_eq!(add_float(1e22, 1.0).unwrap(), "10000000000000000000000");
+        assert!(add_float(f64::MAX, f64::MAX).is_err());
+    }
+
+    #[test]
+    fn failed_float_increments_leave_no_key() {
+        let mut storage = Storage::new();
+        assert!(increment_float(&mut storage, b"fk".to_vec(), f64::INFINITY).is_err());
+        assert!(
+            hash_increment_float(&mut storage, b"hk".to_vec(), b"f".to_vec(), f64::INFINITY)
+                .is_err()
+        );
+        assert!(storage.is_empty());
+        assert_eq!(
+            increment_float(&mut storage, b"fk".to_vec(), 1.5).unwrap(),
+            "1.5"
+        );
+        assert!(increment_float(&mut storage, b"fk".to_vec(), f64::INFINITY).is_err());
+        assert!(matches!(
+            &storage[&b"fk"[..]],
+            (None, RedisValue::String(value), _) if value == b"1.5"
+        ));
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_match(b"user:*:session", b"user:42:session"));
+        assert!(!glob_match(b"user:*:session", b"user:42:sessions"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a**b", b"ab"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"*ab*ab", b"abababab"));
+        assert!(!glob_match(b"", b"a"));
+    }
+
+    #[test]
+    fn glob_matches_classes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"[a-c]", b"b"));
+        assert!(glob_match(b"[c-a]", b"b"));
+        assert!(!glob_match(b"[a-c]", b"d"));
+        // A `[` inside a class is just another member of it.
+        assert!(glob_match(b"[[]", b"["));
+        assert!(glob_match(b"[a[]x", b"[x"));
+        assert!(glob_match(b"[a[]x", b"ax"));
+        assert!(glob_match(b"[^[]]", b"a]"));
+        assert!(!glob_match(b"[^[]]", b"[]"));
+        assert!(glob_match(b"[[a-c]]", b"b]"));
+        // A class closes at the first unescaped `]`.
+        assert!(glob_match(b"[\\]a]", b"]"));
+        assert!(glob_match(b"[\\]a]", b"a"));
+        assert!(!glob_match(b"[]]", b"]"));
+    }
+
+    #[test]
+    fn glob_matches_escapes() {
+        assert!(glob_match(b"\\*", b"*"));
+        assert!(!glob_match(b"\\*", b"a"));
+        assert!(glob_match(b"a\\?", b"a?"));
+        assert!(!glob_match(b"a\\?", b"ab"));
+        assert!(glob_match(b"\\[a]", b"[a]"));
+        assert!(glob_match(b"\\\\", b"\\"));
+        assert!(glob_match(b"*\\*", b"abc*"));
+        // A trailing backslash is a literal one.
+        assert!(glob_match(b"a\\", b"a\\"));
+    }
+
+    #[test]
+    fn glob_match_doesnt_backtrack_exponentially() {
+        let pattern = [b"*a".repeat(12), b"*b".to_vec()].concat();
+        let started = time::Instant::now();
+        assert!(!glob_match(&pattern, &b"a".repeat(40)));
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parses_a_command() {
+        let (object, consumed) = parse(b"*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n").unwrap().unwrap();
+        assert_eq!(consumed, 22);
+        assert!(matches!(
+            object,
+            RedisObject::Array(args) if matches!(
+                args.as_slice(),
+                [RedisObject::BulkString(name), RedisObject::BulkString(arg)]
+                    if name == b"ECHO" && arg == b"hi"
+            )
+        ));
+    }
+
+    #[test]
+    fn waits_for_the_end_of_a_line() {
+        assert!(matches!(parse(b"*1"), Ok(None)));
+        assert!(matches!(parse(b"*1\r\n$4\r"), Ok(None)));
+        assert!(matches!(
+            parse(b"*\r\n"),
+            Err(ProtocolError::InvalidMultibulkLength)
+        ));
+        assert!(matches!(
+            parse(b"*1\r\n$\r\n"),
+            Err(ProtocolError::InvalidBulkLength)
+        ));
+    }
+
+    #[test]
+    fn parses_a_command_sent_a_byte_at_a_time() {
+        let stream = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*1\r\n$4\r\nPING\r\n";
+        let mut parser = RESPParser::default();
+        let mut pending = Vec::new();
+        let mut commands = Vec::new();
+        for &byte in stream {
+            pending.push(byte);
+            if let Some((RedisObject::Array(args), consumed)) = parser.parse(&pending).unwrap() {
+                pending.drain(..consumed);
+                commands.push(bulk_strings(&args).unwrap());
+            }
+        }
+        assert!(pending.is_empty());
+        assert_eq!(commands, [vec!["SET", "key", "value"], vec!["PING"]]);
+    }
+
+    #[test]
+    fn parses_inline_commands() {
+        let (object, consumed) = parse(b"SET key 'a b'\r\nPING").unwrap().unwrap();
+        assert_eq!(consumed, 15);
+        assert_eq!(
+            command_args(&object),
+            [b"SET".to_vec(), b"key".to_vec(), b"a b".to_vec()]
+        );
+        assert!(matches!(
+            parse(b"SET key \"value\n"),
+            Err(ProtocolError::UnbalancedQuotes)
+        ));
+    }
+
+    #[test]
+    fn enforces_length_limits() {
+        assert!(matches!(
+            parse(b"*1048577\r\n"),
+            Err(ProtocolError::InvalidMultibulkLength)
+        ));
+        assert!(matches!(parse(b"*1048576\r\n"), Ok(None)));
+        assert!(matches!(
+            parse(b"*1\r\n$536870913\r\n"),
+            Err(ProtocolError::InvalidBulkLength)
+        ));
+        assert!(matches!(parse(b"*1\r\n$536870912\r\n"), Ok(None)));
+        assert!(matches!(
+            parse(b"*1\r\n$-1\r\n"),
+            Err(ProtocolError::InvalidBulkLength)
+        ));
+        assert!(matches!(
+            parse(&vec![b'a'; INLINE_MAX_LEN + 1]),
+            Err(ProtocolError::InlineTooBig)
+        ));
+        let mut header = b"*1\r\n$".to_vec();
+        header.resize(2 * INLINE_MAX_LEN, b'1');
+        assert!(matches!(
+            parse(&header),
+            Err(ProtocolError::BulkCountTooBig)
+        ));
+    }
+
+    #[test]
+    fn rejects_nested_arrays() {
+        assert!(parse(b"*1\r\n*1\r\n$4\r\nPING\r\n").is_err());
+        assert!(parse(b"*1\r\n:1\r\n").is_err());
+        // Deep enough to overflow the stack if nesting were followed.
+        assert!(parse(&b"*1\r\n".repeat(300_000)).is_err());
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..20_000 {
+            let len = rng.next() as usize % 64;
+            let bytes = (0..len)
+                .map(|_| b"*$:+-\r\n0123456789-aZ\x00\xff"[rng.next() as usize % 21])
+                .collect::<Vec<_>>();
+            let _ = parse(&bytes);
+        }
+    }
+
+    #[test]
+    fn mutated_commands_never_panic() {
+        let command = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        for end in 0..command.len() {
+            assert!(matches!(parse(&command[..end]), Ok(None)));
+        }
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let mut bytes = command.to_vec();
+            for _ in 0..1 + rng.next() % 4 {
+                let index = rng.next() as usize % bytes.len();
+                bytes[index] = rng.next() as u8;
+            }
+            bytes.truncate(1 + rng.next() as usize % bytes.len());
+            let _ = parse(&bytes);
         }
     }
 }
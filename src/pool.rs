@@ -0,0 +1,74 @@
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that run jobs, reused from one job to the next instead of
+/// starting a thread for each. Threads are started as jobs need them, up to
+/// `max_threads`; once that many are busy, jobs wait in a queue for the next
+/// thread to finish.
+pub struct ThreadPool {
+    max_threads: usize,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    threads: usize,
+    /// A way to hand a job to each thread waiting for one.
+    idle: Vec<Sender<Job>>,
+    /// Jobs waiting for a thread.
+    queued: VecDeque<Job>,
+}
+
+impl ThreadPool {
+    pub fn new(max_threads: usize) -> Self {
+        Self {
+            max_threads: max_threads.max(1),
+            inner: Arc::default(),
+        }
+    }
+
+    /// Runs `job` on an idle thread, a new one, or the first to free up.
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let mut inner = self.inner.lock().unwrap();
+        let job: Job = Box::new(job);
+        if let Some(idle) = inner.idle.pop() {
+            // An idle thread is waiting on the other end, so this can't fail.
+            idle.send(job).unwrap();
+        } else if inner.threads < self.max_threads {
+            inner.threads += 1;
+            let pool = self.inner.clone();
+            thread::spawn(move || work(pool, job));
+        } else {
+            inner.queued.push_back(job);
+        }
+    }
+}
+
+/// Runs `job` and then every job handed to this thread after it, forever.
+fn work(pool: Arc<Mutex<Inner>>, mut job: Job) -> ! {
+    let (sender, receiver) = mpsc::channel();
+    loop {
+        // A job that panics has already reported it, and shouldn't take the
+        // thread down with it.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        let mut inner = pool.lock().unwrap();
+        job = match inner.queued.pop_front() {
+            Some(queued) => queued,
+            None => {
+                inner.idle.push(sender.clone());
+                drop(inner);
+                // This thread holds a sender itself, so this can't fail.
+                receiver.recv().unwrap()
+            }
+        };
+    }
+}
@@ -0,0 +1,1191 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs, io,
+    path::Path,
+    time,
+};
+
+use crate::{unix_millis_now, zset::SortedSet, LastAccess, RedisValue, Storage};
+
+const OPCODE_FUNCTION: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+/// Quicklist node containers, as stored in `TYPE_LIST_QUICKLIST_2` values.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+const QUICKLIST_NODE_PACKED: usize = 2;
+
+/// Types of the values in a module's data, each stored before the value.
+const MODULE_OPCODE_EOF: usize = 0;
+const MODULE_OPCODE_SINT: usize = 1;
+const MODULE_OPCODE_UINT: usize = 2;
+const MODULE_OPCODE_FLOAT: usize = 3;
+const MODULE_OPCODE_DOUBLE: usize = 4;
+const MODULE_OPCODE_STRING: usize = 5;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+/// The reflected form of the Jones polynomial used for RDB checksums.
+const CRC64_POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+
+/// The contents of an RDB snapshot file.
+#[derive(Debug)]
+pub struct RDBObject {
+    /// Auxiliary fields such as `redis-ver` stored in the header.
+    pub metadata: HashMap<String, Vec<u8>>,
+    /// The keyspace of each database in the file, by database number.
+    pub databases: HashMap<usize, Storage>,
+}
+
+/// A length prefix as stored in an RDB file.
+#[derive(Debug, PartialEq)]
+enum Length {
+    Len(usize),
+    /// The object that follows is stored in the special format identified
+    /// by the value, rather than as a length-prefixed string.
+    Encoded(u8),
+}
+
+impl RDBObject {
+    pub fn from_file(path: &Path) -> Result<Self, ()> {
+        let data = fs::read(path).map_err(|_| ())?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ()> {
+        if data.len() < 9 || &data[..5] != b"REDIS" {
+            return Err(());
+        }
+        let version = std::str::from_utf8(&data[5..9])
+            .map_err(|_| ())?
+            .parse::<u32>()
+            .map_err(|_| ())?;
+        let mut rdb = Self {
+            metadata: HashMap::new(),
+            databases: HashMap::new(),
+        };
+        let mut pos = 9;
+        // Keys before the first selector belong to database 0.
+        let mut db_index = 0;
+        // Expiry of the next key, as milliseconds since the Unix epoch.
+        let mut expire_at = None;
+        // How long ago the next key was last accessed, in seconds.
+        let mut idle = None;
+        loop {
+            let opcode = *data.get(pos).ok_or(())?;
+            pos += 1;
+            match opcode {
+                OPCODE_AUX => {
+                    let (key, consumed) = decode_object(&data[pos..])?;
+                    pos += consumed;
+                    let (value, consumed) = decode_object(&data[pos..])?;
+                    pos += consumed;
+                    rdb.metadata
+                        .insert(String::from_utf8(key).map_err(|_| ())?, value);
+                }
+                OPCODE_SELECTDB => {
+                    let (Length::Len(index), consumed) = decode_length(&data[pos..])? else {
+                        return Err(());
+                    };
+                    db_index = index;
+                    pos += consumed;
+                }
+                OPCODE_RESIZEDB => {
+                    for _ in 0..2 {
+                        let (_, consumed) = decode_length(&data[pos..])?;
+                        pos += consumed;
+                    }
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    let bytes = data.get(pos..pos + 8).ok_or(())?;
+                    expire_at = Some(i64::from_le_bytes(bytes.try_into().unwrap()));
+                    pos += 8;
+                }
+                OPCODE_EXPIRETIME => {
+                    let bytes = data.get(pos..pos + 4).ok_or(())?;
+                    expire_at = Some(u32::from_le_bytes(bytes.try_into().unwrap()) as i64 * 1000);
+                    pos += 4;
+                }
+                // Saved for every key when Redis evicts by LRU.
+                OPCODE_IDLE => {
+                    let (seconds, consumed) = decode_plain_length(&data[pos..])?;
+                    idle = Some(seconds);
+                    pos += consumed;
+                }
+                // Saved for every key when Redis evicts by LFU, which isn't
+                // supported, so the counter is skipped.
+                OPCODE_FREQ => {
+                    data.get(pos).ok_or(())?;
+                    pos += 1;
+                }
+                // Functions aren't supported, so their code is skipped.
+                OPCODE_FUNCTION => {
+                    let (_, consumed) = decode_object(&data[pos..])?;
+                    pos += consumed;
+                }
+                OPCODE_MODULE_AUX => pos += skip_module_aux(&data[pos..])?,
+                OPCODE_EOF => {
+                    // Files before version 5 have no checksum.
+                    if version >= 5 {
+                        let bytes = data.get(pos..pos + 8).ok_or(())?;
+                        let checksum = u64::from_le_bytes(bytes.try_into().unwrap());
+                        // A zero checksum means checksums were disabled.
+                        if checksum != 0 && checksum != crc64(&data[..pos]) {
+                            return Err(());
+                        }
+                    }
+                    break;
+                }
+                type_flag => {
+                    let (key, consumed) = decode_object(&data[pos..])?;
+                    pos += consumed;
+                    let (value, consumed) = decode_value(type_flag, &data[pos..])?;
+                    pos += consumed;
+                    let last_access = match idle.take() {
+                        Some(seconds) => {
+                            LastAccess::idle_for(time::Duration::from_secs(seconds as u64))
+                        }
+                        None => LastAccess::default(),
+                    };
+                    // The file stores wall-clock time while storage uses
+                    // monotonic instants, so convert via the time left.
+                    let expiry = match expire_at.take() {
+                        Some(expire_at) => {
+                            let remaining = expire_at.saturating_sub(unix_millis_now());
+                            if remaining <= 0 {
+                                continue;
+                            }
+                            time::Instant::now()
+                                .checked_add(time::Duration::from_millis(remaining as u64))
+                        }
+                        None => None,
+                    };
+                    rdb.databases
+                        .entry(db_index)
+                        .or_default()
+                        .insert(key, (expiry, value, last_access));
+                }
+            }
+        }
+        Ok(rdb)
+    }
+
+    /// Serializes the snapshot in RDB format, checksum included. Expiries are
+    /// written as absolute Unix timestamps and keys that have already expired
+    /// are left out.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = b"REDIS0011".to_vec();
+        let mut metadata = self.metadata.iter().collect::<Vec<_>>();
+        metadata.sort();
+        for (key, value) in metadata {
+            out.push(OPCODE_AUX);
+            encode_object(&mut out, key.as_bytes());
+            encode_object(&mut out, value);
+        }
+        let mut databases = self.databases.iter().collect::<Vec<_>>();
+        databases.sort_by_key(|(index, _)| **index);
+        let now = time::Instant::now();
+        for (index, storage) in databases {
+            let live = storage
+                .iter()
+                .filter(|(_, (expiry, _, _))| expiry.is_none_or(|expiry| expiry > now))
+                .collect::<Vec<_>>();
+            if live.is_empty() {
+                continue;
+            }
+            out.push(OPCODE_SELECTDB);
+            encode_length(&mut out, *index);
+            out.push(OPCODE_RESIZEDB);
+            encode_length(&mut out, live.len());
+            encode_length(
+                &mut out,
+                live.iter().filter(|(_, (e, _, _))| e.is_some()).count(),
+            );
+            for (key, (expiry, value, _)) in live {
+                if let Some(expiry) = expiry {
+                    let expire_at = unix_millis_now() + (*expiry - now).as_millis() as i64;
+                    out.push(OPCODE_EXPIRETIME_MS);
+                    out.extend_from_slice(&expire_at.to_le_bytes());
+                }
+                encode_value(&mut out, key, value);
+            }
+        }
+        out.push(OPCODE_EOF);
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Writes the snapshot to `path`. The file is written under a temporary
+    /// name and renamed into place, so a crash never leaves a partial file.
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        let temp_path = path.with_extension("rdb.tmp");
+        fs::write(&temp_path, self.to_bytes())?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Encodes a key and its value, preceded by the value's type.
+fn encode_value(out: &mut Vec<u8>, key: &[u8], value: &RedisValue) {
+    let type_flag = match value {
+        RedisValue::String(_) => TYPE_STRING,
+        RedisValue::List(_) => TYPE_LIST,
+        RedisValue::Set(_) => TYPE_SET,
+        RedisValue::Hash(_) => TYPE_HASH,
+        RedisValue::ZSet(_) => TYPE_ZSET_2,
+    };
+    out.push(type_flag);
+    encode_object(out, key);
+    encode_contents(out, value);
+}
+
+/// The size of a value once encoded, leaving out its key and type, as
+/// reported by `DEBUG OBJECT`.
+pub fn encoded_len(value: &RedisValue) -> usize {
+    let mut out = Vec::new();
+    encode_contents(&mut out, value);
+    out.len()
+}
+
+/// Encodes a value without its key and type.
+fn encode_contents(out: &mut Vec<u8>, value: &RedisValue) {
+    match value {
+        RedisValue::String(value) => encode_object(out, value),
+        RedisValue::List(list) => {
+            encode_length(out, list.len());
+            for item in list {
+                encode_object(out, item);
+            }
+        }
+        RedisValue::Set(set) => {
+            encode_length(out, set.len());
+            for member in set {
+                encode_object(out, member);
+            }
+        }
+        RedisValue::Hash(hash) => {
+            encode_length(out, hash.len());
+            for (field, value) in hash {
+                encode_object(out, field);
+                encode_object(out, value);
+            }
+        }
+        RedisValue::ZSet(zset) => {
+            encode_length(out, zset.len());
+            for (member, score) in zset.iter() {
+                encode_object(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Encodes a length prefix in the smallest format that fits it.
+fn encode_length(out: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        out.push(length as u8);
+    } else if length < 1 << 14 {
+        out.extend_from_slice(&[0x40 | (length >> 8) as u8, length as u8]);
+    } else if let Ok(length) = u32::try_from(length) {
+        out.push(0x80);
+        out.extend_from_slice(&length.to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+}
+
+/// Encodes a string as its length followed by its bytes.
+fn encode_object(out: &mut Vec<u8>, bytes: &[u8]) {
+    encode_length(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+/// Decodes a value of the given type, returning it along with the number of
+/// bytes it took up.
+fn decode_value(type_flag: u8, data: &[u8]) -> Result<(RedisValue, usize), ()> {
+    match type_flag {
+        TYPE_STRING => {
+            let (value, consumed) = decode_object(data)?;
+            Ok((RedisValue::String(value), consumed))
+        }
+        TYPE_LIST => {
+            let (items, consumed) = decode_objects(data, 1)?;
+            Ok((RedisValue::List(items.into()), consumed))
+        }
+        TYPE_SET => {
+            let (items, consumed) = decode_objects(data, 1)?;
+            Ok((RedisValue::Set(items.into_iter().collect()), consumed))
+        }
+        TYPE_ZSET => {
+            let (zset, consumed) = decode_zset(data, false)?;
+            Ok((RedisValue::ZSet(zset), consumed))
+        }
+        TYPE_HASH => {
+            let (items, consumed) = decode_objects(data, 2)?;
+            Ok((RedisValue::Hash(into_pairs(items)?), consumed))
+        }
+        TYPE_ZSET_2 => {
+            let (zset, consumed) = decode_zset(data, true)?;
+            Ok((RedisValue::ZSet(zset), consumed))
+        }
+        TYPE_LIST_ZIPLIST => {
+            let (blob, consumed) = decode_object(data)?;
+            Ok((RedisValue::List(decode_ziplist(&blob)?.into()), consumed))
+        }
+        TYPE_SET_INTSET => {
+            let (blob, consumed) = decode_object(data)?;
+            Ok((RedisValue::Set(decode_intset(&blob)?), consumed))
+        }
+        TYPE_ZSET_ZIPLIST => {
+            let (blob, consumed) = decode_object(data)?;
+            let zset = into_zset(decode_ziplist(&blob)?)?;
+            Ok((RedisValue::ZSet(zset), consumed))
+        }
+        TYPE_HASH_ZIPLIST => {
+            let (blob, consumed) = decode_object(data)?;
+            let hash = into_pairs(decode_ziplist(&blob)?)?;
+            Ok((RedisValue::Hash(hash), consumed))
+        }
+        TYPE_LIST_QUICKLIST => {
+            let (nodes, consumed) = decode_objects(data, 1)?;
+            let mut list = VecDeque::new();
+            for node in nodes {
+                list.extend(decode_ziplist(&node)?);
+            }
+            Ok((RedisValue::List(list), consumed))
+        }
+        TYPE_HASH_LISTPACK => {
+            let (blob, consumed) = decode_object(data)?;
+            let hash = into_pairs(decode_listpack(&blob)?)?;
+            Ok((RedisValue::Hash(hash), consumed))
+        }
+        TYPE_ZSET_LISTPACK => {
+            let (blob, consumed) = decode_object(data)?;
+            let zset = into_zset(decode_listpack(&blob)?)?;
+            Ok((RedisValue::ZSet(zset), consumed))
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let (node_count, mut pos) = decode_plain_length(data)?;
+            let mut list = VecDeque::new();
+            for _ in 0..node_count {
+                let (container, consumed) = decode_plain_length(&data[pos..])?;
+                pos += consumed;
+                let (node, consumed) = decode_object(&data[pos..])?;
+                pos += consumed;
+                match container {
+                    QUICKLIST_NODE_PLAIN => list.push_back(node),
+                    QUICKLIST_NODE_PACKED => list.extend(decode_listpack(&node)?),
+                    _ => return Err(()),
+                }
+            }
+            Ok((RedisValue::List(list), pos))
+        }
+        TYPE_SET_LISTPACK => {
+            let (blob, consumed) = decode_object(data)?;
+            let set = decode_listpack(&blob)?.into_iter().collect();
+            Ok((RedisValue::Set(set), consumed))
+        }
+        _ => Err(()),
+    }
+}
+
+/// Skips a module's auxiliary data, returning the number of bytes it took
+/// up: the module's id and when the data is loaded, then values each
+/// preceded by their type, up to an end marker. Modules aren't supported, so
+/// like Redis does for a module that isn't loaded, the values are read only
+/// to find where the data ends.
+fn skip_module_aux(data: &[u8]) -> Result<usize, ()> {
+    let (_, mut pos) = decode_plain_length(data)?;
+    let (when_type, consumed) = decode_plain_length(&data[pos..])?;
+    pos += consumed;
+    if when_type != MODULE_OPCODE_UINT {
+        return Err(());
+    }
+    let (_, consumed) = decode_plain_length(&data[pos..])?;
+    pos += consumed;
+    loop {
+        let (value_type, consumed) = decode_plain_length(data.get(pos..).ok_or(())?)?;
+        pos += consumed;
+        let rest = &data[pos..];
+        pos += match value_type {
+            MODULE_OPCODE_EOF => return Ok(pos),
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => decode_plain_length(rest)?.1,
+            MODULE_OPCODE_FLOAT => 4,
+            MODULE_OPCODE_DOUBLE => 8,
+            MODULE_OPCODE_STRING => decode_object(rest)?.1,
+            _ => return Err(()),
+        };
+    }
+}
+
+/// Decodes a count followed by `count * per_item` strings, returning the
+/// strings along with the number of bytes they took up.
+fn decode_objects(data: &[u8], per_item: usize) -> Result<(Vec<Vec<u8>>, usize), ()> {
+    let (count, mut pos) = decode_plain_length(data)?;
+    let total = count.checked_mul(per_item).ok_or(())?;
+    let mut items = Vec::new();
+    for _ in 0..total {
+        let (item, consumed) = decode_object(&data[pos..])?;
+        pos += consumed;
+        items.push(item);
+    }
+    Ok((items, pos))
+}
+
+/// Pairs up alternating fields and values into a hash.
+fn into_pairs(items: Vec<Vec<u8>>) -> Result<HashMap<Vec<u8>, Vec<u8>>, ()> {
+    if !items.len().is_multiple_of(2) {
+        return Err(());
+    }
+    let mut items = items.into_iter();
+    let mut hash = HashMap::new();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
+
+/// Pairs up alternating members and scores into a sorted set.
+fn into_zset(items: Vec<Vec<u8>>) -> Result<SortedSet, ()> {
+    let mut zset = SortedSet::default();
+    for (member, score) in into_pairs(items)? {
+        zset.insert(member, parse_score(&score)?);
+    }
+    Ok(zset)
+}
+
+/// Decodes a count followed by that many members and their scores, returning
+/// the sorted set along with the number of bytes it took up. Scores are
+/// either 8-byte little-endian doubles, or strings prefixed by a one-byte
+/// length, which is instead 254 or 255 for infinity or negative infinity.
+/// Sorted sets can't hold NaN, so a NaN score is rejected.
+fn decode_zset(data: &[u8], binary_scores: bool) -> Result<(SortedSet, usize), ()> {
+    let (count, mut pos) = decode_plain_length(data)?;
+    let mut zset = SortedSet::default();
+    for _ in 0..count {
+        let (member, consumed) = decode_object(&data[pos..])?;
+        pos += consumed;
+        let score = if binary_scores {
+            let bytes = data.get(pos..pos + 8).ok_or(())?;
+            pos += 8;
+            let score = f64::from_le_bytes(bytes.try_into().unwrap());
+            if score.is_nan() {
+                return Err(());
+            }
+            score
+        } else {
+            let length = *data.get(pos).ok_or(())? as usize;
+            pos += 1;
+            match length {
+                253 => return Err(()),
+                254 => f64::INFINITY,
+                255 => f64::NEG_INFINITY,
+                _ => {
+                    let score = parse_score(data.get(pos..pos + length).ok_or(())?)?;
+                    pos += length;
+                    score
+                }
+            }
+        };
+        zset.insert(member, score);
+    }
+    Ok((zset, pos))
+}
+
+/// Parses a score stored as a string.
+fn parse_score(s: &[u8]) -> Result<f64, ()> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or(())
+}
+
+/// Decodes a length prefix, returning it along with the number of bytes it
+/// took up. The top two bits of the first byte select the format: a 6-bit
+/// length, a 14-bit length, a 32 or 64-bit big-endian length in the bytes
+/// that follow, or a special encoding.
+fn decode_length(data: &[u8]) -> Result<(Length, usize), ()> {
+    let first_byte = *data.first().ok_or(())?;
+    match first_byte >> 6 {
+        0 => Ok((Length::Len((first_byte & 0x3F) as usize), 1)),
+        1 => {
+            let second_byte = *data.get(1).ok_or(())?;
+            let length = (((first_byte & 0x3F) as usize) << 8) | second_byte as usize;
+            Ok((Length::Len(length), 2))
+        }
+        2 => match first_byte {
+            0x80 => {
+                let bytes = data.get(1..5).ok_or(())?;
+                let length = u32::from_be_bytes(bytes.try_into().unwrap());
+                Ok((Length::Len(length as usize), 5))
+            }
+            0x81 => {
+                let bytes = data.get(1..9).ok_or(())?;
+                let length = u64::from_be_bytes(bytes.try_into().unwrap());
+                Ok((Length::Len(usize::try_from(length).map_err(|_| ())?), 9))
+            }
+            _ => Err(()),
+        },
+        _ => Ok((Length::Encoded(first_byte & 0x3F), 1)),
+    }
+}
+
+/// Decodes a length prefix that must be a plain length rather than a special
+/// encoding.
+fn decode_plain_length(data: &[u8]) -> Result<(usize, usize), ()> {
+    match decode_length(data)? {
+        (Length::Len(length), consumed) => Ok((length, consumed)),
+        (Length::Encoded(_), _) => Err(()),
+    }
+}
+
+/// Decodes a string, returning it along with the number of bytes it took up.
+/// Strings are either stored as a length followed by that many bytes, or in
+/// a special encoding: an 8, 16 or 32-bit little-endian integer, or LZF
+/// compressed.
+fn decode_object(data: &[u8]) -> Result<(Vec<u8>, usize), ()> {
+    match decode_length(data)? {
+        (Length::Len(length), consumed) => {
+            let end = consumed.checked_add(length).ok_or(())?;
+            let bytes = data.get(consumed..end).ok_or(())?;
+            Ok((bytes.to_vec(), end))
+        }
+        (Length::Encoded(ENCODING_INT8), consumed) => {
+            let n = *data.get(consumed).ok_or(())? as i8;
+            Ok((n.to_string().into_bytes(), consumed + 1))
+        }
+        (Length::Encoded(ENCODING_INT16), consumed) => {
+            let bytes = data.get(consumed..consumed + 2).ok_or(())?;
+            let n = i16::from_le_bytes(bytes.try_into().unwrap());
+            Ok((n.to_string().into_bytes(), consumed + 2))
+        }
+        (Length::Encoded(ENCODING_INT32), consumed) => {
+            let bytes = data.get(consumed..consumed + 4).ok_or(())?;
+            let n = i32::from_le_bytes(bytes.try_into().unwrap());
+            Ok((n.to_string().into_bytes(), consumed + 4))
+        }
+        (Length::Encoded(ENCODING_LZF), consumed) => {
+            let (compressed_len, compressed_len_size) = decode_length(&data[consumed..])?;
+            let (expected_len, expected_len_size) =
+                decode_length(&data[consumed + compressed_len_size..])?;
+            let (Length::Len(compressed_len), Length::Len(expected_len)) =
+                (compressed_len, expected_len)
+            else {
+                return Err(());
+            };
+            let start = consumed + compressed_len_size + expected_len_size;
+            let end = start.checked_add(compressed_len).ok_or(())?;
+            let compressed = data.get(start..end).ok_or(())?;
+            Ok((lzf_decompress(compressed, expected_len)?, end))
+        }
+        (Length::Encoded(_), _) => Err(()),
+    }
+}
+
+/// Decompresses an LZF compressed string. The input is a sequence of
+/// literal runs and back references into the output produced so far, each
+/// introduced by a control byte: values below 32 start a run of that many
+/// plus one literal bytes, anything else is a back reference whose length
+/// and offset are packed into the control byte and the bytes after it.
+fn lzf_decompress(compressed: &[u8], expected_len: usize) -> Result<Vec<u8>, ()> {
+    // The expected length comes from the file, so don't trust it further
+    // than the input could expand: a 3-byte back reference yields at most
+    // 264 bytes.
+    let max_len = compressed.len().saturating_mul(88);
+    let mut out = Vec::with_capacity(expected_len.min(max_len));
+    let mut pos = 0;
+    while pos < compressed.len() {
+        let ctrl = compressed[pos] as usize;
+        pos += 1;
+        if ctrl < 32 {
+            let run = compressed.get(pos..pos + ctrl + 1).ok_or(())?;
+            out.extend_from_slice(run);
+            pos += ctrl + 1;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *compressed.get(pos).ok_or(())? as usize;
+                pos += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *compressed.get(pos).ok_or(())? as usize + 1;
+            pos += 1;
+            let start = out.len().checked_sub(offset).ok_or(())?;
+            // The reference may overlap the bytes it produces, so copy one
+            // byte at a time.
+            for i in start..start + len + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > expected_len {
+            return Err(());
+        }
+    }
+    if out.len() != expected_len {
+        return Err(());
+    }
+    Ok(out)
+}
+
+/// Computes the CRC-64/Jones checksum Redis stores at the end of RDB files.
+fn crc64(data: &[u8]) -> u64 {
+    let mut crc = 0u64;
+    for &byte in data {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Decodes the entries of a ziplist. The list starts with a 10-byte header
+/// holding its size, tail offset and entry count, and ends with `0xFF`. Each
+/// entry holds the length of the previous entry, then either a string with a
+/// 6, 14 or 32-bit length, or an integer of 4 to 64 bits.
+fn decode_ziplist(blob: &[u8]) -> Result<Vec<Vec<u8>>, ()> {
+    let mut items = Vec::new();
+    let mut pos = 10;
+    loop {
+        let prev_len = *blob.get(pos).ok_or(())?;
+        if prev_len == 0xFF {
+            break;
+        }
+        pos += if prev_len == 0xFE { 5 } else { 1 };
+        let encoding = *blob.get(pos).ok_or(())?;
+        pos += 1;
+        let (len, int_width) = match encoding >> 6 {
+            0 => ((encoding & 0x3F) as usize, None),
+            1 => {
+                let next = *blob.get(pos).ok_or(())? as usize;
+                pos += 1;
+                ((((encoding & 0x3F) as usize) << 8) | next, None)
+            }
+            2 => {
+                let bytes = blob.get(pos..pos + 4).ok_or(())?;
+                pos += 4;
+                (u32::from_be_bytes(bytes.try_into().unwrap()) as usize, None)
+            }
+            _ => match encoding {
+                0xC0 => (2, Some(2)),
+                0xD0 => (4, Some(4)),
+                0xE0 => (8, Some(8)),
+                0xF0 => (3, Some(3)),
+                0xFE => (1, Some(1)),
+                0xF1..=0xFD => {
+                    items.push(((encoding & 0x0F) - 1).to_string().into_bytes());
+                    continue;
+                }
+                _ => return Err(()),
+            },
+        };
+        let bytes = blob.get(pos..pos + len).ok_or(())?;
+        pos += len;
+        items.push(match int_width {
+            Some(width) => le_signed(bytes, width).to_string().into_bytes(),
+            None => bytes.to_vec(),
+        });
+    }
+    Ok(items)
+}
+
+/// Decodes the entries of a listpack. The list starts with a 6-byte header
+/// holding its size and entry count, and ends with `0xFF`. Each entry is an
+/// encoding byte selecting a small integer or string inline, or an integer
+/// or string in the bytes that follow, and ends with its own length stored
+/// backwards so the list can be walked from the tail.
+fn decode_listpack(blob: &[u8]) -> Result<Vec<Vec<u8>>, ()> {
+    let mut items = Vec::new();
+    let mut pos = 6;
+    loop {
+        let encoding = *blob.get(pos).ok_or(())?;
+        if encoding == 0xFF {
+            break;
+        }
+        let start = pos;
+        pos += 1;
+        let item = if encoding & 0x80 == 0 {
+            (encoding & 0x7F).to_string().into_bytes()
+        } else if encoding & 0xC0 == 0x80 {
+            let len = (encoding & 0x3F) as usize;
+            let bytes = blob.get(pos..pos + len).ok_or(())?;
+            pos += len;
+            bytes.to_vec()
+        } else if encoding & 0xE0 == 0xC0 {
+            let next = *blob.get(pos).ok_or(())? as i64;
+            pos += 1;
+            let n = (((encoding & 0x1F) as i64) << 8) | next;
+            // 13-bit two's complement.
+            let n = if n >= 1 << 12 { n - (1 << 13) } else { n };
+            n.to_string().into_bytes()
+        } else if encoding & 0xF0 == 0xE0 {
+            let next = *blob.get(pos).ok_or(())? as usize;
+            pos += 1;
+            let len = (((encoding & 0x0F) as usize) << 8) | next;
+            let bytes = blob.get(pos..pos + len).ok_or(())?;
+            pos += len;
+            bytes.to_vec()
+        } else {
+            let width = match encoding {
+                0xF0 => {
+                    let bytes = blob.get(pos..pos + 4).ok_or(())?;
+                    pos += 4;
+                    let len = u32::from_le_bytes(bytes.try_into().unwrap()) as usize;
+                    let bytes = blob.get(pos..pos + len).ok_or(())?;
+                    pos += len;
+                    items.push(bytes.to_vec());
+                    pos += listpack_backlen_size(pos - start);
+                    continue;
+                }
+                0xF1 => 2,
+                0xF2 => 3,
+                0xF3 => 4,
+                0xF4 => 8,
+                _ => return Err(()),
+            };
+            let bytes = blob.get(pos..pos + width).ok_or(())?;
+            pos += width;
+            le_signed(bytes, width).to_string().into_bytes()
+        };
+        items.push(item);
+        pos += listpack_backlen_size(pos - start);
+    }
+    Ok(items)
+}
+
+/// Number of bytes used to store the back length of a listpack entry whose
+/// encoding and data take up `entry_len` bytes.
+fn listpack_backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16383 => 2,
+        16384..=2097151 => 3,
+        2097152..=268435455 => 4,
+        _ => 5,
+    }
+}
+
+/// Decodes an intset: a 32-bit element width in bytes, a 32-bit count, then
+/// that many little-endian integers of that width.
+fn decode_intset(blob: &[u8]) -> Result<HashSet<Vec<u8>>, ()> {
+    let header = blob.get(..8).ok_or(())?;
+    let width = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let count = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(());
+    }
+    let end = count
+        .checked_mul(width)
+        .and_then(|n| n.checked_add(8))
+        .ok_or(())?;
+    let ints = blob.get(8..end).ok_or(())?;
+    Ok(ints
+        .chunks(width)
+        .map(|bytes| le_signed(bytes, width).to_string().into_bytes())
+        .collect())
+}
+
+/// Reads a little-endian two's complement integer `width` bytes wide.
+fn le_signed(bytes: &[u8], width: usize) -> i64 {
+    let mut buf = [0u8; 8];
+    buf[..width].copy_from_slice(&bytes[..width]);
+    // Shift the sign bit to the top to sign-extend.
+    let shift = 64 - 8 * width as u32;
+    (i64::from_le_bytes(buf) << shift) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps the opcodes of a file in a header, and ends it with a valid
+    /// checksum.
+    fn rdb_file(body: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(body);
+        data.push(OPCODE_EOF);
+        let checksum = crc64(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    /// The string stored at `key`, if it's there and a string.
+    fn string<'a>(storage: &'a Storage, key: &[u8]) -> Option<&'a [u8]> {
+        match storage.get(key) {
+            Some((_, RedisValue::String(value), _)) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn decodes_lengths() {
+        assert_eq!(decode_length(&[0x0A]), Ok((Length::Len(10), 1)));
+        assert_eq!(decode_length(&[0x42, 0xBC]), Ok((Length::Len(700), 2)));
+        assert_eq!(
+            decode_length(&[0x80, 0x00, 0x00, 0x42, 0x68]),
+            Ok((Length::Len(17000), 5))
+        );
+        assert_eq!(
+            decode_length(&[0x81, 0, 0, 0, 0x01, 0, 0, 0, 0]),
+            Ok((Length::Len(1 << 32), 9))
+        );
+        assert_eq!(decode_length(&[0xC0]), Ok((Length::Encoded(0), 1)));
+        assert_eq!(decode_length(&[0xC3]), Ok((Length::Encoded(3), 1)));
+        // Lengths cut short, and a format that doesn't exist.
+        assert_eq!(decode_length(&[]), Err(()));
+        assert_eq!(decode_length(&[0x40]), Err(()));
+        assert_eq!(decode_length(&[0x80, 0, 0]), Err(()));
+        assert_eq!(decode_length(&[0x82]), Err(()));
+    }
+
+    #[test]
+    fn decodes_lengths_as_encoded() {
+        for length in [0, 63, 64, 16383, 16384, u32::MAX as usize, 1 << 40] {
+            let mut out = Vec::new();
+            encode_length(&mut out, length);
+            assert_eq!(decode_length(&out), Ok((Length::Len(length), out.len())));
+        }
+    }
+
+    #[test]
+    fn loads_a_300_byte_value() {
+        let value = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        let mut body = vec![OPCODE_SELECTDB, 0, TYPE_STRING, 3];
+        body.extend_from_slice(b"big");
+        // 300 needs a 14-bit length.
+        body.extend_from_slice(&[0x41, 0x2C]);
+        body.extend_from_slice(&value);
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        assert_eq!(string(&rdb.databases[&0], b"big"), Some(value.as_slice()));
+    }
+
+    #[test]
+    fn loads_integer_encoded_strings() {
+        let body = [
+            &[OPCODE_SELECTDB, 0][..],
+            &[TYPE_STRING, 2, b'i', b'8', 0xC0, 0xF6],
+            &[TYPE_STRING, 3, b'i', b'1', b'6', 0xC1, 0x39, 0x30],
+            &[
+                TYPE_STRING,
+                3,
+                b'i',
+                b'3',
+                b'2',
+                0xC2,
+                0x87,
+                0xD6,
+                0x12,
+                0x00,
+            ],
+        ]
+        .concat();
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        let storage = &rdb.databases[&0];
+        assert_eq!(string(storage, b"i8"), Some(&b"-10"[..]));
+        assert_eq!(string(storage, b"i16"), Some(&b"12345"[..]));
+        assert_eq!(string(storage, b"i32"), Some(&b"1234567"[..]));
+    }
+
+    #[test]
+    fn decompresses_lzf_strings() {
+        // A literal run of "abc", then a back reference that copies it
+        // three more times, overlapping the bytes it produces.
+        let compressed = [0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02];
+        assert_eq!(lzf_decompress(&compressed, 12).unwrap(), b"abcabcabcabc");
+        // A short back reference, with its length in the control byte.
+        let compressed = [0x01, b'a', b'b', 0x80, 0x01];
+        assert_eq!(lzf_decompress(&compressed, 8).unwrap(), b"abababab");
+        // A reference before the start of the output, and output of the
+        // wrong length.
+        assert_eq!(lzf_decompress(&[0x00, b'a', 0x20, 0x05], 4), Err(()));
+        assert_eq!(lzf_decompress(&compressed, 7), Err(()));
+        assert_eq!(lzf_decompress(&compressed, 9), Err(()));
+        // A length no input could expand to, as a corrupt file may claim.
+        assert_eq!(lzf_decompress(&compressed, usize::MAX), Err(()));
+        let body = [
+            &[OPCODE_SELECTDB, 0, TYPE_STRING, 1, b'k', 0xC3, 0x05][..],
+            &[0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+            &compressed,
+        ]
+        .concat();
+        assert!(RDBObject::from_bytes(&rdb_file(&body)).is_err());
+    }
+
+    #[test]
+    fn loads_lzf_compressed_values() {
+        let body = [
+            &[OPCODE_SELECTDB, 0, TYPE_STRING, 1, b'k'][..],
+            &[0xC3, 7, 12, 0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02],
+        ]
+        .concat();
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        assert_eq!(string(&rdb.databases[&0], b"k"), Some(&b"abcabcabcabc"[..]));
+    }
+
+    #[test]
+    fn loads_far_future_expiries() {
+        // The last millisecond of the year 9999.
+        let far = 253_402_300_799_999i64;
+        let body = [
+            &[OPCODE_SELECTDB, 0, OPCODE_EXPIRETIME_MS][..],
+            &far.to_le_bytes(),
+            &[TYPE_STRING, 3, b'f', b'a', b'r', 1, b'v'],
+            &[OPCODE_EXPIRETIME_MS],
+            &i64::MAX.to_le_bytes(),
+            &[TYPE_STRING, 3, b'm', b'a', b'x', 1, b'v'],
+            &[OPCODE_EXPIRETIME],
+            &u32::MAX.to_le_bytes(),
+            &[TYPE_STRING, 4, b's', b'e', b'c', b's', 1, b'v'],
+            &[OPCODE_EXPIRETIME_MS],
+            &1000i64.to_le_bytes(),
+            &[TYPE_STRING, 4, b'p', b'a', b's', b't', 1, b'v'],
+            &[OPCODE_EXPIRETIME_MS],
+            &i64::MIN.to_le_bytes(),
+            &[TYPE_STRING, 3, b'm', b'i', b'n', 1, b'v'],
+        ]
+        .concat();
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        let storage = &rdb.databases[&0];
+        let years = |n: u64| time::Duration::from_secs(n * 365 * 24 * 60 * 60);
+        let (expiry, _, _) = &storage[&b"far"[..]];
+        assert!(expiry.unwrap() > time::Instant::now() + years(7000));
+        let (expiry, _, _) = &storage[&b"secs"[..]];
+        assert!(expiry.unwrap() > time::Instant::now() + years(70));
+        // Too far off to be represented, so it's kept without one.
+        assert_eq!(string(storage, b"max"), Some(&b"v"[..]));
+        assert!(!storage.contains_key(&b"past"[..]));
+        assert!(!storage.contains_key(&b"min"[..]));
+
+        // Saved again as the same Unix time, give or take the time taken.
+        let saved = RDBObject::from_bytes(&rdb.to_bytes()).unwrap();
+        let (before, _, _) = &storage[&b"far"[..]];
+        let (after, _, _) = &saved.databases[&0][&b"far"[..]];
+        let drift = after.unwrap().max(before.unwrap()) - after.unwrap().min(before.unwrap());
+        assert!(drift < time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn loads_every_database() {
+        let body = [
+            &[OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 1, 0][..],
+            &[TYPE_STRING, 1, b'a', 1, b'0'],
+            &[OPCODE_SELECTDB, 3, OPCODE_RESIZEDB, 2, 0],
+            &[TYPE_STRING, 1, b'b', 1, b'3'],
+            &[TYPE_STRING, 1, b'c', 1, b'3'],
+        ]
+        .concat();
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        assert_eq!(rdb.databases.len(), 2);
+        assert_eq!(rdb.databases[&0].len(), 1);
+        assert_eq!(string(&rdb.databases[&0], b"a"), Some(&b"0"[..]));
+        assert_eq!(rdb.databases[&3].len(), 2);
+        assert_eq!(string(&rdb.databases[&3], b"b"), Some(&b"3"[..]));
+        assert_eq!(string(&rdb.databases[&3], b"c"), Some(&b"3"[..]));
+
+        let saved = RDBObject::from_bytes(&rdb.to_bytes()).unwrap();
+        assert_eq!(string(&saved.databases[&0], b"a"), Some(&b"0"[..]));
+        assert_eq!(string(&saved.databases[&3], b"b"), Some(&b"3"[..]));
+    }
+
+    /// The value stored at `key`.
+    fn value<'a>(storage: &'a Storage, key: &[u8]) -> &'a RedisValue {
+        &storage[key].1
+    }
+
+    fn list(items: &[&str]) -> VecDeque<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    fn set(members: &[&str]) -> HashSet<Vec<u8>> {
+        members
+            .iter()
+            .map(|member| member.as_bytes().to_vec())
+            .collect()
+    }
+
+    fn hash(pairs: &[(&str, &str)]) -> HashMap<Vec<u8>, Vec<u8>> {
+        pairs
+            .iter()
+            .map(|(field, value)| (field.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn zset(value: &RedisValue) -> Vec<(&[u8], f64)> {
+        match value {
+            RedisValue::ZSet(zset) => zset
+                .iter()
+                .map(|(member, score)| (member.as_slice(), score))
+                .collect(),
+            _ => panic!("not a sorted set"),
+        }
+    }
+
+    /// A value of each type in each encoding Redis saves, with a
+    /// two-letter key: the type, then the encoding.
+    /// A value of each type in each encoding Redis saves, with a
+    /// two-letter key: the type, then the encoding.
+    const EVERY_ENCODING: &[&[u8]] = &[
+        // A list, set and hash of plain strings.
+        b"\x01\x02lp\x02\x01a\x01b",
+        b"\x02\x02sp\x02\x01a\x01b",
+        b"\x04\x02hp\x01\x01f\x01v",
+        // Sorted sets with scores as strings, infinity included, and as
+        // doubles.
+        b"\x03\x02zp\x02\x01m\x031.5\x01n\xfe",
+        b"\x05\x02zd\x01\x01m\x00\x00\x00\x00\x00\x00\xf8\x3f",
+        // Ziplists of "a", 5 in the entry's encoding byte and 300 as a
+        // 16-bit integer, of f and v, and of m with 1.5 and n with 2.
+        b"\x0a\x02lz\x14\x14\x00\x00\x00\x0f\x00\x00\x00\x03\x00\x00\x01a\x03\xf6\x02\xc0\x2c\x01\
+            \xff",
+        b"\x0d\x02hz\x11\x11\x00\x00\x00\x0d\x00\x00\x00\x02\x00\x00\x01f\x03\x01v\xff",
+        b"\x0c\x02zz\x18\x18\x00\x00\x00\x15\x00\x00\x00\x04\x00\x00\x01m\x03\x031.5\x05\x01n\x03\
+            \xf3\xff",
+        // An intset of 16-bit integers.
+        b"\x0b\x02si\x0e\x02\x00\x00\x00\x03\x00\x00\x00\xff\xff\x05\x00\x2c\x01",
+        // Listpacks of f, v, n and 7 as a 7-bit integer, of m with 1.5 and
+        // n with -2 as a 13-bit integer, and of "a" and 1000.
+        b"\x10\x02hl\x12\x12\x00\x00\x00\x04\x00\x81f\x02\x81v\x02\x81n\x02\x07\x01\xff",
+        b"\x11\x02zl\x15\x15\x00\x00\x00\x04\x00\x81m\x02\x831.5\x04\x81n\x02\xdf\xfe\x02\xff",
+        b"\x14\x02sl\x0d\x0d\x00\x00\x00\x02\x00\x81a\x02\xc3\xe8\x02\xff",
+        // A quicklist of two ziplists, holding "a" and "b".
+        b"\x0e\x02lq\x02\x0e\x0e\x00\x00\x00\x0a\x00\x00\x00\x01\x00\x00\x01a\xff\x0e\x0e\x00\x00\
+            \x00\x0a\x00\x00\x00\x01\x00\x00\x01b\xff",
+        // A quicklist of a plain node holding "big", and a listpack
+        // holding "c" and "d".
+        b"\x12\x02l2\x02\x01\x03big\x02\x0d\x0d\x00\x00\x00\x02\x00\x81c\x02\x81d\x02\xff",
+    ];
+
+    #[test]
+    fn loads_every_encoding() {
+        let body = [&[OPCODE_SELECTDB, 0][..], &EVERY_ENCODING.concat()].concat();
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        let storage = &rdb.databases[&0];
+        assert_eq!(storage.len(), EVERY_ENCODING.len());
+        let lists = [
+            (&b"lp"[..], list(&["a", "b"])),
+            (b"lz", list(&["a", "5", "300"])),
+            (b"lq", list(&["a", "b"])),
+            (b"l2", list(&["big", "c", "d"])),
+        ];
+        for (key, expected) in lists {
+            assert!(matches!(value(storage, key), RedisValue::List(l) if *l == expected));
+        }
+        let sets = [
+            (&b"sp"[..], set(&["a", "b"])),
+            (b"si", set(&["-1", "5", "300"])),
+            (b"sl", set(&["a", "1000"])),
+        ];
+        for (key, expected) in sets {
+            assert!(matches!(value(storage, key), RedisValue::Set(s) if *s == expected));
+        }
+        let hashes = [
+            (&b"hp"[..], hash(&[("f", "v")])),
+            (b"hz", hash(&[("f", "v")])),
+            (b"hl", hash(&[("f", "v"), ("n", "7")])),
+        ];
+        for (key, expected) in hashes {
+            assert!(matches!(value(storage, key), RedisValue::Hash(h) if *h == expected));
+        }
+        let m = &b"m"[..];
+        let n = &b"n"[..];
+        assert_eq!(zset(value(storage, b"zp")), [(m, 1.5), (n, f64::INFINITY)]);
+        assert_eq!(zset(value(storage, b"zd")), [(m, 1.5)]);
+        assert_eq!(zset(value(storage, b"zz")), [(m, 1.5), (n, 2.0)]);
+        assert_eq!(zset(value(storage, b"zl")), [(n, -2.0), (m, 1.5)]);
+    }
+
+    #[test]
+    fn skips_functions_modules_and_access_counters() {
+        let body = [
+            &[OPCODE_FUNCTION, 4][..],
+            b"code",
+            // A module id, the UINT type and when the data is loaded, then
+            // one value of each type and the end marker.
+            &[OPCODE_MODULE_AUX, 0x81, 1, 2, 3, 4, 5, 6, 7, 8, 2, 2],
+            &[
+                1, 5, 2, 6, 3, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 5, 1, b's', 0,
+            ],
+            &[OPCODE_SELECTDB, 0, OPCODE_IDLE, 0x41, 0x2C],
+            &[TYPE_STRING, 4, b'i', b'd', b'l', b'e', 1, b'v'],
+            &[OPCODE_FREQ, 5],
+            &[TYPE_STRING, 4, b'f', b'r', b'e', b'q', 1, b'v'],
+        ]
+        .concat();
+        let rdb = RDBObject::from_bytes(&rdb_file(&body)).unwrap();
+        let storage = &rdb.databases[&0];
+        assert_eq!(string(storage, b"idle"), Some(&b"v"[..]));
+        assert_eq!(string(storage, b"freq"), Some(&b"v"[..]));
+        // Idle for 300 seconds, or since the tests started if that's more
+        // recent.
+        let uptime = time::Duration::from_millis(crate::uptime_millis());
+        let idle = storage[&b"idle"[..]].2.idle();
+        assert!(idle >= time::Duration::from_secs(300).min(uptime));
+        assert!(storage[&b"freq"[..]].2.idle() < time::Duration::from_secs(1));
+        // Module data that isn't scheduled as UINT, or that ends early.
+        let body = [OPCODE_MODULE_AUX, 1, 1, 2, 0];
+        assert!(RDBObject::from_bytes(&rdb_file(&body)).is_err());
+        let truncated = [&b"REDIS0011"[..], &[OPCODE_MODULE_AUX, 1, 2, 2, 4, 0, 0]].concat();
+        assert!(RDBObject::from_bytes(&truncated).is_err());
+    }
+
+    /// An empty database saved by Redis 7.2.0.
+    const REDIS_7_2_EMPTY: &[u8] = b"REDIS0011\xfa\x09redis-ver\x057.2.0\xfa\x0aredis-bits\xc0@\
+        \xfa\x05ctime\xc2m\x08\xbce\xfa\x08used-mem\xc2\xb0\xc4\x10\x00\xfa\x08aof-base\xc0\x00\
+        \xff\xf0n;\xfe\xc0\xffZ\xa2";
+
+    #[test]
+    fn computes_crc64_jones() {
+        assert_eq!(crc64(b""), 0);
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+        let (data, checksum) = REDIS_7_2_EMPTY.split_at(REDIS_7_2_EMPTY.len() - 8);
+        assert_eq!(crc64(data).to_le_bytes(), checksum);
+    }
+
+    #[test]
+    fn checks_the_checksum() {
+        let rdb = RDBObject::from_bytes(REDIS_7_2_EMPTY).unwrap();
+        assert_eq!(rdb.metadata["redis-ver"], b"7.2.0");
+        assert_eq!(rdb.metadata["redis-bits"], b"64");
+        assert!(rdb.databases.is_empty());
+
+        // Turn "7.2.0" into "6.2.0".
+        let mut corrupt = REDIS_7_2_EMPTY.to_vec();
+        corrupt[21] ^= 1;
+        assert!(RDBObject::from_bytes(&corrupt).is_err());
+        // A zero checksum means it wasn't computed.
+        let len = corrupt.len();
+        corrupt[len - 8..].fill(0);
+        assert!(RDBObject::from_bytes(&corrupt).is_ok());
+    }
+}
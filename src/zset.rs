@@ -0,0 +1,369 @@
+use std::{cmp::Ordering, collections::HashMap, ops::Range};
+
+use crate::random_u64;
+
+/// A sorted set: members with scores, ordered by score and then by member.
+/// The order is kept in a treap whose nodes know the size of their subtree,
+/// so finding a member's rank or the member at a rank takes `O(log N)`, like
+/// Redis's skiplist with spans.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: Link,
+}
+
+/// A score that can be ordered. Scores are never NaN, and -0 is stored as 0
+/// so equal scores compare equal.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+type Entry = (Score, Vec<u8>);
+
+type Link = Option<Box<Node>>;
+
+/// A node of the treap: ordered by entry like a binary search tree, and by
+/// a random priority like a heap, which keeps it balanced on average.
+#[derive(Debug, Clone)]
+struct Node {
+    entry: Entry,
+    priority: u64,
+    /// How many entries are in the subtree rooted here.
+    size: usize,
+    left: Link,
+    right: Link,
+}
+
+/// The memory each member takes up in the treap, besides its bytes.
+pub const NODE_SIZE: usize = size_of::<Node>();
+
+impl Node {
+    fn update_size(&mut self) {
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+fn size(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+/// Splits the entries of a treap into those `in_prefix` holds for and the
+/// rest. It must hold for a prefix of the entries in order.
+fn split(link: Link, in_prefix: &impl Fn(&Entry) -> bool) -> (Link, Link) {
+    let Some(mut node) = link else {
+        return (None, None);
+    };
+    if in_prefix(&node.entry) {
+        let (left, right) = split(node.right.take(), in_prefix);
+        node.right = left;
+        node.update_size();
+        (Some(node), right)
+    } else {
+        let (left, right) = split(node.left.take(), in_prefix);
+        node.left = right;
+        node.update_size();
+        (left, Some(node))
+    }
+}
+
+/// Joins two treaps, every entry of `left` coming before those of `right`.
+fn merge(left: Link, right: Link) -> Link {
+    match (left, right) {
+        (None, link) | (link, None) => link,
+        (Some(mut left), Some(mut right)) => {
+            if left.priority > right.priority {
+                left.right = merge(left.right.take(), Some(right));
+                left.update_size();
+                Some(left)
+            } else {
+                right.left = merge(Some(left), right.left.take());
+                right.update_size();
+                Some(right)
+            }
+        }
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// The score of `member`, if it's in the set.
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` with `score`, or moves it to `score` if it's already in
+    /// the set, and returns its previous score.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let score = if score == 0.0 { 0.0 } else { score };
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.remove_entry(&(Score(previous), member.clone()));
+        }
+        let entry = (Score(score), member);
+        let (left, right) = split(self.ordered.take(), &|other| *other < entry);
+        let node = Node {
+            entry,
+            priority: random_u64(),
+            size: 1,
+            left: None,
+            right: None,
+        };
+        self.ordered = merge(merge(left, Some(Box::new(node))), right);
+        previous
+    }
+
+    /// Removes `member`, returning whether it was in the set.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.remove_entry(&(Score(score), member.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_entry(&mut self, entry: &Entry) {
+        let (left, rest) = split(self.ordered.take(), &|other| other < entry);
+        let (_, right) = split(rest, &|other| other == entry);
+        self.ordered = merge(left, right);
+    }
+
+    /// The position of `member` counting from the lowest score, if it's in
+    /// the set.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let entry = (Score(self.score(member)?), member.to_vec());
+        Some(self.count_prefix(|other| *other < entry))
+    }
+
+    /// How many entries `in_prefix` holds for, which must be a prefix of
+    /// them in order.
+    fn count_prefix(&self, in_prefix: impl Fn(&Entry) -> bool) -> usize {
+        let mut count = 0;
+        let mut link = &self.ordered;
+        while let Some(node) = link {
+            if in_prefix(&node.entry) {
+                count += size(&node.left) + 1;
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+        count
+    }
+
+    /// The members and their scores, from the lowest score to the highest.
+    pub fn iter(&self) -> Iter<'_> {
+        self.range(0..self.len())
+    }
+
+    /// The members and their scores at the positions in `ranks`, counting
+    /// from the lowest score, which must be within the set.
+    pub fn range(&self, ranks: Range<usize>) -> Iter<'_> {
+        let mut iter = Iter {
+            front: Vec::new(),
+            back: Vec::new(),
+            remaining: ranks.len(),
+        };
+        if ranks.is_empty() {
+            return iter;
+        }
+        // Walk down to the first and the last entry, keeping the nodes on
+        // the way that come at or after the first, and at or before the
+        // last.
+        let (mut first, mut link) = (ranks.start, &self.ordered);
+        while let Some(node) = link {
+            let left = size(&node.left);
+            if first <= left {
+                iter.front.push(&**node);
+                if first == left {
+                    break;
+                }
+                link = &node.left;
+            } else {
+                first -= left + 1;
+                link = &node.right;
+            }
+        }
+        let (mut last, mut link) = (ranks.end - 1, &self.ordered);
+        while let Some(node) = link {
+            let left = size(&node.left);
+            if last >= left {
+                iter.back.push(&**node);
+                if last == left {
+                    break;
+                }
+                last -= left + 1;
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+        iter
+    }
+
+    /// The positions of the members with scores between `min` and `max`,
+    /// counting from the lowest score. Each bound is a score and whether
+    /// members with exactly that score are included.
+    pub fn score_ranks(&self, min: (f64, bool), max: (f64, bool)) -> Range<usize> {
+        let start = self.count_prefix(|(score, _)| below(score.0, min));
+        let end = self.count_prefix(|(score, _)| !above(score.0, max));
+        start..end.max(start)
+    }
+
+    /// The members, in no particular order.
+    pub fn members(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.scores.keys()
+    }
+}
+
+/// Whether `score` comes before the range that starts at `min`.
+fn below(score: f64, min: (f64, bool)) -> bool {
+    score < min.0 || (!min.1 && score == min.0)
+}
+
+/// Whether `score` comes after the range that ends at `max`.
+fn above(score: f64, max: (f64, bool)) -> bool {
+    score > max.0 || (!max.1 && score == max.0)
+}
+
+/// An iterator over a run of the members of a [`SortedSet`] and their
+/// scores, in order.
+pub struct Iter<'a> {
+    /// The next node from the front, on top of the nodes still to come
+    /// after it whose left subtree it's in.
+    front: Vec<&'a Node>,
+    /// The same for the back.
+    back: Vec<&'a Node>,
+    /// How many entries are left, so the two ends stop where they meet.
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Vec<u8>, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = self.front.pop()?;
+        let mut link = &node.right;
+        while let Some(next) = link {
+            self.front.push(next);
+            link = &next.left;
+        }
+        Some((&node.entry.1, node.entry.0 .0))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let node = self.back.pop()?;
+        let mut link = &node.left;
+        while let Some(next) = link {
+            self.back.push(next);
+            link = &next.right;
+        }
+        Some((&node.entry.1, node.entry.0 .0))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The set's members and scores, as kept in order.
+    fn entries(zset: &SortedSet) -> Vec<(Vec<u8>, f64)> {
+        zset.iter()
+            .map(|(member, score)| (member.clone(), score))
+            .collect()
+    }
+
+    #[test]
+    fn agrees_with_a_sorted_vec() {
+        let mut zset = SortedSet::default();
+        let mut expected = Vec::<(Vec<u8>, f64)>::new();
+        for _ in 0..2000 {
+            let member = (random_u64() % 200).to_string().into_bytes();
+            let score = (random_u64() % 50) as f64 - 25.0;
+            expected.retain(|(other, _)| *other != member);
+            if random_u64().is_multiple_of(4) {
+                zset.remove(&member);
+            } else {
+                zset.insert(member.clone(), score);
+                expected.push((member, score));
+            }
+        }
+        expected.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        assert_eq!(entries(&zset), expected);
+        assert_eq!(zset.len(), expected.len());
+        for (rank, (member, _)) in expected.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(rank));
+        }
+        assert_eq!(zset.rank(b"missing"), None);
+        let reversed = zset.iter().rev().map(|(member, _)| member.clone());
+        assert!(reversed.eq(expected.iter().rev().map(|(member, _)| member.clone())));
+        for (start, end) in [(0, 0), (0, 1), (3, 40), (10, expected.len())] {
+            let range = zset.range(start..end).map(|(member, _)| member.clone());
+            assert!(range.eq(expected[start..end]
+                .iter()
+                .map(|(member, _)| member.clone())));
+        }
+        // Both ends of a range meet in the middle.
+        let mut range = zset.range(5..9);
+        assert_eq!(range.next().unwrap().0, &expected[5].0);
+        assert_eq!(range.next_back().unwrap().0, &expected[8].0);
+        assert_eq!(range.next().unwrap().0, &expected[6].0);
+        assert_eq!(range.next_back().unwrap().0, &expected[7].0);
+        assert!(range.next().is_none() && range.next_back().is_none());
+        let in_range = |min: (f64, bool), max: (f64, bool)| {
+            let ranks = zset.score_ranks(min, max);
+            let count = expected
+                .iter()
+                .filter(|(_, score)| !below(*score, min) && !above(*score, max))
+                .count();
+            assert_eq!(ranks.len(), count);
+        };
+        in_range((-5.0, true), (5.0, true));
+        in_range((-5.0, false), (5.0, false));
+        in_range((f64::NEG_INFINITY, true), (f64::INFINITY, true));
+        in_range((5.0, true), (-5.0, true));
+    }
+}